@@ -0,0 +1,104 @@
+//! `add` command: inserts new dependency into every selected manifest
+
+use std::{fs, path::PathBuf};
+
+use jrsonnet_evaluator::{bail, Result};
+use toml_edit::{Array, Document, InlineTable, Item, Table, Value};
+use tracing::info;
+
+use crate::{
+	keep_text_format, mark_changed, natives::path_to_string, paths, DepKind, DirectSource,
+	ToRuntime,
+};
+
+/// Description of the added dependency entry
+pub struct NewDependency {
+	pub name: String,
+	pub package: Option<String>,
+	pub source: DirectSource,
+	pub kind: DepKind,
+	pub target: Option<String>,
+	pub features: Vec<String>,
+	pub optional: bool,
+}
+impl NewDependency {
+	fn section(&self) -> Vec<&str> {
+		let kind = match self.kind {
+			DepKind::Normal => "dependencies",
+			DepKind::Dev => "dev-dependencies",
+			DepKind::Build => "build-dependencies",
+		};
+		self.target
+			.as_ref()
+			.map_or_else(|| vec![kind], |target| vec!["target", target, kind])
+	}
+	/// Entry for the manifest in specified directory, path source should be absolute
+	fn entry(&self, dir: &str) -> Result<Value> {
+		let mut table = InlineTable::new();
+		if let Some(package) = &self.package {
+			table.insert("package", package.as_str().into());
+		}
+		let source = DirectSource {
+			path: self
+				.source
+				.path
+				.as_deref()
+				.map(|path| paths::relative(dir, path))
+				.transpose()?,
+			..self.source.clone()
+		};
+		source.write(&mut table);
+		if !self.features.is_empty() {
+			table.insert(
+				"features",
+				Value::Array(self.features.iter().map(String::as_str).collect::<Array>()),
+			);
+		}
+		if self.optional {
+			table.insert("optional", true.into());
+		}
+		Ok(match table.get("version") {
+			Some(version) if table.len() == 1 => version.clone(),
+			_ => Value::InlineTable(table),
+		})
+	}
+}
+
+/// Creates missing tables of the section, `target` and `target.<cfg>` are implicit
+fn section_table<'t>(mut table: &'t mut Table, section: &[&str]) -> Result<&'t mut Table> {
+	for (i, frag) in section.iter().enumerate() {
+		if !table.contains_key(frag) {
+			let mut new = Table::new();
+			new.set_implicit(i + 1 != section.len());
+			table.insert(frag, Item::Table(new));
+		}
+		let Some(next) = table.get_mut(frag).and_then(Item::as_table_mut) else {
+			bail!("{frag} is not a table");
+		};
+		table = next;
+	}
+	Ok(table)
+}
+
+pub fn add(manifests: &[PathBuf], dep: &NewDependency) -> Result<()> {
+	let section = dep.section();
+	for manifest in manifests {
+		let toml = fs::read_to_string(manifest).run_err()?;
+		let mut doc: Document = toml.parse().run_err()?;
+		// Virtual manifests have no dependencies of their own
+		if !doc.contains_key("package") {
+			continue;
+		}
+		let table = section_table(doc.as_table_mut(), &section)?;
+		if table.contains_key(&dep.name) {
+			info!("{} already depends on {}", manifest.display(), dep.name);
+			continue;
+		}
+		let dir = path_to_string(manifest.parent().expect("manifest is a file"))?;
+		table.insert(&dep.name, Item::Value(dep.entry(&dir)?));
+		info!("adding {} to {}", dep.name, manifest.display());
+		fs::write(manifest, keep_text_format(&toml, &doc.to_string())).run_err()?;
+		mark_changed();
+	}
+	Ok(())
+}
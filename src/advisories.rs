@@ -0,0 +1,163 @@
+//! Access to the [RustSec advisory database](https://github.com/rustsec/advisory-db)
+//!
+//! Database is a git repository with a markdown file with toml front matter per advisory,
+//! stored in `crates/<package>/<id>.md`
+
+use std::{
+	collections::BTreeMap,
+	fs,
+	path::{Path, PathBuf},
+};
+
+use jrsonnet_evaluator::{
+	bail,
+	function::{builtin, CallLocation},
+	Result, Val,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{caller_dir, cargo_config::cargo_home, git::git, natives, ToRuntime};
+
+const DEFAULT_URL: &str = "https://github.com/rustsec/advisory-db";
+
+#[derive(Deserialize)]
+struct AdvisoryFile {
+	advisory: AdvisoryMeta,
+	#[serde(default)]
+	versions: Versions,
+}
+#[derive(Deserialize)]
+struct AdvisoryMeta {
+	id: String,
+	package: String,
+	date: String,
+	url: Option<String>,
+	informational: Option<String>,
+	withdrawn: Option<String>,
+	#[serde(default)]
+	aliases: Vec<String>,
+}
+
+#[derive(Deserialize, Serialize, Default, Clone, Debug)]
+pub struct Versions {
+	#[serde(default)]
+	pub patched: Vec<String>,
+	#[serde(default)]
+	pub unaffected: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Advisory {
+	pub id: String,
+	pub package: String,
+	pub title: String,
+	pub date: String,
+	pub url: Option<String>,
+	/// Informational advisory kind (i.e `unmaintained`), None for vulnerabilities
+	pub informational: Option<String>,
+	pub aliases: Vec<String>,
+	pub patched: Vec<String>,
+	pub unaffected: Vec<String>,
+}
+fn parse(path: &Path) -> Result<Option<Advisory>> {
+	let text = fs::read_to_string(path).run_err()?;
+	let Some(rest) = text.trim_start().strip_prefix("```toml") else {
+		bail!(
+			"{}: advisory should start with toml front matter",
+			path.display()
+		)
+	};
+	let Some((front, body)) = rest.split_once("\n```") else {
+		bail!("{}: unterminated front matter", path.display())
+	};
+	let file: AdvisoryFile = toml::from_str(front)
+		.map_err(|e| format!("{}: {e}", path.display()))
+		.run_err()?;
+	if file.advisory.withdrawn.is_some() {
+		return Ok(None);
+	}
+	let title = body
+		.lines()
+		.find_map(|l| l.strip_prefix("# "))
+		.unwrap_or_default()
+		.trim()
+		.to_owned();
+	Ok(Some(Advisory {
+		id: file.advisory.id,
+		package: file.advisory.package,
+		title,
+		date: file.advisory.date,
+		url: file.advisory.url,
+		informational: file.advisory.informational,
+		aliases: file.advisory.aliases,
+		patched: file.versions.patched,
+		unaffected: file.versions.unaffected,
+	}))
+}
+
+/// Location of the database, shared with `cargo audit`
+pub fn default_path() -> Result<PathBuf> {
+	let Some(home) = cargo_home() else {
+		bail!("unable to determine cargo home")
+	};
+	Ok(home.join("advisory-db"))
+}
+
+/// Clone default database, if it doesn't exist yet
+pub fn fetch_default() -> Result<PathBuf> {
+	let path = default_path()?;
+	if !path.exists() {
+		info!("cloning advisory database to {}", path.display());
+		let parent = path.parent().expect("database is in cargo home");
+		fs::create_dir_all(parent).run_err()?;
+		git(
+			parent,
+			&[
+				"clone",
+				"--depth",
+				"1",
+				DEFAULT_URL,
+				&natives::path_to_string(&path)?,
+			],
+		)?;
+	}
+	Ok(path)
+}
+
+/// Load all non-withdrawn advisories from database, keyed by package name
+pub fn load(db: &Path) -> Result<BTreeMap<String, Vec<Advisory>>> {
+	let crates = db.join("crates");
+	if !crates.is_dir() {
+		bail!("{} is not an advisory database", db.display())
+	}
+	let mut out = <BTreeMap<String, Vec<Advisory>>>::new();
+	for entry in walkdir::WalkDir::new(crates) {
+		let entry = entry.run_err()?;
+		if !entry.file_type().is_file()
+			|| entry.path().extension().and_then(|e| e.to_str()) != Some("md")
+		{
+			continue;
+		}
+		if let Some(advisory) = parse(entry.path())? {
+			out.entry(advisory.package.clone())
+				.or_default()
+				.push(advisory);
+		}
+	}
+	for advisories in out.values_mut() {
+		advisories.sort_by(|a, b| a.id.cmp(&b.id));
+	}
+	Ok(out)
+}
+
+#[builtin]
+pub fn load_advisories(loc: CallLocation, db: Option<String>) -> Result<Val> {
+	let db = if let Some(db) = db {
+		caller_dir(loc)?.join(db)
+	} else {
+		fetch_default()?
+	};
+	let advisories = natives::cached(format!("loadAdvisories {}", db.display()), || load(&db))?;
+	Val::from_serde(&*advisories).run_err()
+}
@@ -0,0 +1,87 @@
+//! `apply-patch-file` command: merges `[patch]` section generated by `soft-patch` into the manifest or cargo config
+
+use std::{fs, path::Path};
+
+use jrsonnet_evaluator::{bail, Result};
+use toml_edit::{Document, Item, Table};
+use tracing::info;
+
+use crate::{keep_text_format, mark_changed, verify::has_marker, DirectSource, ToRuntime};
+
+fn is_managed(table: &Table, name: &str) -> bool {
+	has_marker(table.key_decor(name))
+		|| match table.get(name) {
+			Some(Item::Table(t)) => has_marker(Some(t.decor())),
+			Some(Item::Value(v)) => has_marker(Some(v.decor())),
+			_ => false,
+		}
+}
+
+/// Merges `[patch]` entries of the patch document into the target file, which is created if missing
+///
+/// Existing entries are only replaced if they are managed by deppatcher, or if `force` is set,
+/// otherwise conflicting entries are reported, and nothing is written
+pub fn apply_patch_file(target: &Path, patch: &Document, force: bool) -> Result<()> {
+	let Some(patch) = patch.get("patch").and_then(Item::as_table) else {
+		bail!("patch file has no [patch] section");
+	};
+	let original = if target.exists() {
+		fs::read_to_string(target).run_err()?
+	} else {
+		String::new()
+	};
+	let mut doc: Document = original.parse().run_err()?;
+
+	let mut conflicts = Vec::new();
+	let mut changed = false;
+	if !doc.contains_key("patch") {
+		let mut table = Table::new();
+		table.set_implicit(true);
+		doc.insert("patch", Item::Table(table));
+	}
+	let Some(target_patch) = doc.get_mut("patch").and_then(Item::as_table_mut) else {
+		bail!("patch is not a table in {}", target.display());
+	};
+	for (source, entries) in patch {
+		let Some(entries) = entries.as_table() else {
+			bail!("patch.{source} is not a table");
+		};
+		if !target_patch.contains_key(source) {
+			target_patch.insert(source, Item::Table(Table::new()));
+		}
+		let Some(target_entries) = target_patch.get_mut(source).and_then(Item::as_table_mut) else {
+			bail!("patch.{source} is not a table in {}", target.display());
+		};
+		for (name, entry) in entries {
+			if let Some(existing) = target_entries.get(name) {
+				if DirectSource::from_item(existing) == DirectSource::from_item(entry) {
+					continue;
+				}
+				if !force && !is_managed(target_entries, name) {
+					conflicts.push(format!("patch.{source}.{name}"));
+					continue;
+				}
+			}
+			info!("applying patch.{source}.{name}");
+			target_entries.insert(name, entry.clone());
+			changed = true;
+		}
+	}
+
+	if !conflicts.is_empty() {
+		bail!(
+			"entries already exist and are not managed by deppatcher, pass --force to overwrite them:\n{}",
+			conflicts.join("\n")
+		);
+	}
+	if changed {
+		if let Some(parent) = target.parent() {
+			fs::create_dir_all(parent).run_err()?;
+		}
+		fs::write(target, keep_text_format(&original, &doc.to_string())).run_err()?;
+		mark_changed();
+	} else {
+		info!("{} is up to date", target.display());
+	}
+	Ok(())
+}
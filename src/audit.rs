@@ -0,0 +1,158 @@
+//! `audit-patch` command: raise requirements of vulnerable dependencies to their nearest fixed versions
+
+use std::{
+	collections::BTreeMap,
+	path::{Path, PathBuf},
+};
+
+use cargo_lock::{Lockfile, SourceId};
+use jrsonnet_evaluator::{
+	typed::{Either2, Null},
+	Either, Result,
+};
+use semver::{Version, VersionReq};
+use tracing::info;
+
+use crate::{
+	advisories::{self, Advisory},
+	index, manifests, patch, DirectInput, DirectSource, Key, ToRuntime, PROGRESS,
+};
+
+struct Fix {
+	advisories: Vec<String>,
+	to: Option<Version>,
+}
+
+fn affects(advisory: &Advisory, version: &Version) -> Result<bool> {
+	for req in advisory.patched.iter().chain(&advisory.unaffected) {
+		if VersionReq::parse(req).run_err()?.matches(version) {
+			return Ok(false);
+		}
+	}
+	Ok(true)
+}
+
+/// Lowest non-yanked release, which isn't affected by any of advisories,
+/// semver-compatible releases are preferred
+fn nearest_fixed(name: &str, from: &Version, advisories: &[&Advisory]) -> Result<Option<Version>> {
+	let compatible = VersionReq::parse(&format!("^{from}")).run_err()?;
+	let mut candidates = Vec::new();
+	for v in index::versions(name)? {
+		if v.yanked || !v.vers.pre.is_empty() || v.vers <= *from {
+			continue;
+		}
+		let mut affected = false;
+		for advisory in advisories {
+			affected |= affects(advisory, &v.vers)?;
+		}
+		if !affected {
+			candidates.push(v.vers);
+		}
+	}
+	candidates.sort();
+	Ok(candidates
+		.iter()
+		.find(|v| compatible.matches(v))
+		.or_else(|| candidates.first())
+		.cloned())
+}
+
+pub fn audit_patch(
+	root: &Path,
+	db: Option<PathBuf>,
+	informational: bool,
+	dry_run: bool,
+) -> Result<()> {
+	let lockfile = Lockfile::load(root.join("Cargo.lock")).run_err()?;
+	let db = if let Some(db) = db {
+		db
+	} else {
+		advisories::fetch_default()?
+	};
+	let advisories = advisories::load(&db)?;
+
+	let mut fixes = <BTreeMap<(String, Version), Fix>>::new();
+	for package in &lockfile.packages {
+		if !package
+			.source
+			.as_ref()
+			.is_some_and(SourceId::is_default_registry)
+		{
+			continue;
+		}
+		let name = package.name.as_str();
+		let Some(package_advisories) = advisories.get(name) else {
+			continue;
+		};
+		let version = Version::parse(&package.version.to_string()).run_err()?;
+		let mut matched = Vec::new();
+		for advisory in package_advisories {
+			if (informational || advisory.informational.is_none()) && affects(advisory, &version)? {
+				matched.push(advisory);
+			}
+		}
+		if matched.is_empty() {
+			continue;
+		}
+		let to = nearest_fixed(name, &version, &matched)?;
+		fixes.insert(
+			(name.to_owned(), version),
+			Fix {
+				advisories: matched.iter().map(|a| a.id.clone()).collect(),
+				to,
+			},
+		);
+	}
+
+	let mutator = |_: &Key, input: DirectInput| -> Result<Either![Null, DirectSource]> {
+		let source = &input.source;
+		if source.path.is_some()
+			|| source.git.is_some()
+			|| source.registry.is_some()
+			|| source.registry_index.is_some()
+			|| source.workspace == Some(true)
+		{
+			return Ok(Either2::A(Null));
+		}
+		let Some(req) = source
+			.version
+			.as_deref()
+			.and_then(|v| VersionReq::parse(v).ok())
+		else {
+			return Ok(Either2::A(Null));
+		};
+		for ((name, from), fix) in &fixes {
+			let Some(to) = &fix.to else {
+				continue;
+			};
+			if *name == input.package && req.matches(from) && !req.matches(to) {
+				return Ok(Either2::B(DirectSource {
+					version: Some(to.to_string()),
+					..source.clone()
+				}));
+			}
+		}
+		Ok(Either2::A(Null))
+	};
+	if !dry_run {
+		for manifest in manifests(root)? {
+			info!(target: PROGRESS, "patching {}", manifest.display());
+			patch(&manifest, &mutator, false)?;
+		}
+	}
+
+	for ((name, from), fix) in &fixes {
+		let ids = fix.advisories.join(", ");
+		if let Some(to) = &fix.to {
+			println!("{name} {from} => {to} ({ids})");
+		} else {
+			println!("{name} {from}: no fixed version available ({ids})");
+		}
+	}
+	if !dry_run && fixes.values().any(|f| f.to.is_some()) {
+		println!(
+			"run `cargo update` to apply fixes, which are compatible with current requirements"
+		);
+	}
+	Ok(())
+}
@@ -0,0 +1,65 @@
+//! Cargo configuration discovery, see <https://doc.rust-lang.org/cargo/reference/config.html#hierarchical-structure>
+
+use std::{
+	env, fs,
+	path::{Path, PathBuf},
+};
+
+use jrsonnet_evaluator::Result;
+use toml::{Table, Value};
+
+use crate::ToRuntime;
+
+pub fn cargo_home() -> Option<PathBuf> {
+	env::var_os("CARGO_HOME")
+		.map(PathBuf::from)
+		.or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".cargo")))
+}
+
+/// Config files, which apply to specified directory, from the most specific to the least
+pub fn config_files(dir: &Path) -> Vec<PathBuf> {
+	let mut dirs = dir
+		.ancestors()
+		.map(|d| d.join(".cargo"))
+		.collect::<Vec<_>>();
+	if let Some(home) = cargo_home() {
+		if !dirs.contains(&home) {
+			dirs.push(home);
+		}
+	}
+	dirs.into_iter()
+		.filter_map(|dir| {
+			// Extensionless variant takes precedence, if both exist
+			["config", "config.toml"]
+				.into_iter()
+				.map(|name| dir.join(name))
+				.find(|p| p.is_file())
+		})
+		.collect()
+}
+
+/// Merge `higher` config over `lower`, arrays are concatenated, tables are merged recursively,
+/// other values are replaced
+fn merge(lower: &mut Table, higher: Table) {
+	for (k, v) in higher {
+		match (lower.get_mut(&k), v) {
+			(Some(Value::Table(lower)), Value::Table(higher)) => merge(lower, higher),
+			(Some(Value::Array(lower)), Value::Array(higher)) => lower.extend(higher),
+			(_, v) => {
+				lower.insert(k, v);
+			}
+		}
+	}
+}
+
+/// Effective cargo config for specified directory
+pub fn load(dir: &Path) -> Result<Table> {
+	let mut out = Table::new();
+	for file in config_files(dir).into_iter().rev() {
+		let config: Table = toml::from_str(&fs::read_to_string(&file).run_err()?)
+			.map_err(|e| format!("{}: {e}", file.display()))
+			.run_err()?;
+		merge(&mut out, config);
+	}
+	Ok(out)
+}
@@ -0,0 +1,40 @@
+//! Shell completions and man pages, generated from the command line definition
+
+use std::{fs, io::stdout, path::Path};
+
+use clap::{Command, CommandFactory};
+use clap_complete::Shell;
+use jrsonnet_evaluator::Result;
+use tracing::info;
+
+use crate::{Opts, ToRuntime, PROGRESS};
+
+pub fn completions(shell: Shell) {
+	clap_complete::generate(shell, &mut Opts::command(), "deppatcher", &mut stdout());
+}
+
+/// Writes page for the command, and for every its subcommand, named like `deppatcher-patch.1`
+fn write_manpages(dir: &Path, command: &Command, name: &str) -> Result<()> {
+	let path = dir.join(format!("{name}.1"));
+	let mut out = Vec::new();
+	clap_mangen::Man::new(command.clone())
+		.title(name)
+		.render(&mut out)
+		.run_err()?;
+	fs::write(&path, out).run_err()?;
+	info!(target: PROGRESS, "written {}", path.display());
+	for sub in command.get_subcommands() {
+		if sub.is_hide_set() || sub.get_name() == "help" {
+			continue;
+		}
+		write_manpages(dir, sub, &format!("{name}-{}", sub.get_name()))?;
+	}
+	Ok(())
+}
+
+pub fn manpages(dir: &Path) -> Result<()> {
+	fs::create_dir_all(dir).run_err()?;
+	let mut command = Opts::command();
+	command.build();
+	write_manpages(dir, &command, "deppatcher")
+}
@@ -0,0 +1,112 @@
+//! `link --copy` and `link-registry`: dependencies are linked to local copies of crates
+
+use std::{
+	collections::{BTreeMap, HashSet},
+	fs,
+	path::Path,
+};
+
+use jrsonnet_evaluator::Result;
+use serde::Serialize;
+use tracing::info;
+
+use crate::{
+	cargo_flags, dependencies, graph, manifests, natives::path_to_string, state, timings, ToRuntime,
+};
+
+/// Directories, which are never copied: build outputs and VCS metadata
+const SKIPPED: &[&str] = &["target", ".git"];
+
+/// Recursively copies crate sources, skipping build outputs and VCS metadata
+pub fn copy_dir(from: &Path, to: &Path) -> Result<()> {
+	let walk = walkdir::WalkDir::new(from)
+		.into_iter()
+		.filter_entry(|e| e.depth() == 0 || !SKIPPED.iter().any(|s| e.file_name() == *s));
+	for entry in walk {
+		let entry = entry.run_err()?;
+		let target = to.join(entry.path().strip_prefix(from).run_err()?);
+		if entry.file_type().is_dir() {
+			fs::create_dir_all(&target).run_err()?;
+		} else {
+			fs::copy(entry.path(), &target).run_err()?;
+		}
+	}
+	Ok(())
+}
+
+/// Copies crates of the `workspace`, which are depended on by manifests of the `root`, into `dir/<package>`
+///
+/// Existing copies are kept as is, as they might contain local changes.
+/// Returns absolute paths of the copies, keyed by package name
+pub fn copy_linked(root: &Path, workspace: &Path, dir: &Path) -> Result<BTreeMap<String, String>> {
+	let mut command = cargo_metadata::MetadataCommand::new();
+	command.no_deps();
+	command.other_options(cargo_flags());
+	command.current_dir(workspace);
+	let metadata = timings::measure(timings::Phase::Metadata, || command.exec()).run_err()?;
+
+	let mut used = HashSet::new();
+	for manifest in manifests(root)? {
+		used.extend(dependencies(&manifest)?.into_iter().map(|(_, d)| d.package));
+	}
+
+	let dir = root.join(dir);
+	let mut out = BTreeMap::new();
+	for package in &metadata.packages {
+		if !used.contains(package.name.as_str()) {
+			continue;
+		}
+		let from = package.manifest_path.parent().expect("manifest is a file");
+		let to = dir.join(package.name.as_str());
+		if to.exists() {
+			info!("{} is already copied to {}", package.name, to.display());
+		} else {
+			info!("copying {} to {}", package.name, to.display());
+			copy_dir(from.as_std_path(), &to)?;
+		}
+		out.insert(
+			package.name.clone(),
+			path_to_string(&to.canonicalize().run_err()?)?,
+		);
+	}
+	Ok(out)
+}
+
+/// Local copy of the package, linked instead of it
+#[derive(Serialize)]
+pub struct LocalCopy {
+	pub version: String,
+	pub path: String,
+}
+
+/// Copies extracted registry sources of the locked packages (`name` or `name@version`) into the external state directory
+///
+/// Returns copies keyed by package name
+pub fn copy_from_registry(root: &Path, specs: &[String]) -> Result<BTreeMap<String, LocalCopy>> {
+	let graph = graph::load(root)?;
+	let dir = state::external_dir(root)?.join("registry");
+	let mut out = BTreeMap::new();
+	for spec in specs {
+		let pkg = graph::find_locked(&graph, spec, |p| {
+			p.source()
+				.external_source()
+				.is_some_and(|s| s.starts_with("registry+") || s.starts_with("sparse+"))
+		})?;
+		let from = pkg.manifest_path().parent().expect("manifest is a file");
+		let to = dir.join(format!("{}-{}", pkg.name(), pkg.version()));
+		if to.exists() {
+			info!("{spec} is already copied to {}", to.display());
+		} else {
+			info!("copying {} to {}", from, to.display());
+			copy_dir(from.as_std_path(), &to)?;
+		}
+		out.insert(
+			pkg.name().to_owned(),
+			LocalCopy {
+				version: pkg.version().to_string(),
+				path: path_to_string(&to.canonicalize().run_err()?)?,
+			},
+		);
+	}
+	Ok(out)
+}
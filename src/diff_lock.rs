@@ -0,0 +1,155 @@
+//! `diff-lock` command: compare package versions and sources between two lockfiles
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::Path,
+};
+
+use cargo_lock::Lockfile;
+use clap::ValueEnum;
+use jrsonnet_evaluator::Result;
+use serde::Serialize;
+use serde_json::{json, Value as Json};
+
+use crate::{graph, DirectSource, ToRuntime};
+
+#[derive(ValueEnum, Clone, Copy, Default)]
+pub enum DiffFormat {
+	/// Human-readable report
+	#[default]
+	Text,
+	/// `{"changed": {"name": {"from": ..., "to": ...}}, "added": ..., "removed": ...}` object
+	Json,
+	/// Rule, replaying changes of the packages onto other workspace
+	Rule,
+}
+
+#[derive(Serialize)]
+struct Change {
+	from: DirectSource,
+	to: DirectSource,
+}
+
+#[derive(Default, Serialize)]
+struct Diff {
+	/// Packages locked to a single version in both lockfiles, which version or source differs
+	changed: BTreeMap<String, Change>,
+	added: BTreeMap<String, Vec<DirectSource>>,
+	removed: BTreeMap<String, Vec<DirectSource>>,
+}
+
+fn sources(lockfile: &Path) -> Result<BTreeMap<String, BTreeSet<DirectSource>>> {
+	let lockfile = Lockfile::load(lockfile).run_err()?;
+	let mut out = <BTreeMap<_, BTreeSet<_>>>::new();
+	for pkg in &lockfile.packages {
+		if let Some(source) = graph::lock_source(pkg) {
+			out.entry(pkg.name.as_str().to_owned())
+				.or_default()
+				.insert(source);
+		}
+	}
+	Ok(out)
+}
+
+fn diff(old: &Path, new: &Path) -> Result<Diff> {
+	let old = sources(old)?;
+	let new = sources(new)?;
+	let mut out = Diff::default();
+	// Output maps are sorted anyway
+	for name in old
+		.keys()
+		.chain(new.keys().filter(|k| !old.contains_key(*k)))
+	{
+		let empty = BTreeSet::new();
+		let old = old.get(name).unwrap_or(&empty);
+		let new = new.get(name).unwrap_or(&empty);
+		let removed = old.difference(new).cloned().collect::<Vec<_>>();
+		let added = new.difference(old).cloned().collect::<Vec<_>>();
+		match (<[_; 1]>::try_from(removed), <[_; 1]>::try_from(added)) {
+			(Ok([from]), Ok([to])) => {
+				out.changed.insert(name.clone(), Change { from, to });
+			}
+			(removed, added) => {
+				let removed = removed.map_or_else(|v| v, Vec::from);
+				let added = added.map_or_else(|v| v, Vec::from);
+				if !removed.is_empty() {
+					out.removed.insert(name.clone(), removed);
+				}
+				if !added.is_empty() {
+					out.added.insert(name.clone(), added);
+				}
+			}
+		}
+	}
+	Ok(out)
+}
+
+/// Version for packages from the default registry, and full source table for everything else
+fn describe(source: &DirectSource) -> String {
+	match &source.version {
+		Some(version) if source.registry.is_none() && source.git.is_none() => version.clone(),
+		_ => source.to_table().to_string(),
+	}
+}
+
+/// Source with every unset source key explicitly nulled, to be used with `std.mergePatch`
+fn replacement(source: &DirectSource) -> Result<Json> {
+	let mut out = json!({
+		"version": null,
+		"registry": null,
+		"registryIndex": null,
+		"path": null,
+		"git": null,
+		"rev": null,
+		"tag": null,
+		"branch": null,
+	});
+	let Json::Object(set) = serde_json::to_value(source).run_err()? else {
+		unreachable!("source is serialized as object")
+	};
+	for (k, v) in set {
+		out[k] = v;
+	}
+	Ok(out)
+}
+
+pub fn diff_lock(old: &Path, new: &Path, format: DiffFormat) -> Result<()> {
+	let diff = diff(old, new)?;
+	match format {
+		DiffFormat::Text => {
+			for (name, Change { from, to }) in &diff.changed {
+				println!("{name}: {} => {}", describe(from), describe(to));
+			}
+			for (name, sources) in &diff.added {
+				for source in sources {
+					println!("+ {name} {}", describe(source));
+				}
+			}
+			for (name, sources) in &diff.removed {
+				for source in sources {
+					println!("- {name} {}", describe(source));
+				}
+			}
+		}
+		DiffFormat::Json => println!(
+			"{}",
+			serde_json::to_string_pretty(&diff).expect("diff is serializable")
+		),
+		DiffFormat::Rule => {
+			println!("// Generated by `deppatcher diff-lock`, replays changes between lockfiles");
+			println!("local changes = {{");
+			for (name, Change { to, .. }) in &diff.changed {
+				println!(
+					"\t{}: {},",
+					serde_json::to_string(name).expect("string is serializable"),
+					replacement(to)?
+				);
+			}
+			println!("}};");
+			println!("function(pkg) if std.objectHas(changes, pkg.package)");
+			println!("\t&& !std.objectHas(pkg.source, 'path') && !std.get(pkg.source, 'workspace', false)");
+			println!("then std.mergePatch(pkg.source, changes[pkg.package])");
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,193 @@
+//! `doctor` command: diagnostics of the leftovers of previous deppatcher runs
+
+use std::{fs, path::Path};
+
+use cargo_lock::Lockfile;
+use guppy::graph::PackageGraph;
+use jrsonnet_evaluator::Result;
+use toml_edit::{Document, Item};
+
+use crate::{
+	dependencies, display_key, get_item, graph, manifests, rust_version, DirectSource, Key,
+	ToRuntime,
+};
+
+/// `[[patch.unused]]` entry of Cargo.lock, with the guessed reason of it being ignored
+pub struct UnusedPatch {
+	pub name: String,
+	pub version: String,
+	pub reason: String,
+}
+
+/// Lists patches, which cargo has ignored during last resolution.
+///
+/// Graph is used to explain why patch is not used, it might not be available if workspace is broken.
+pub fn unused_patches(root: &Path, graph: Option<&PackageGraph>) -> Result<Vec<UnusedPatch>> {
+	let lockfile = root.join("Cargo.lock");
+	if !lockfile.is_file() {
+		return Ok(vec![]);
+	}
+	let lockfile = Lockfile::load(lockfile).run_err()?;
+	let mut out = Vec::new();
+	for unused in lockfile.patch.unused {
+		let name = unused.name.as_str();
+		let version = semver::Version::parse(&unused.version.to_string()).run_err()?;
+		let reason = graph.map_or_else(
+			|| "dependency graph is unavailable".to_owned(),
+			|graph| {
+				let mut reqs = Vec::new();
+				for pkg in graph.packages() {
+					for link in pkg.direct_links() {
+						if link.to().name() == name {
+							reqs.push((link.version_req().clone(), pkg.name().to_owned()));
+						}
+					}
+				}
+				if reqs.is_empty() {
+					return format!("nothing depends on {name}");
+				}
+				let mismatched = reqs
+					.iter()
+					.filter(|(req, _)| !req.matches(&version))
+					.map(|(req, from)| format!("{from} requires {req}"))
+					.collect::<Vec<_>>();
+				if mismatched.len() == reqs.len() {
+					format!("version mismatch: {}", mismatched.join(", "))
+				} else {
+					"patched source is not the source dependents use".to_owned()
+				}
+			},
+		);
+		out.push(UnusedPatch {
+			name: name.to_owned(),
+			version: unused.version.to_string(),
+			reason,
+		});
+	}
+	Ok(out)
+}
+
+/// Stored originals, with their dependency paths
+///
+/// Originals of removed dependencies have `remove` set
+pub fn originals(item: &Item, key: &mut Key, out: &mut Vec<(Key, DirectSource)>) {
+	match item {
+		Item::Table(table) => {
+			for (k, v) in table {
+				key.push(k.to_owned());
+				originals(v, key, out);
+				key.pop();
+			}
+		}
+		Item::Value(v) => {
+			if let Some(table) = v.as_inline_table() {
+				let mut source = DirectSource::read_original(table);
+				if table.get("removed").and_then(toml_edit::Value::as_bool) == Some(true) {
+					source.remove = Some(true);
+				}
+				out.push((key.clone(), source));
+			}
+		}
+		_ => {}
+	}
+}
+
+struct Report {
+	problems: usize,
+}
+impl Report {
+	fn problem(&mut self, location: &str, problem: &str, fix: &str) {
+		self.problems += 1;
+		println!("{location}: {problem}");
+		println!("  fix: {fix}");
+	}
+}
+
+/// Returns `true` if no problems were found
+pub fn doctor(root: &Path) -> Result<bool> {
+	let mut report = Report { problems: 0 };
+
+	for manifest in manifests(root)? {
+		let relative = manifest.strip_prefix(root).unwrap_or(&manifest).display();
+		let doc: Document = fs::read_to_string(&manifest).run_err()?.parse().run_err()?;
+
+		for metadata_root in ["package", "workspace"] {
+			let Some(stored) = get_item(
+				doc.as_item(),
+				[metadata_root, "metadata", "deppatcher", "originals"],
+			) else {
+				continue;
+			};
+			for (key, original) in rust_version::stored_originals(stored) {
+				let current = get_item(doc.as_item(), key.iter().map(String::as_str));
+				if current.and_then(Item::as_str) == Some(&original) {
+					report.problem(
+						&format!("{relative}: {}", display_key(&key)),
+						"stored original is equal to the current rust-version",
+						"remove the entry from originals",
+					);
+				}
+			}
+			let mut found = Vec::new();
+			originals(stored, &mut Vec::new(), &mut found);
+			for (key, original) in found {
+				let location = format!("{relative}: {}", display_key(&key));
+				match get_item(doc.as_item(), key.iter().map(String::as_str)) {
+					None if original.is_remove() => {}
+					Some(_) if original.is_remove() => report.problem(
+						&location,
+						"dependency was removed by deppatcher, but is present again",
+						"remove the entry from originals",
+					),
+					None => report.problem(
+						&location,
+						"original is stored for dependency, which no longer exists",
+						"remove the entry from originals, or run `deppatcher freeze`",
+					),
+					Some(dep) => {
+						let current = DirectSource::from_item(dep);
+						if current == original.with_unset_from(&current) {
+							report.problem(
+								&location,
+								"stored original is equal to the current source",
+								"remove the entry from originals, or run `deppatcher freeze`",
+							);
+						}
+					}
+				}
+			}
+		}
+
+		let dir = manifest.parent().expect("manifest is a file");
+		for (key, input) in dependencies(&manifest)? {
+			let Some(path) = &input.source.path else {
+				continue;
+			};
+			if !dir.join(path).join("Cargo.toml").is_file() {
+				report.problem(
+					&format!("{relative}: {}", display_key(&key)),
+					&format!("path dependency points at missing crate {path}"),
+					"fix the path, or `deppatcher revert` the override",
+				);
+			}
+		}
+	}
+
+	// Metadata also refreshes the lockfile, so the unused list is up to date
+	let graph = graph::load(root).ok();
+	for unused in unused_patches(root, graph.as_ref())? {
+		report.problem(
+			"Cargo.lock",
+			&format!(
+				"patch for {} {} is not used by cargo ({})",
+				unused.name, unused.version, unused.reason
+			),
+			"make sure patched version matches dependency requirements, or remove the patch",
+		);
+	}
+
+	if report.problems == 0 {
+		println!("no problems found");
+	}
+	Ok(report.problems == 0)
+}
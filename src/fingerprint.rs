@@ -0,0 +1,125 @@
+//! Incremental runs: manifests unchanged since they were processed with the same rules are skipped
+
+use std::{
+	any::Any,
+	cell::RefCell,
+	collections::{BTreeMap, BTreeSet},
+	env, fs,
+	path::{Path, PathBuf},
+};
+
+use jrsonnet_evaluator::{parser::SourcePath, FileImportResolver, ImportResolver, Result};
+use jrsonnet_gcmodule::Trace;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{state, ToRuntime};
+
+thread_local! {
+	/// Files loaded by the evaluator during this run, see [`TrackingImportResolver`]
+	static IMPORTED: RefCell<BTreeSet<PathBuf>> = RefCell::default();
+}
+
+/// File resolver, which records every loaded file, so changes of imported libraries invalidate fingerprints
+#[derive(Trace, Default)]
+pub struct TrackingImportResolver(FileImportResolver);
+impl ImportResolver for TrackingImportResolver {
+	fn resolve_from(&self, from: &SourcePath, path: &str) -> Result<SourcePath> {
+		self.0.resolve_from(from, path)
+	}
+	fn resolve_from_default(&self, path: &str) -> Result<SourcePath> {
+		self.0.resolve_from_default(path)
+	}
+	fn resolve(&self, path: &Path) -> Result<SourcePath> {
+		self.0.resolve(path)
+	}
+	fn load_file_contents(&self, resolved: &SourcePath) -> Result<Vec<u8>> {
+		if let Some(path) = resolved.path() {
+			IMPORTED.with_borrow_mut(|i| i.insert(path.to_owned()));
+		}
+		self.0.load_file_contents(resolved)
+	}
+	fn as_any(&self) -> &dyn Any {
+		self
+	}
+}
+
+fn hash_file(path: &Path) -> Option<String> {
+	Some(format!("{:x}", Sha256::digest(fs::read(path).ok()?)))
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Cache {
+	/// Hashes of the files imported by rules, keyed by path
+	imports: BTreeMap<PathBuf, String>,
+	/// Fingerprints, keyed by manifest path
+	entries: BTreeMap<String, String>,
+}
+
+/// Fingerprints of processed manifests, stored in `.deppatcher/cache`
+pub struct Fingerprints {
+	path: PathBuf,
+	/// Command line and hash of the rule files, the same manifest processed differently has a different fingerprint
+	run: String,
+	cache: Cache,
+}
+impl Fingerprints {
+	/// `rule_hash` covers rule files content, which is not a part of the command line
+	pub fn load(root: &Path, rule_hash: &str) -> Result<Self> {
+		let path = state::dir(root)?.join("cache");
+		// Cache is only an optimization, unreadable one is started over
+		let mut cache: Cache = fs::read_to_string(&path)
+			.ok()
+			.and_then(|c| serde_json::from_str(&c).ok())
+			.unwrap_or_default();
+		// Imported files are only known after evaluation, so they are checked against the previous run instead
+		if cache
+			.imports
+			.iter()
+			.any(|(import, hash)| hash_file(import).as_ref() != Some(hash))
+		{
+			cache = Cache::default();
+		}
+		let args = env::args().skip(1).collect::<Vec<_>>();
+		Ok(Self {
+			path,
+			run: format!("{rule_hash} {args:?}"),
+			cache,
+		})
+	}
+
+	fn fingerprint(&self, manifest: &Path) -> Result<String> {
+		let mut hasher = Sha256::new();
+		hasher.update(&self.run);
+		hasher.update(fs::read(manifest).run_err()?);
+		Ok(format!("{:x}", hasher.finalize()))
+	}
+
+	fn key(manifest: &Path) -> String {
+		manifest.to_string_lossy().into_owned()
+	}
+
+	/// Whether manifest wasn't changed since it was last processed by the same run
+	pub fn is_fresh(&self, manifest: &Path) -> Result<bool> {
+		let Some(stored) = self.cache.entries.get(&Self::key(manifest)) else {
+			return Ok(false);
+		};
+		Ok(*stored == self.fingerprint(manifest)?)
+	}
+
+	/// Records the current state of the processed manifest
+	pub fn update(&mut self, manifest: &Path) -> Result<()> {
+		let fingerprint = self.fingerprint(manifest)?;
+		self.cache.entries.insert(Self::key(manifest), fingerprint);
+		Ok(())
+	}
+
+	pub fn save(&mut self) -> Result<()> {
+		for import in IMPORTED.with_borrow(Clone::clone) {
+			if let Some(hash) = hash_file(&import) {
+				self.cache.imports.insert(import, hash);
+			}
+		}
+		fs::write(&self.path, serde_json::to_string(&self.cache).run_err()?).run_err()
+	}
+}
@@ -0,0 +1,140 @@
+//! `fork` command: clones repository of the dependency at the resolved version, and links it
+
+use std::{collections::BTreeMap, fs, path::Path};
+
+use guppy::graph::{ExternalSource, PackageMetadata};
+use jrsonnet_evaluator::{bail, Result};
+use serde::Deserialize;
+use toml_edit::{Document, Item};
+use tracing::{info, warn};
+
+use crate::{copy::LocalCopy, get_item, git, graph, natives::path_to_string, ToRuntime};
+
+/// `.cargo_vcs_info.json`, written by `cargo package` into the published crate
+#[derive(Deserialize)]
+struct VcsInfo {
+	git: VcsGit,
+	#[serde(default)]
+	path_in_vcs: String,
+}
+#[derive(Deserialize)]
+struct VcsGit {
+	sha1: String,
+}
+
+/// Repository, revision and crate directory inside of the repository, if they are known
+fn origin(pkg: &PackageMetadata<'_>) -> Result<(String, Option<String>, Option<String>)> {
+	if let Some(ExternalSource::Git {
+		repository,
+		resolved,
+		..
+	}) = pkg.source().parse_external()
+	{
+		return Ok((repository.to_owned(), Some(resolved.to_owned()), None));
+	}
+	let Some(repository) = pkg.repository() else {
+		bail!("{} doesn't specify its repository", pkg.name());
+	};
+	let vcs_info = pkg
+		.manifest_path()
+		.parent()
+		.expect("manifest is a file")
+		.join(".cargo_vcs_info.json");
+	let vcs_info = match fs::read_to_string(vcs_info) {
+		Ok(info) => Some(serde_json::from_str::<VcsInfo>(&info).run_err()?),
+		Err(_) => None,
+	};
+	Ok((
+		repository.to_owned(),
+		vcs_info.as_ref().map(|i| i.git.sha1.clone()),
+		vcs_info.map(|i| i.path_in_vcs),
+	))
+}
+
+/// Release tag of the version, following common naming conventions
+fn release_tag(clone: &Path, name: &str, version: &str) -> Option<String> {
+	[
+		format!("v{version}"),
+		version.to_owned(),
+		format!("{name}-v{version}"),
+		format!("{name}-{version}"),
+		format!("{name}@{version}"),
+		format!("{name}@v{version}"),
+	]
+	.into_iter()
+	.find(|tag| {
+		git::git(
+			clone,
+			&["rev-parse", "--verify", "-q", &format!("refs/tags/{tag}")],
+		)
+		.is_ok()
+	})
+}
+
+/// Directory of the package in the repository, when crate doesn't record it
+fn find_package(clone: &Path, name: &str) -> Result<Option<String>> {
+	let walk = walkdir::WalkDir::new(clone)
+		.into_iter()
+		.filter_entry(|e| e.file_name() != ".git" && e.file_name() != "target");
+	for entry in walk {
+		let entry = entry.run_err()?;
+		if entry.file_name() != "Cargo.toml" {
+			continue;
+		}
+		let doc: Document = fs::read_to_string(entry.path())
+			.run_err()?
+			.parse()
+			.run_err()?;
+		if get_item(doc.as_item(), ["package", "name"]).and_then(Item::as_str) == Some(name) {
+			let dir = entry.path().parent().expect("manifest is a file");
+			return Ok(Some(path_to_string(dir.strip_prefix(clone).run_err()?)?));
+		}
+	}
+	Ok(None)
+}
+
+/// Clones repository of the locked package (`name` or `name@version`) into `dir/<name>`, checking out the resolved version
+///
+/// Existing clone is reused as is. Returns the crate directory keyed by package name
+pub fn fork(root: &Path, spec: &str, dir: &Path) -> Result<BTreeMap<String, LocalCopy>> {
+	let graph = graph::load(root)?;
+	let pkg = graph::find_locked(&graph, spec, |p| !p.source().is_local())?;
+	let name = pkg.name();
+	let version = pkg.version().to_string();
+	let (repository, rev, path_in_vcs) = origin(&pkg)?;
+
+	let clone = root.join(dir).join(name);
+	if clone.exists() {
+		info!("{} is already cloned", clone.display());
+	} else {
+		info!("cloning {repository} to {}", clone.display());
+		fs::create_dir_all(clone.parent().expect("clone is in directory")).run_err()?;
+		git::git(root, &["clone", &repository, &path_to_string(&clone)?])?;
+		let rev = rev.or_else(|| release_tag(&clone, name, &version));
+		if let Some(rev) = rev {
+			info!("checking out {rev}");
+			git::git(
+				&clone,
+				&["checkout", "-q", "-b", &format!("fork-{version}"), &rev],
+			)?;
+		} else {
+			warn!("no tag is found for {name} {version}, keeping the default branch checked out");
+		}
+	}
+
+	let crate_dir = match path_in_vcs {
+		Some(path) => Some(path),
+		None => find_package(&clone, name)?,
+	};
+	let Some(crate_dir) = crate_dir else {
+		bail!("{name} is not found in {}", clone.display());
+	};
+	let crate_dir = clone.join(crate_dir);
+	Ok(BTreeMap::from([(
+		name.to_owned(),
+		LocalCopy {
+			version,
+			path: path_to_string(&crate_dir.canonicalize().run_err()?)?,
+		},
+	)]))
+}
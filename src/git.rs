@@ -0,0 +1,115 @@
+//! Helpers for invoking git
+
+use std::{
+	path::{Path, PathBuf},
+	process::Command,
+};
+
+use jrsonnet_evaluator::{bail, Result};
+use tracing::info;
+
+use crate::ToRuntime;
+
+/// Runs git command, returning its trimmed stdout
+pub fn git(dir: &Path, args: &[&str]) -> Result<String> {
+	let out = Command::new("git")
+		.current_dir(dir)
+		.args(args)
+		.output()
+		.run_err()?;
+	if !out.status.success() {
+		bail!(
+			"git {} failed: {}",
+			args.join(" "),
+			String::from_utf8_lossy(&out.stderr).trim()
+		);
+	}
+	Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+/// Root of the repository containing the directory, if any
+pub fn toplevel(dir: &Path) -> Result<Option<PathBuf>> {
+	let Ok(toplevel) = git(dir, &["rev-parse", "--show-toplevel"]) else {
+		return Ok(None);
+	};
+	Ok(Some(Path::new(&toplevel).canonicalize().run_err()?))
+}
+
+/// Files, which belong to the same repository as the directory
+fn in_repository(dir: &Path, files: &[PathBuf]) -> Result<Vec<String>> {
+	let Some(toplevel) = toplevel(dir)? else {
+		return Ok(vec![]);
+	};
+	let mut out = Vec::new();
+	for file in files {
+		if file.canonicalize().run_err()?.starts_with(&toplevel) {
+			out.push(file.to_string_lossy().into_owned());
+		}
+	}
+	Ok(out)
+}
+
+/// Commits current state of specified files (and only them), if they have any changes
+///
+/// Files outside of the repository containing root are skipped
+pub fn checkpoint(root: &Path, files: &[PathBuf], message: &str) -> Result<()> {
+	let files = in_repository(root, files)?;
+	if files.is_empty() {
+		return Ok(());
+	}
+	let with_files = |args: &[&'static str]| {
+		let mut args = args.to_vec();
+		args.push("--");
+		args.extend(files.iter().map(String::as_str));
+		args
+	};
+
+	let subject = message.lines().next().unwrap_or_default();
+
+	git(root, &with_files(&["add"]))?;
+	let unchanged = Command::new("git")
+		.current_dir(root)
+		.args(with_files(&["diff", "--cached", "--quiet"]))
+		.status()
+		.run_err()?
+		.success();
+	if unchanged {
+		info!("no manifest changes, skipping commit \"{subject}\"");
+		return Ok(());
+	}
+	let mut commit = vec!["commit", "--quiet", "-m", message];
+	commit.extend(with_files(&[]));
+	git(root, &commit)?;
+	info!("committed \"{subject}\"");
+	Ok(())
+}
+
+/// Lists specified files, which have uncommitted changes
+///
+/// Files outside of git repositories are never considered dirty
+pub fn dirty(files: &[PathBuf]) -> Result<Vec<String>> {
+	let mut out = Vec::new();
+	for file in files {
+		let dir = file.parent().expect("file has parent");
+		let name = file.to_string_lossy();
+		if in_repository(dir, std::slice::from_ref(file))?.is_empty() {
+			continue;
+		}
+		if !git(dir, &["status", "--porcelain", "--", &name])?.is_empty() {
+			out.push(name.into_owned());
+		}
+	}
+	Ok(out)
+}
+
+/// Refuses to continue, if any of the files, which are about to be modified, have uncommitted changes
+pub fn ensure_clean(files: &[PathBuf]) -> Result<()> {
+	let dirty = dirty(files)?;
+	if !dirty.is_empty() {
+		bail!(
+			"manifests have uncommitted changes, commit them first, or pass --allow-dirty:\n{}",
+			dirty.join("\n")
+		);
+	}
+	Ok(())
+}
@@ -0,0 +1,262 @@
+//! Resolved dependency graph access, backed by guppy
+
+use std::{collections::HashMap, path::Path};
+
+use cargo_lock::{package::GitReference, Lockfile};
+
+use guppy::graph::{ExternalSource, GitReq, PackageGraph, PackageMetadata, PackageSource};
+use jrsonnet_evaluator::{
+	bail,
+	function::{builtin, CallLocation},
+	typed::Typed,
+	val::ArrValue,
+	ObjValue, ObjValueBuilder, Result, Val,
+};
+
+use crate::{caller_dir, cargo_flags, natives, timings, DirectSource, ToRuntime};
+
+pub fn load(dir: &Path) -> Result<PackageGraph> {
+	let mut command = guppy::MetadataCommand::new();
+	command.current_dir(dir);
+	exec(command)
+}
+
+/// Graph of the workspace, which contains specified manifest
+pub fn load_manifest(manifest: &Path) -> Result<PackageGraph> {
+	let mut command = guppy::MetadataCommand::new();
+	command.manifest_path(manifest);
+	exec(command)
+}
+
+fn exec(mut command: guppy::MetadataCommand) -> Result<PackageGraph> {
+	command.other_options(cargo_flags());
+	timings::measure(timings::Phase::Metadata, || command.exec())
+		.run_err()?
+		.build_graph()
+		.run_err()
+}
+
+const CRATES_IO: &str = "https://github.com/rust-lang/crates.io-index";
+const CRATES_IO_SPARSE: &str = "sparse+https://index.crates.io";
+
+/// Registry url without the trailing slash and `.git`, git indexes without the `registry+` prefix
+pub fn canonical_registry(url: &str) -> &str {
+	let url = url.strip_prefix("registry+").unwrap_or(url);
+	let url = url.trim_end_matches('/');
+	url.strip_suffix(".git").unwrap_or(url)
+}
+
+/// Whether the registry url is either the git or the sparse index of crates.io
+pub fn is_crates_io(url: &str) -> bool {
+	let url = canonical_registry(url);
+	url.eq_ignore_ascii_case(CRATES_IO) || url.eq_ignore_ascii_case(CRATES_IO_SPARSE)
+}
+
+/// Source, from which package was resolved
+pub fn source_of(pkg: PackageMetadata<'_>) -> DirectSource {
+	let source = pkg.source();
+	let es = source.parse_external();
+	let git = match es {
+		Some(ExternalSource::Git {
+			repository,
+			req,
+			resolved,
+		}) => Some((repository.to_string(), req, resolved)),
+		_ => None,
+	};
+	DirectSource {
+		version: Some(pkg.version().to_string()),
+		registry: match es {
+			Some(ExternalSource::Registry(r)) => Some(r.to_string()),
+			// guppy only knows git indexes, sparse ones keep their prefix, as in `[patch]` keys
+			None => match source {
+				PackageSource::External(s) if s.starts_with("sparse+") => Some(s.to_string()),
+				_ => None,
+			},
+			_ => None,
+		},
+		path: source.local_path().map(ToString::to_string),
+		git: git.as_ref().map(|(r, _, _)| r.clone()),
+		rev: git.as_ref().and_then(|(_, e, _)| match e {
+			GitReq::Rev(e) => Some((*e).to_string()),
+			_ => None,
+		}),
+		tag: git.as_ref().and_then(|(_, e, _)| match e {
+			GitReq::Tag(t) => Some((*t).to_string()),
+			_ => None,
+		}),
+		branch: git.as_ref().and_then(|(_, e, _)| match e {
+			GitReq::Branch(b) => Some((*b).to_string()),
+			_ => None,
+		}),
+		registry_index: None,
+		workspace: None,
+		artifact: None,
+		lib: None,
+		target: None,
+		public: None,
+		features: None,
+		remove: None,
+		name: None,
+		package: None,
+		extra: None,
+	}
+}
+
+/// The only locked package matching `name` or `name@version` spec, and the filter
+pub fn find_locked<'g>(
+	graph: &'g PackageGraph,
+	spec: &str,
+	filter: impl Fn(&PackageMetadata<'g>) -> bool,
+) -> Result<PackageMetadata<'g>> {
+	let (name, version) = spec
+		.split_once('@')
+		.map_or((spec, None), |(n, v)| (n, Some(v)));
+	let candidates = graph
+		.packages()
+		.filter(|p| {
+			p.name() == name && version.is_none_or(|v| p.version().to_string() == v) && filter(p)
+		})
+		.collect::<Vec<_>>();
+	match candidates.as_slice() {
+		[] => bail!("{spec} is not locked"),
+		[pkg] => Ok(*pkg),
+		_ => bail!(
+			"multiple versions of {name} are locked ({}), specify one as {name}@<version>",
+			candidates
+				.iter()
+				.map(|p| p.version().to_string())
+				.collect::<Vec<_>>()
+				.join(", ")
+		),
+	}
+}
+
+/// Source of the locked package, `None` for path dependencies, as lockfile doesn't record their paths
+pub fn lock_source(pkg: &cargo_lock::Package) -> Option<DirectSource> {
+	let source = pkg.source.as_ref()?;
+	let git = source.git_reference();
+	Some(DirectSource {
+		version: Some(pkg.version.to_string()),
+		registry: (source.is_registry() && !source.is_default_registry())
+			.then(|| source.url().to_string()),
+		git: source.is_git().then(|| source.url().to_string()),
+		rev: match git {
+			Some(GitReference::Rev(r)) => Some(r.clone()),
+			_ => None,
+		},
+		tag: match git {
+			Some(GitReference::Tag(t)) => Some(t.clone()),
+			_ => None,
+		},
+		branch: match git {
+			Some(GitReference::Branch(b)) => Some(b.clone()),
+			_ => None,
+		},
+		..DirectSource::default()
+	})
+}
+
+/// Package sources recorded in the reference lockfile, used to guess the original sources of patched packages
+pub struct Baseline(HashMap<String, Vec<DirectSource>>);
+impl Baseline {
+	pub fn load(lockfile: &Path) -> Result<Self> {
+		let lockfile = Lockfile::load(lockfile).run_err()?;
+		let mut out = <HashMap<_, Vec<_>>>::new();
+		for pkg in &lockfile.packages {
+			let Some(source) = lock_source(pkg) else {
+				continue;
+			};
+			out.entry(pkg.name.as_str().to_owned())
+				.or_default()
+				.push(source);
+		}
+		Ok(Self(out))
+	}
+
+	pub fn map_sources(mut self, f: impl Fn(DirectSource) -> DirectSource) -> Self {
+		for sources in self.0.values_mut() {
+			for source in sources {
+				*source = f(std::mem::take(source));
+			}
+		}
+		self
+	}
+
+	/// Source of the package in the baseline, falls back to the current source if it can't be determined
+	///
+	/// If there are multiple packages with the same name, the one with the same version is picked
+	pub fn original(&self, name: &str, current: &DirectSource) -> DirectSource {
+		let Some(candidates) = self.0.get(name) else {
+			return current.clone();
+		};
+		if candidates.contains(current) {
+			return current.clone();
+		}
+		match candidates.as_slice() {
+			[single] => single.clone(),
+			_ => candidates
+				.iter()
+				.find(|c| c.version == current.version)
+				.unwrap_or(current)
+				.clone(),
+		}
+	}
+}
+
+fn package_to_val(pkg: PackageMetadata<'_>) -> Result<Val> {
+	let mut out = ObjValueBuilder::new();
+	out.field("id").value(pkg.id().repr());
+	out.field("name").value(pkg.name());
+	out.field("version").value(pkg.version().to_string());
+	out.field("source")
+		.try_value(DirectSource::into_untyped(source_of(pkg))?)?;
+	out.field("manifestPath")
+		.value(pkg.manifest_path().as_str());
+	out.field("inWorkspace")
+		.value(Val::Bool(pkg.in_workspace()));
+	let dependencies = pkg
+		.direct_links()
+		.map(|link| {
+			let mut dep = ObjValueBuilder::new();
+			dep.field("package").value(link.to().id().repr());
+			dep.field("name").value(link.dep_name());
+			dep.field("normal")
+				.value(Val::Bool(link.normal().is_present()));
+			dep.field("build")
+				.value(Val::Bool(link.build().is_present()));
+			dep.field("dev").value(Val::Bool(link.dev().is_present()));
+			Val::Obj(dep.build())
+		})
+		.collect::<ArrValue>();
+	out.field("dependencies").value(Val::Arr(dependencies));
+	Ok(Val::Obj(out.build()))
+}
+
+/// Full resolved graph of the workspace, packages are keyed by package id
+#[builtin]
+pub fn load_graph(loc: CallLocation, workspace: Option<String>) -> Result<ObjValue> {
+	let mut path = caller_dir(loc)?;
+	if let Some(workspace) = workspace {
+		path.push(workspace);
+	}
+	let path = path.canonicalize().unwrap_or(path);
+	let graph = natives::cached(format!("loadGraph {}", path.display()), || load(&path))?;
+
+	let mut packages = ObjValueBuilder::new();
+	for pkg in graph.packages() {
+		packages
+			.field(pkg.id().repr())
+			.try_value(package_to_val(pkg)?)?;
+	}
+	let members = graph
+		.workspace()
+		.iter()
+		.map(|pkg| Val::from(pkg.id().repr()))
+		.collect::<ArrValue>();
+
+	let mut out = ObjValueBuilder::new();
+	out.field("packages").value(Val::Obj(packages.build()));
+	out.field("workspaceMembers").value(Val::Arr(members));
+	Ok(out.build())
+}
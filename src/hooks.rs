@@ -0,0 +1,75 @@
+//! `install-hooks` command: git hooks, which prevent committing manifests with overrides
+
+use std::{fs, path::Path};
+
+use clap::ValueEnum;
+use jrsonnet_evaluator::{bail, Result};
+
+use crate::{git::git, ToRuntime};
+
+/// Marker, by which hooks installed by deppatcher are recognized
+const HOOK_MARKER: &str = "# deppatcher:hook";
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum Hook {
+	PreCommit,
+	PrePush,
+}
+impl Hook {
+	const fn file_name(self) -> &'static str {
+		match self {
+			Self::PreCommit => "pre-commit",
+			Self::PrePush => "pre-push",
+		}
+	}
+}
+
+/// Single-quoted shell word, quotes inside are closed, escaped and reopened
+fn shell_quote(s: &str) -> String {
+	format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn script(workspace: &str) -> String {
+	let workspace = shell_quote(workspace);
+	format!(
+		"#!/bin/sh\n\
+		{HOOK_MARKER}\n\
+		# Installed by `deppatcher install-hooks`, reinstall to update\n\
+		cd \"$(git rev-parse --show-toplevel)\"/{workspace} || exit 1\n\
+		exec deppatcher verify-clean\n"
+	)
+}
+
+pub fn install_hooks(root: &Path, hooks: &[Hook], force: bool) -> Result<()> {
+	let toplevel = git(root, &["rev-parse", "--show-toplevel"])?;
+	let hooks_dir = root.join(git(root, &["rev-parse", "--git-path", "hooks"])?);
+	let workspace = root
+		.canonicalize()
+		.run_err()?
+		.strip_prefix(Path::new(&toplevel).canonicalize().run_err()?)
+		.run_err()?
+		.to_string_lossy()
+		.replace('\\', "/");
+
+	fs::create_dir_all(&hooks_dir).run_err()?;
+	let hooks_dir = hooks_dir.canonicalize().run_err()?;
+	for hook in hooks {
+		let path = hooks_dir.join(hook.file_name());
+		if let Ok(existing) = fs::read_to_string(&path) {
+			if !existing.contains(HOOK_MARKER) && !force {
+				bail!(
+					"{} already exists, and was not installed by deppatcher, use --force to overwrite",
+					path.display()
+				);
+			}
+		}
+		fs::write(&path, script(&workspace)).run_err()?;
+		#[cfg(unix)]
+		{
+			use std::os::unix::fs::PermissionsExt;
+			fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).run_err()?;
+		}
+		println!("installed {}", path.display());
+	}
+	Ok(())
+}
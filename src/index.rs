@@ -0,0 +1,95 @@
+//! Minimal client for the crates.io [sparse index](https://doc.rust-lang.org/cargo/reference/registry-index.html)
+
+use jrsonnet_evaluator::{
+	bail,
+	function::builtin,
+	typed::{Either2, Null},
+	Either, Result,
+};
+use semver::{Version, VersionReq};
+use serde::Deserialize;
+
+use crate::{natives, ToRuntime};
+
+const SPARSE_URL: &str = "https://index.crates.io";
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct IndexVersion {
+	pub vers: Version,
+	#[serde(default)]
+	pub yanked: bool,
+	/// MSRV declared by the package, if any
+	#[serde(default)]
+	pub rust_version: Option<String>,
+}
+impl IndexVersion {
+	/// Whether package builds with the toolchain, packages with no declared MSRV are assumed to
+	pub fn supports(&self, rust_version: &Version) -> bool {
+		self.rust_version
+			.as_deref()
+			.and_then(parse_rust_version)
+			.is_none_or(|msrv| msrv <= *rust_version)
+	}
+}
+
+/// `1.70` and `1.70.0` forms of the toolchain version
+pub fn parse_rust_version(v: &str) -> Option<Version> {
+	let mut parts = v.trim().split('.').map(str::parse::<u64>);
+	let major = parts.next()?.ok()?;
+	let minor = parts.next().transpose().ok()?.unwrap_or(0);
+	let patch = parts.next().transpose().ok()?.unwrap_or(0);
+	if parts.next().is_some() {
+		return None;
+	}
+	Some(Version::new(major, minor, patch))
+}
+
+fn index_path(name: &str) -> String {
+	let name = name.to_lowercase();
+	match name.len() {
+		1 => format!("1/{name}"),
+		2 => format!("2/{name}"),
+		3 => format!("3/{}/{name}", &name[..1]),
+		_ => format!("{}/{}/{name}", &name[..2], &name[2..4]),
+	}
+}
+
+/// All published versions of the package, in publication order
+pub fn versions(name: &str) -> Result<Vec<IndexVersion>> {
+	let url = format!("{SPARSE_URL}/{}", index_path(name));
+	let body = match ureq::get(&url).call() {
+		Ok(r) => r.into_string().run_err()?,
+		Err(ureq::Error::Status(404, _)) => bail!("package {name} is not found in the index"),
+		Err(e) => bail!("failed to query index for {name}: {e}"),
+	};
+	body.lines()
+		.filter(|l| !l.trim().is_empty())
+		.map(|l| serde_json::from_str(l).run_err())
+		.collect()
+}
+
+/// Newest non-yanked version matching the requirement, which supports specified toolchain
+// Parameter names are visible to jsonnet, where named arguments are camelCase
+#[allow(non_snake_case)]
+#[builtin]
+pub fn max_version_for(
+	name: String,
+	req: String,
+	rustVersion: Option<String>,
+) -> Result<Either![Null, String]> {
+	let req = VersionReq::parse(&req).run_err()?;
+	let rust_version = match rustVersion.as_deref().map(|v| (v, parse_rust_version(v))) {
+		None => None,
+		Some((_, Some(v))) => Some(v),
+		Some((v, None)) => bail!("invalid rust version {v}"),
+	};
+	// Rules are called for every dependency, the same package is queried once per run
+	let versions = natives::cached(format!("maxVersionFor {name}"), || versions(&name))?;
+	Ok(versions
+		.iter()
+		.filter(|v| !v.yanked && req.matches(&v.vers))
+		.filter(|v| rust_version.as_ref().is_none_or(|r| v.supports(r)))
+		.map(|v| &v.vers)
+		.max()
+		.map_or(Either2::A(Null), |v| Either2::B(v.to_string())))
+}
@@ -0,0 +1,61 @@
+//! `--jobs`: how many network requests and external commands (git fetches, index queries, prefetches) run at once
+//!
+//! Rules are always evaluated sequentially, evaluator state can't be shared between threads
+
+use std::{
+	env,
+	sync::{
+		atomic::{AtomicUsize, Ordering},
+		Mutex, OnceLock,
+	},
+	thread,
+};
+
+use jrsonnet_evaluator::Result;
+
+use crate::ToRuntime;
+
+static JOBS: OnceLock<usize> = OnceLock::new();
+
+/// `--jobs` if passed, otherwise `CARGO_BUILD_JOBS`, otherwise number of available cores
+pub fn init(jobs: Option<usize>) {
+	let jobs = jobs
+		.or_else(|| env::var("CARGO_BUILD_JOBS").ok()?.parse().ok())
+		.unwrap_or_else(|| thread::available_parallelism().map_or(1, usize::from));
+	assert!(JOBS.set(jobs.max(1)).is_ok(), "jobs are initialized once");
+}
+
+fn count() -> usize {
+	*JOBS.get_or_init(|| 1)
+}
+
+/// Calls `f` for every item, at most `--jobs` at a time, results are returned in the order of items
+///
+/// Errors are reported after every item is processed, the first one wins
+pub fn map<T: Sync, R: Send>(items: &[T], f: impl Fn(&T) -> Result<R> + Sync) -> Result<Vec<R>> {
+	let workers = count().min(items.len());
+	if workers <= 1 {
+		return items.iter().map(f).collect();
+	}
+	let next = AtomicUsize::new(0);
+	let results = Mutex::new(items.iter().map(|_| None).collect::<Vec<_>>());
+	thread::scope(|s| {
+		for _ in 0..workers {
+			s.spawn(|| loop {
+				let i = next.fetch_add(1, Ordering::Relaxed);
+				let Some(item) = items.get(i) else {
+					break;
+				};
+				// Evaluator errors are bound to the thread they were created in
+				let result = f(item).map_err(|e| e.to_string());
+				results.lock().expect("worker doesn't panic")[i] = Some(result);
+			});
+		}
+	});
+	results
+		.into_inner()
+		.expect("worker doesn't panic")
+		.into_iter()
+		.map(|r| r.expect("every item is processed").run_err())
+		.collect()
+}
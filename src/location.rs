@@ -0,0 +1,68 @@
+//! Lines of dependency entries in the manifest being patched, for log messages
+//!
+//! `toml_edit` doesn't expose spans of the parsed document, so manifest source is scanned for
+//! table headers and keys instead. Lines inside of multiline strings and arrays might be
+//! mistaken for keys, only the first occurrence of the key is recorded, so this rarely matters
+
+use std::{cell::RefCell, collections::HashMap};
+
+use toml_edit::Key;
+
+thread_local! {
+	static LINES: RefCell<HashMap<Vec<String>, usize>> = RefCell::default();
+}
+
+/// Guard, which makes lines of the manifest source available to [`line_of`]
+pub struct Lines(HashMap<Vec<String>, usize>);
+impl Lines {
+	pub fn enter(source: &str) -> Self {
+		Self(LINES.with_borrow_mut(|l| std::mem::replace(l, key_lines(source))))
+	}
+}
+impl Drop for Lines {
+	fn drop(&mut self) {
+		let previous = std::mem::take(&mut self.0);
+		LINES.with_borrow_mut(|l| *l = previous);
+	}
+}
+
+/// 1-based line, at which the table or the key was declared in the current manifest
+pub fn line_of(key: &[String]) -> Option<usize> {
+	LINES.with_borrow(|l| l.get(key).copied())
+}
+
+fn parse_key(repr: &str) -> Option<Vec<String>> {
+	let keys = Key::parse(repr.trim()).ok()?;
+	Some(keys.iter().map(|k| k.get().to_owned()).collect())
+}
+
+/// Header of the `[table]` or `[[array]]` line, comment after the header might contain `]`,
+/// so the shortest prefix which is a valid key wins
+fn parse_header(line: &str) -> Option<Vec<String>> {
+	let inner = line.strip_prefix("[[").or_else(|| line.strip_prefix('['))?;
+	inner
+		.match_indices(']')
+		.find_map(|(end, _)| parse_key(&inner[..end]))
+}
+
+fn key_lines(source: &str) -> HashMap<Vec<String>, usize> {
+	let mut out = HashMap::new();
+	let mut table = Vec::new();
+	for (i, line) in source.lines().enumerate() {
+		let line = line.trim();
+		if line.starts_with('[') {
+			if let Some(header) = parse_header(line) {
+				out.entry(header.clone()).or_insert(i + 1);
+				table = header;
+			}
+		} else if let Some((key, _)) = line.split_once('=') {
+			// Dotted keys (`serde.workspace = true`) declare the entry named by the first part
+			if let Some(first) = parse_key(key).and_then(|k| k.into_iter().next()) {
+				let mut key = table.clone();
+				key.push(first);
+				out.entry(key).or_insert(i + 1);
+			}
+		}
+	}
+	out
+}
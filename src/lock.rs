@@ -0,0 +1,39 @@
+//! Advisory lock, preventing concurrent deppatcher runs from modifying the same tree
+
+use std::{
+	fs::{File, OpenOptions, TryLockError},
+	path::Path,
+};
+
+use jrsonnet_evaluator::{bail, Result};
+use tracing::info;
+
+use crate::{state, ToRuntime};
+
+/// Held lock, released on drop
+pub struct Lock {
+	_file: File,
+}
+
+/// Locks `.deppatcher/lock` in the workspace root, failing if it is held by another process, unless `wait` is set
+pub fn acquire(root: &Path, wait: bool) -> Result<Lock> {
+	let file = OpenOptions::new()
+		.create(true)
+		.truncate(false)
+		.write(true)
+		.open(state::dir(root)?.join("lock"))
+		.run_err()?;
+	match file.try_lock() {
+		Ok(()) => {}
+		Err(TryLockError::WouldBlock) if wait => {
+			info!("waiting for another deppatcher instance to finish");
+			file.lock().run_err()?;
+		}
+		Err(TryLockError::WouldBlock) => bail!(
+			"another deppatcher instance is operating on {}, pass --wait to wait for it",
+			root.display()
+		),
+		Err(TryLockError::Error(e)) => return Err(e).run_err(),
+	}
+	Ok(Lock { _file: file })
+}
@@ -0,0 +1,148 @@
+//! `lock-hashes` command: content hashes of overridden sources, to verify every developer links the same bits
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	fs,
+	path::{Path, PathBuf},
+	process,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use jrsonnet_evaluator::{bail, Result};
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::{dependencies, git::git, jobs, manifests, state::TempDir, DirectSource, ToRuntime};
+
+/// SRI sha256 hash of directory content
+///
+/// Files are hashed in path order, together with their relative paths, build output and VCS metadata is skipped
+fn hash_dir(dir: &Path) -> Result<String> {
+	let mut hasher = Sha256::new();
+	let walk = walkdir::WalkDir::new(dir)
+		.sort_by_file_name()
+		.into_iter()
+		.filter_entry(|e| {
+			e.depth() == 0 || !matches!(e.file_name().to_str(), Some("target" | ".git"))
+		});
+	for entry in walk {
+		let entry = entry.run_err()?;
+		if !entry.file_type().is_file() {
+			continue;
+		}
+		let relative = entry
+			.path()
+			.strip_prefix(dir)
+			.expect("walking inside dir")
+			.components()
+			.map(|c| c.as_os_str().to_string_lossy())
+			.collect::<Vec<_>>()
+			.join("/");
+		let content = fs::read(entry.path()).run_err()?;
+		hasher.update(relative.as_bytes());
+		hasher.update([0]);
+		hasher.update((content.len() as u64).to_le_bytes());
+		hasher.update(&content);
+	}
+	Ok(format!("sha256-{}", STANDARD.encode(hasher.finalize())))
+}
+
+fn hash_git(root: &Path, url: &str, rev: &str) -> Result<String> {
+	info!("fetching {url} at {rev}");
+	let temp = TempDir::new(root, &format!("{}-{rev}", process::id()))?;
+	let dir = temp.content();
+	fs::create_dir_all(&dir).run_err()?;
+	git(&dir, &["init", "--quiet"])?;
+	git(&dir, &["fetch", "--quiet", "--depth", "1", url, rev])?;
+	git(&dir, &["checkout", "--quiet", "FETCH_HEAD"])?;
+	hash_dir(&dir)
+}
+
+/// Overridden source, which can be hashed
+enum Hashable {
+	Git { url: String, rev: String },
+	Path(PathBuf),
+}
+impl Hashable {
+	fn new(manifest: &Path, source: &DirectSource) -> Option<Self> {
+		if let Some(url) = &source.git {
+			let Some(rev) = &source.rev else {
+				warn!("{url} is not pinned to rev, skipping");
+				return None;
+			};
+			Some(Self::Git {
+				url: url.clone(),
+				rev: rev.clone(),
+			})
+		} else if let Some(path) = &source.path {
+			let dir = manifest.parent().expect("manifest is a file").join(path);
+			Some(Self::Path(dir.canonicalize().unwrap_or(dir)))
+		} else {
+			None
+		}
+	}
+	fn id(&self) -> String {
+		match self {
+			Self::Git { url, rev } => format!("git+{url}#{rev}"),
+			Self::Path(dir) => format!("path+{}", dir.display()),
+		}
+	}
+	fn hash(&self, root: &Path) -> Result<String> {
+		match self {
+			Self::Git { url, rev } => hash_git(root, url, rev),
+			Self::Path(dir) => hash_dir(dir),
+		}
+	}
+}
+
+/// Print hashes of every overridden git/path source, keyed by package name
+pub fn lock_hashes(root: &Path) -> Result<()> {
+	let mut sources = <BTreeMap<String, Hashable>>::new();
+	let mut used = <BTreeMap<String, BTreeSet<String>>>::new();
+	for manifest in manifests(root)? {
+		for (_, input) in dependencies(&manifest)? {
+			if input.source == input.original_source {
+				continue;
+			}
+			let Some(source) = Hashable::new(&manifest, &input.source) else {
+				continue;
+			};
+			let id = source.id();
+			used.entry(input.package).or_default().insert(id.clone());
+			sources.entry(id).or_insert(source);
+		}
+	}
+	// Sources are shared between packages, every one is only hashed once
+	let sources = sources.into_iter().collect::<Vec<_>>();
+	let hashes = jobs::map(&sources, |(_, source)| source.hash(root))?;
+	let hashes = sources
+		.into_iter()
+		.map(|(id, _)| id)
+		.zip(hashes)
+		.collect::<BTreeMap<_, _>>();
+	let packages = used.into_iter().map(|(package, ids)| {
+		let sources = ids
+			.into_iter()
+			.map(|id| {
+				let hash = hashes[&id].clone();
+				(id, hash)
+			})
+			.collect::<BTreeMap<_, _>>();
+		(package, sources)
+	});
+
+	let mut out = BTreeMap::new();
+	for (package, sources) in packages {
+		let mut unique = sources.values().collect::<Vec<_>>();
+		unique.dedup();
+		if unique.len() > 1 {
+			bail!(
+				"{package} is overridden with different sources: {}",
+				sources.keys().cloned().collect::<Vec<_>>().join(", ")
+			)
+		}
+		out.insert(package, unique[0].clone());
+	}
+	println!("{}", serde_json::to_string_pretty(&out).run_err()?);
+	Ok(())
+}
@@ -3,30 +3,87 @@
 #![doc = include_str!("../README.md")]
 
 use std::{
-	collections::{BTreeMap, HashSet},
+	cell::RefCell,
+	collections::{BTreeMap, HashMap, HashSet},
 	env::current_dir,
-	fs,
+	fmt::Write as _,
+	fs::{self, File},
 	io::{stdin, Read},
 	path::{Path, PathBuf},
+	process::ExitCode,
 	result,
 	string::ToString,
+	sync::{
+		atomic::{AtomicBool, Ordering},
+		OnceLock,
+	},
+	time::Duration,
 };
 
-use clap::Parser;
-use guppy::graph::{DependencyDirection, ExternalSource, GitReq};
+use clap::{Parser, Subcommand, ValueEnum};
+use guppy::graph::DependencyDirection;
 use jrsonnet_cli::{InputOpts, StdOpts};
 use jrsonnet_evaluator::{
 	bail,
 	error::{ErrorKind, Result},
 	function::{builtin, CallLocation, FuncVal},
-	parser::Source,
-	typed::{Either2, NativeFn, Null, Typed},
+	parser::{Source, SourceFile},
+	typed::{ComplexValType, Either2, Either3, NativeFn, Null, Typed},
 	val::StrValue,
 	ContextBuilder, ContextInitializer, Either, ObjValue, ObjValueBuilder, State, Thunk, Val,
 };
 use jrsonnet_gcmodule::Trace;
-use toml_edit::{Document, Formatted, InlineTable, Item, Table, TableLike, Value};
-use tracing::info;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use toml_edit::{Decor, Document, InlineTable, Item, KeyMut, Table, TableLike, Value};
+use tracing::{debug, info, level_filters::LevelFilter, trace, warn, Level};
+use tracing_subscriber::{
+	filter::Targets, layer::SubscriberExt as _, util::SubscriberInitExt as _,
+};
+
+mod add;
+mod advisories;
+mod apply_patch;
+mod audit;
+mod cargo_config;
+mod completions;
+mod copy;
+mod diff_lock;
+mod doctor;
+mod fingerprint;
+mod fork;
+mod git;
+mod graph;
+mod hooks;
+mod index;
+mod jobs;
+mod location;
+mod lock;
+mod lock_hashes;
+mod natives;
+mod nix;
+mod normalize_versions;
+mod outdated;
+mod paths;
+mod policy;
+mod query;
+mod registries;
+mod report;
+mod review;
+mod rust_version;
+mod sbom;
+mod serve;
+mod state;
+mod style;
+mod sync_versions;
+mod timings;
+mod tree;
+mod vendor;
+mod verify;
+mod versions;
+mod watch;
+mod why;
+mod workspace_deps;
 
 trait ToRuntime<T> {
 	fn run_err(self) -> Result<T>;
@@ -40,22 +97,176 @@ where
 	}
 }
 
-#[derive(Typed, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[derive(Typed, Serialize, Debug, Clone, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct DirectSource {
 	/// Package version, None if package is obtained not from registry
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub version: Option<String>,
 	/// None for default registry
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub registry: Option<String>,
+	/// Index url of the registry, for registries not named in the cargo config (`registry-index` key)
+	#[typed(rename = "registryIndex")]
+	#[serde(rename = "registryIndex", skip_serializing_if = "Option::is_none")]
+	pub registry_index: Option<String>,
 
 	/// Full path to package directory
 	/// (not to workspace containing this package)
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub path: Option<String>,
 
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub git: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub rev: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub tag: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub branch: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
 	pub workspace: Option<bool>,
+
+	/// Artifact kinds of the (unstable) artifact dependency, i.e `["bin", "cdylib"]`
+	///
+	/// Together with `lib` and `target`, unset ones are left as is, and the empty list removes all three keys
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub artifact: Option<Vec<String>>,
+	/// Whether library of the artifact dependency is also available
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub lib: Option<bool>,
+	/// Target triple, for which the artifact dependency is built
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub target: Option<String>,
+
+	/// Whether dependency is exposed in the public API of the crate (RFC 3516)
+	///
+	/// Left as is when unset, `false` removes the key, unless it is already declared as `false`
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub public: Option<bool>,
+
+	/// Enabled features, unset ones are left as is, and the empty list removes the `features` key
+	///
+	/// Only stored in originals, if they were changed
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub features: Option<Vec<String>>,
+
+	/// Remove dependency from the manifest, only meaningful as a rule result
+	///
+	/// Removed dependencies are passed to rules with this field set,
+	/// returning their original source restores them
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub remove: Option<bool>,
+
+	/// New key of the dependency entry, when returned from the rule
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	/// New `package` of the dependency entry, when returned from the rule
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub package: Option<String>,
+
+	/// Keys written verbatim into the dependency table, `null` removes the key
+	///
+	/// Escape hatch for keys not supported by deppatcher, not recorded in originals
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub extra: Option<BTreeMap<String, ExtraValue>>,
+}
+
+/// Arbitrary value of [`DirectSource::extra`]
+#[derive(Serialize, Debug, Clone)]
+#[serde(transparent)]
+pub struct ExtraValue(serde_json::Value);
+impl ExtraValue {
+	fn to_toml(&self) -> Result<Option<Value>> {
+		fn convert(v: &serde_json::Value) -> Result<Option<Value>> {
+			use serde_json::Value as Json;
+			Ok(Some(match v {
+				Json::Null => return Ok(None),
+				Json::Bool(b) => (*b).into(),
+				Json::Number(n) => n
+					.as_i64()
+					.map_or_else(|| n.as_f64().unwrap_or_default().into(), Into::into),
+				Json::String(s) => s.as_str().into(),
+				Json::Array(a) => Value::Array(
+					a.iter()
+						.filter_map(|v| convert(v).transpose())
+						.collect::<Result<_>>()?,
+				),
+				Json::Object(o) => {
+					let mut table = InlineTable::new();
+					for (k, v) in o {
+						if let Some(v) = convert(v)? {
+							table.insert(k, v);
+						}
+					}
+					Value::InlineTable(table)
+				}
+			}))
+		}
+		convert(&self.0)
+	}
+	/// Whether writing the value to `key` would leave the table unchanged
+	fn is_written_in(&self, table: &dyn TableLike, key: &str) -> bool {
+		fn convert(v: &Value) -> serde_json::Value {
+			use serde_json::Value as Json;
+			match v {
+				Value::String(s) => Json::String(s.value().clone()),
+				Value::Integer(i) => (*i.value()).into(),
+				Value::Float(f) => (*f.value()).into(),
+				Value::Boolean(b) => (*b.value()).into(),
+				Value::Datetime(d) => Json::String(d.value().to_string()),
+				Value::Array(a) => Json::Array(a.iter().map(convert).collect()),
+				Value::InlineTable(t) => {
+					Json::Object(t.iter().map(|(k, v)| (k.to_owned(), convert(v))).collect())
+				}
+			}
+		}
+		let Ok(new) = self.to_toml() else {
+			// Skipped on write
+			return true;
+		};
+		// Current value is converted the same way, so formatting differences are ignored
+		let current = table
+			.get(key)
+			.and_then(Item::as_value)
+			.map(|v| Self(convert(v)).to_toml());
+		match (new, current) {
+			(None, current) => current.is_none() && !table.contains_key(key),
+			(Some(new), Some(Ok(Some(current)))) => new.to_string() == current.to_string(),
+			_ => false,
+		}
+	}
+}
+impl Typed for ExtraValue {
+	const TYPE: &'static ComplexValType = &ComplexValType::Any;
+
+	fn into_untyped(typed: Self) -> Result<Val> {
+		Val::from_serde(typed.0).run_err()
+	}
+	fn from_untyped(untyped: Val) -> Result<Self> {
+		serde_json::to_value(&untyped).run_err().map(Self)
+	}
+}
+// Json values are not comparable, so their canonical representation is compared instead
+impl PartialEq for ExtraValue {
+	fn eq(&self, other: &Self) -> bool {
+		self.cmp(other).is_eq()
+	}
+}
+impl Eq for ExtraValue {}
+impl PartialOrd for ExtraValue {
+	fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+		Some(self.cmp(other))
+	}
+}
+impl Ord for ExtraValue {
+	fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+		self.0.to_string().cmp(&other.0.to_string())
+	}
+}
+impl std::hash::Hash for ExtraValue {
+	fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+		self.0.to_string().hash(state);
+	}
 }
 impl DirectSource {
 	fn read(table: &dyn TableLike) -> Self {
@@ -69,9 +280,61 @@ impl DirectSource {
 			tag: get("tag"),
 			branch: get("branch"),
 			registry: get("registry"),
+			registry_index: get("registry-index"),
 			workspace: get_bool("workspace"),
+			artifact: match table.get("artifact") {
+				Some(Item::Value(Value::Array(kinds))) => Some(
+					kinds
+						.iter()
+						.filter_map(Value::as_str)
+						.map(ToOwned::to_owned)
+						.collect(),
+				),
+				v => v.and_then(Item::as_str).map(|kind| vec![kind.to_owned()]),
+			},
+			lib: get_bool("lib"),
+			target: get("target"),
+			public: get_bool("public"),
+			features: table
+				.get("features")
+				.and_then(Item::as_array)
+				.map(|features| {
+					features
+						.iter()
+						.filter_map(Value::as_str)
+						.map(ToOwned::to_owned)
+						.collect()
+				}),
+			remove: None,
+			name: None,
+			package: None,
+			extra: None,
+		}
+	}
+	/// Reads stored original, which also contains name and package, if dependency was renamed
+	///
+	/// Artifact keys and `public` are always stored, so the missing ones were not declared
+	fn read_original(table: &dyn TableLike) -> Self {
+		let get = |s: &str| table.get(s).and_then(Item::as_str).map(ToOwned::to_owned);
+		let source = Self::read(table);
+		Self {
+			name: get("name"),
+			package: get("package"),
+			artifact: source.artifact.clone().or_else(|| Some(Vec::new())),
+			public: source.public.or(Some(false)),
+			..source
 		}
 	}
+	/// Reads both the `name = "version"` and the table forms
+	fn from_item(item: &Item) -> Self {
+		item.as_table_like().map_or_else(
+			|| Self {
+				version: item.as_str().map(ToOwned::to_owned),
+				..Self::default()
+			},
+			Self::read,
+		)
+	}
 	fn write(&self, table: &mut dyn TableLike) {
 		let mut set = |s: &str, v: &Option<String>| {
 			if let Some(v) = v {
@@ -81,12 +344,13 @@ impl DirectSource {
 			}
 		};
 		set("version", &self.version);
-		set("path", &self.path);
+		set("path", &self.path.as_deref().map(paths::normalize));
 		set("git", &self.git);
 		set("rev", &self.rev);
 		set("tag", &self.tag);
 		set("branch", &self.branch);
 		set("registry", &self.registry);
+		set("registry-index", &self.registry_index);
 		let mut set_bool = |s: &str, v: &Option<bool>| {
 			if let Some(v) = v {
 				table.insert(s, Item::Value((*v).into()));
@@ -95,6 +359,91 @@ impl DirectSource {
 			}
 		};
 		set_bool("workspace", &self.workspace);
+		match self.artifact.as_deref() {
+			None => {}
+			Some([]) => {
+				for key in ["artifact", "lib", "target"] {
+					table.remove(key);
+				}
+			}
+			Some(kinds) => {
+				// Single artifact kind is written in the short form
+				let kinds = match kinds {
+					[kind] => kind.into(),
+					kinds => kinds.iter().collect(),
+				};
+				table.insert("artifact", Item::Value(kinds));
+				match self.lib {
+					Some(lib) => table.insert("lib", Item::Value(lib.into())),
+					None => table.remove("lib"),
+				};
+				match &self.target {
+					Some(target) => table.insert("target", Item::Value(target.into())),
+					None => table.remove("target"),
+				};
+			}
+		}
+		match self.public {
+			Some(true) => {
+				table.insert("public", Item::Value(true.into()));
+			}
+			// Not public is the default, explicitly declared one is kept
+			Some(false) if table.get("public").and_then(Item::as_bool) != Some(false) => {
+				table.remove("public");
+			}
+			_ => {}
+		}
+		if let Some(features) = &self.features {
+			write_features(table, features);
+		}
+		for (k, v) in self.extra.iter().flatten() {
+			match v.to_toml() {
+				Ok(Some(v)) => {
+					table.insert(k, Item::Value(v));
+				}
+				Ok(None) => {
+					table.remove(k);
+				}
+				Err(e) => warn!("skipping extra key {k}: {e:?}"),
+			}
+		}
+	}
+	/// Fills fields, which are left as is when unset (features, artifact keys and `public`), from the current entry
+	///
+	/// Rules often return fresh sources, i.e `{ path: ... }`, which should not lose them.
+	/// Originals only store features if they were changed, otherwise they are the same as the current ones.
+	/// Removal of the key not declared by the current entry is the same as unset
+	fn with_unset_from(self, current: &Self) -> Self {
+		let features = match self.features {
+			Some(f) if f.is_empty() && current.features.is_none() => None,
+			f => f.or_else(|| current.features.clone()),
+		};
+		let (artifact, lib, target) = match self.artifact {
+			Some(kinds) if kinds.is_empty() => {
+				(current.artifact.as_ref().map(|_| kinds), None, None)
+			}
+			Some(kinds) => (Some(kinds), self.lib, self.target),
+			None => (
+				current.artifact.clone(),
+				current.lib,
+				current.target.clone(),
+			),
+		};
+		let public = match self.public {
+			Some(false) if current.public.is_none() => None,
+			public => public.or(current.public),
+		};
+		Self {
+			artifact,
+			lib,
+			target,
+			public,
+			features,
+			..self
+		}
+	}
+	const fn is_remove(&self) -> bool {
+		matches!(self.remove, Some(true))
 	}
 	fn to_table(&self) -> InlineTable {
 		let mut table = InlineTable::new();
@@ -103,7 +452,73 @@ impl DirectSource {
 	}
 }
 
-#[derive(Typed, Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
+/// Writes features into the existing array, keeping its formatting and comments of the kept entries
+fn write_features(table: &mut dyn TableLike, features: &[String]) {
+	if features.is_empty() {
+		table.remove("features");
+		return;
+	}
+	let Some(array) = table
+		.get_mut("features")
+		.and_then(Item::as_value_mut)
+		.and_then(Value::as_array_mut)
+	else {
+		table.insert("features", Item::Value(features.iter().collect()));
+		return;
+	};
+	// Renamed in place
+	if array.len() == features.len() {
+		for (i, feature) in features.iter().enumerate() {
+			let current = array.get_mut(i).expect("lengths are equal");
+			if current.as_str() != Some(feature) {
+				let decor = current.decor().clone();
+				*current = feature.as_str().into();
+				*current.decor_mut() = decor;
+			}
+		}
+		return;
+	}
+	// The first entry is usually formatted differently (`["a", "b"]`)
+	let first = array.get(0).map(|v| v.decor().clone()).unwrap_or_default();
+	let multiline = first
+		.prefix()
+		.and_then(|p| p.as_str())
+		.is_some_and(|p| p.contains('\n'));
+	let other = match array.len() {
+		0 | 1 if !multiline => Decor::new(" ", ""),
+		0 | 1 => first.clone(),
+		_ => array.iter().last().expect("not empty").decor().clone(),
+	};
+	let mut i = 0;
+	while i < array.len() {
+		if array
+			.get(i)
+			.and_then(Value::as_str)
+			.is_some_and(|f| features.iter().any(|n| n == f))
+		{
+			i += 1;
+		} else {
+			array.remove(i);
+		}
+	}
+	// Added ones are inserted at their position in the new list
+	for (i, feature) in features.iter().enumerate() {
+		if !array.iter().any(|v| v.as_str() == Some(feature)) {
+			let mut value = Value::from(feature.as_str());
+			*value.decor_mut() = other.clone();
+			array.insert_formatted(i.min(array.len()), value);
+		}
+	}
+	for (i, value) in array.iter_mut().enumerate() {
+		if i == 0 {
+			*value.decor_mut() = first.clone();
+		} else if *value.decor() == first {
+			*value.decor_mut() = other.clone();
+		}
+	}
+}
+
+#[derive(Typed, Serialize, Debug, Clone, PartialOrd, Ord, PartialEq, Eq)]
 pub struct DirectInput {
 	/// Name with which this package was referenced in `Cargo.toml`
 	/// ```toml
@@ -120,70 +535,364 @@ pub struct DirectInput {
 	pub source: DirectSource,
 	/// Backed up package source
 	#[typed(rename = "originalSource")]
+	#[serde(rename = "originalSource")]
 	pub original_source: DirectSource,
 }
+impl DirectInput {
+	/// Whether rule result differs from the current entry, ignoring extra keys
+	fn is_rewritten_by(&self, new: &DirectSource) -> bool {
+		new.name.as_ref().is_some_and(|n| n != &self.name)
+			|| new.package.as_ref().is_some_and(|p| p != &self.package)
+			|| DirectSource {
+				name: None,
+				package: None,
+				extra: None,
+				..new.clone()
+			} != self.source
+	}
+}
 
 type Key = Vec<String>;
 
-type Mutator = dyn Fn(DirectInput) -> Result<Either![Null, DirectSource]>;
+/// Comment marker of `[patch]` entries generated by deppatcher
+const MANAGED_MARKER: &str = "deppatcher:managed";
+
+/// Receives path to the dependency in manifest (i.e `["target", "cfg(unix)", "dependencies", "serde"]`), and its description
+type Mutator<'m> = dyn Fn(&Key, DirectInput) -> Result<Either![Null, DirectSource]> + 'm;
+
+/// Whole entry is stored, so it can be restored with features and other keys
+fn store_removed(originals: &mut Table, key: &Key, dep: &dyn TableLike, original: &DirectSource) {
+	let mut stored = InlineTable::new();
+	for (k, v) in dep.iter() {
+		if let Some(v) = v.as_value() {
+			stored.insert(k, v.clone());
+		}
+	}
+	original.write(&mut stored);
+	stored.insert("removed", true.into());
+	stored.fmt();
+	set_table(originals, key, Item::Value(Value::InlineTable(stored)));
+}
+
+/// What should be done with the dependency entry after patching
+enum DepAction {
+	Keep,
+	Remove,
+	/// Entry key should be changed to the specified name
+	Rename(String),
+}
+
+/// Manifest and key of the dependency, for log messages
+fn dep_location(key: &Key) -> String {
+	let line = location::line_of(key);
+	let key = display_key(key);
+	match (natives::current_manifest_display(), line) {
+		(Some(manifest), Some(line)) => format!("{manifest}:{line}: {key}"),
+		(Some(manifest), None) => format!("{manifest}: {key}"),
+		(None, _) => key,
+	}
+}
+
+/// Logs changed fields of the dependency table, as `key: old → new`
+fn log_rewrite(key: &Key, old: &DirectSource, new: &DirectSource) {
+	let (old, new) = (old.to_table(), new.to_table());
+	let painter = style::Painter::stderr();
+	let show = |value: Option<&Value>, style| {
+		value.map_or_else(
+			|| painter.paint(style::DIM, "(unset)"),
+			|v| painter.paint(style, v.to_string().trim()),
+		)
+	};
+	let mut keys = old.iter().map(|(k, _)| k).collect::<Vec<_>>();
+	keys.extend(new.iter().map(|(k, _)| k).filter(|k| !old.contains_key(k)));
+	let diff = keys
+		.into_iter()
+		.filter_map(|k| {
+			let (old, new) = (old.get(k), new.get(k));
+			let changed = match (old, new) {
+				(Some(old), Some(new)) => old.to_string().trim() != new.to_string().trim(),
+				_ => true,
+			};
+			changed.then(|| {
+				format!(
+					"{k}: {} → {}",
+					show(old, style::REMOVED),
+					show(new, style::ADDED)
+				)
+			})
+		})
+		.collect::<Vec<_>>()
+		.join(", ");
+	info!("{}: {diff}", dep_location(key));
+}
 
+#[allow(clippy::too_many_lines)]
 fn patch_dep(
 	originals: &mut Item,
-	key: &mut Key,
+	key: &Key,
 	dep: &mut dyn TableLike,
 	mutator: &Mutator,
-) -> Result<()> {
+) -> Result<DepAction> {
 	let force = false;
-	let name = key.iter().last().unwrap().as_str();
+	let name = key.iter().last().unwrap().clone();
 	let package = dep
 		.get("package")
 		.and_then(Item::as_str)
-		.unwrap_or(name)
+		.unwrap_or(&name)
 		.to_owned();
 	let source = DirectSource::read(dep);
 	let (had_original, original_source) = get_item(originals, key.iter().map(String::as_str))
 		.and_then(Item::as_table_like)
-		.map(DirectSource::read)
+		.map(DirectSource::read_original)
 		.map_or_else(|| (false, source.clone()), |v| (true, v));
+	let features_stored = !had_original || original_source.features.is_some();
+	let original_source = original_source.with_unset_from(&source);
 
 	let input = DirectInput {
-		name: name.to_owned(),
+		name: name.clone(),
 		package: package.clone(),
 		source: source.clone(),
 		original_source: original_source.clone(),
 	};
-	let new_source = if let Either2::B(new_source) = mutator(input)? {
+	trace!("{}: rule input {}", dep_location(key), source.to_table());
+	let new_source = if let Either2::B(new_source) = mutator(key, input)? {
 		new_source
 	} else if force {
 		source.clone()
 	} else {
-		return Ok(());
+		debug!("{}: skipped", dep_location(key));
+		return Ok(DepAction::Keep);
 	};
 
-	if new_source == source && !force {
-		return Ok(());
+	let new_name = new_source.name.clone().unwrap_or_else(|| name.clone());
+	let new_package = new_source
+		.package
+		.clone()
+		.unwrap_or_else(|| package.clone());
+	let renamed = new_name != name || new_package != package;
+	let new_source = DirectSource {
+		name: None,
+		package: None,
+		..new_source
 	}
+	.with_unset_from(&source);
 
-	info!("rewrite {} => {}", source.to_table(), new_source.to_table());
+	// Extra keys are compared with the entry itself, as they are not read into the source
+	let unchanged = source
+		== DirectSource {
+			extra: None,
+			..new_source.clone()
+		} && new_source
+		.extra
+		.iter()
+		.flatten()
+		.all(|(k, v)| v.is_written_in(dep, k));
+	if unchanged && !renamed && !force {
+		debug!("{}: rule returned the same source", dep_location(key));
+		return Ok(DepAction::Keep);
+	}
 
 	let originals = originals.as_table_mut().expect("is table checked");
 
-	if !had_original {
-		let name = key.pop().unwrap();
-		key.push(package);
+	if new_source.is_remove() {
+		info!("{}: remove {}", dep_location(key), source.to_table());
+		store_removed(originals, key, dep, &original_source);
+		return Ok(DepAction::Remove);
+	}
+
+	if renamed {
+		info!(
+			"{}: rename to {new_name} (package {new_package})",
+			dep_location(key)
+		);
+	}
+	if new_source != source {
+		log_rewrite(key, &source, &new_source);
+	}
+	if let (Some(path), Some(manifest)) = (&new_source.path, natives::current_manifest()) {
+		if new_source.path != source.path {
+			if let Some(problem) = check_path_source(&manifest, &new_package, path) {
+				warn!("{}: path is likely wrong, {problem}", dep_location(key));
+			}
+		}
+	}
+
+	// Entry state before the first patch, name and package are only stored when they were changed
+	let original_name = original_source.name.clone().unwrap_or_else(|| name.clone());
+	let original_package = original_source
+		.package
+		.clone()
+		.unwrap_or_else(|| package.clone());
+	let original_source = DirectSource {
+		name: None,
+		package: None,
+		..original_source
+	};
+	// Extra keys are not tracked, entry only patched with them doesn't need an original
+	let reverted = original_source
+		== DirectSource {
+			extra: None,
+			..new_source.clone()
+		} && original_name == new_name
+		&& original_package == new_package;
+
+	// Stacked patch: the previous result is lost, and only the first baseline is kept
+	let overridden = source
+		!= DirectSource {
+			extra: None,
+			..new_source.clone()
+		};
+	if had_original && !reverted && overridden && source != original_source {
+		warn!(
+			"{}: overriding {} set by an earlier patch, revert will restore {} instead",
+			dep_location(key),
+			source.to_table(),
+			original_source.to_table()
+		);
+	}
+	if had_original && (reverted || renamed) {
+		set_table(originals, key, Item::None);
+	}
+	if had_original
+		&& !reverted
+		&& !renamed
+		&& !features_stored
+		&& new_source.features != source.features
+	{
+		// Features weren't changed by the earlier patch, but they are now
+		let stored = key
+			.iter()
+			.try_fold(&mut *originals as &mut dyn TableLike, |t, k| {
+				t.get_mut(k)?.as_table_like_mut()
+			});
+		if let Some(stored) = stored {
+			let features = source
+				.features
+				.iter()
+				.flatten()
+				.collect::<toml_edit::Array>();
+			stored.insert("features", Item::Value(Value::Array(features)));
+		}
+	}
+	if !reverted && (!had_original || renamed) {
+		let mut stored = DirectSource {
+			features: None,
+			..original_source.clone()
+		}
+		.to_table();
+		// Empty list is stored too, for revert to remove features added by the rule
+		if original_source.features != new_source.features {
+			let features = original_source.features.iter().flatten().collect();
+			stored.insert("features", Value::Array(features));
+		}
+		if original_name != new_name {
+			stored.insert("name", original_name.as_str().into());
+		}
+		if original_package != new_package {
+			stored.insert("package", original_package.as_str().into());
+		}
+		// Keyed by entry name, as the same package might be declared by several entries
+		let mut stored_key = key.clone();
+		stored_key
+			.last_mut()
+			.expect("dependency key is not empty")
+			.clone_from(&new_name);
 		set_table(
 			originals,
-			key,
-			Item::Value(Value::InlineTable(source.to_table())),
+			&stored_key,
+			Item::Value(Value::InlineTable(stored)),
 		);
-		key.pop();
-		key.push(name);
-	} else if original_source == new_source {
-		set_table(originals, key, Item::None);
 	}
 
 	new_source.write(dep);
+	if renamed {
+		if new_package == new_name {
+			dep.remove("package");
+		} else {
+			dep.insert("package", Item::Value(new_package.as_str().into()));
+		}
+	}
+
+	Ok(if new_name == name {
+		DepAction::Keep
+	} else {
+		DepAction::Rename(new_name)
+	})
+}
+
+/// Originals of the removed dependencies
+fn removed_originals(item: &Item, key: &mut Key, out: &mut Vec<(Key, InlineTable)>) {
+	match item {
+		Item::Table(table) => {
+			for (k, v) in table {
+				key.push(k.to_owned());
+				removed_originals(v, key, out);
+				key.pop();
+			}
+		}
+		Item::Value(Value::InlineTable(table))
+			if table.get("removed").and_then(Value::as_bool) == Some(true) =>
+		{
+			out.push((key.clone(), table.clone()));
+		}
+		_ => {}
+	}
+}
 
+/// Offers removed dependencies to the mutator, restoring ones for which original source was returned
+fn restore_removed(originals: &mut Item, doc: &mut Table, mutator: &Mutator) -> Result<()> {
+	let mut removed = Vec::new();
+	removed_originals(originals, &mut Vec::new(), &mut removed);
+	for (key, mut stored) in removed {
+		// Dependency was added back manually
+		let (first, rest) = key.split_first().expect("dependency key is not empty");
+		if doc
+			.get(first)
+			.and_then(|i| get_item(i, rest.iter().map(String::as_str)))
+			.is_some()
+		{
+			continue;
+		}
+		stored.remove("removed");
+		let name = key.last().expect("dependency key is not empty");
+		let original_source = DirectSource::read(&stored);
+		let input = DirectInput {
+			name: name.clone(),
+			package: stored
+				.get("package")
+				.and_then(Value::as_str)
+				.unwrap_or(name)
+				.to_owned(),
+			source: DirectSource {
+				remove: Some(true),
+				..DirectSource::default()
+			},
+			original_source: original_source.clone(),
+		};
+		match mutator(&key, input)? {
+			Either2::B(source) if source == original_source => {
+				stored.fmt();
+				info!("restore {}", stored.to_string().trim());
+				let restored = match stored.get("version") {
+					Some(version) if stored.len() == 1 => version.clone(),
+					_ => Value::InlineTable(stored),
+				};
+				set_table(doc, &key, Item::Value(restored));
+				set_table(
+					originals.as_table_mut().expect("is table checked"),
+					&key,
+					Item::None,
+				);
+			}
+			Either2::B(source) if !source.is_remove() => {
+				warn!(
+					"removed dependency {} can only be restored to its original source",
+					display_key(&key)
+				);
+			}
+			_ => {}
+		}
+	}
 	Ok(())
 }
 
@@ -194,74 +903,164 @@ fn patch_dep_table(
 	mutator: &Mutator,
 	force_inline: bool,
 ) -> Result<()> {
+	migrate_renamed_originals(originals, key, deps);
+	let mut removed = Vec::new();
+	let mut renamed = Vec::new();
 	for (d, table) in deps
 		.iter_mut()
 		.filter_map(|(k, t)| t.is_table_like().then_some((k, t)))
 	{
 		key.push(d.get().to_owned());
-		patch_dep(
+		match patch_dep(
 			originals,
 			key,
 			table.as_table_like_mut().expect("is table checked"),
 			mutator,
-		)?;
-		if force_inline {
-			if let Some(astable) = table.as_table_mut() {
-				astable.set_implicit(true);
-				*table = Item::Value(Value::InlineTable(astable.clone().into_inline_table()));
-			}
-			if let Some(astable) = table.as_inline_table_mut() {
-				if astable.len() == 1 {
-					astable.set_dotted(true);
-				}
-			}
-		}
-		let astable = table.as_table_like().expect("is table checked");
-		if astable.len() == 1 {
-			if let Some(version) = table.get("version") {
-				*table = version.clone();
+		)? {
+			DepAction::Keep => {}
+			DepAction::Remove => {
+				removed.push(key.pop().expect("just pushed"));
+				continue;
 			}
+			DepAction::Rename(name) => renamed.push((d.get().to_owned(), name)),
 		}
+		format_entry(d, table, force_inline);
 		key.pop();
 	}
 	for (d, table) in deps
 		.iter_mut()
 		.filter_map(|(k, t)| t.is_str().then_some((k, t)))
 	{
-		let version = Value::String(Formatted::new(
-			table.as_str().expect("iterating over strings").to_owned(),
-		));
+		let mut version = table.as_value().expect("iterating over strings").clone();
+		// Comments around the value belong to the whole entry, and are moved to the table
+		let decor = std::mem::take(version.decor_mut());
 		key.push(d.get().to_owned());
 		let mut tmp = InlineTable::new();
 		tmp.insert("version", version);
+		let mut tmp = Value::InlineTable(tmp);
+		*tmp.decor_mut() = decor;
 		// Assuming no one will use `package=version` syntax, when one prefers non-inline table for dependencies (actual psychos).
-		*table = Item::Value(Value::InlineTable(tmp));
+		*table = Item::Value(tmp);
 
-		patch_dep(
+		match patch_dep(
 			originals,
 			key,
 			table.as_table_like_mut().expect("is table checked"),
 			mutator,
-		)?;
-		if force_inline {
-			if let Some(astable) = table.as_table_mut() {
-				astable.set_implicit(true);
-				*table = Item::Value(Value::InlineTable(astable.clone().into_inline_table()));
-			}
-			if let Some(astable) = table.as_inline_table_mut() {
-				if astable.len() == 1 {
-					astable.set_dotted(true);
-				}
+		)? {
+			DepAction::Keep => {}
+			DepAction::Remove => {
+				removed.push(key.pop().expect("just pushed"));
+				continue;
 			}
+			DepAction::Rename(name) => renamed.push((d.get().to_owned(), name)),
+		}
+		format_entry(d, table, force_inline);
+		key.pop();
+	}
+	for d in removed {
+		deps.remove(&d);
+	}
+	rename_entries(deps, renamed)
+}
+
+/// Originals of entries with `package` key used to be stored under the package name, they are moved
+/// to the entry name, unless package name is taken by another entry, which makes the owner ambiguous
+fn migrate_renamed_originals(originals: &mut Item, key: &Key, deps: &Table) {
+	for (name, dep) in deps {
+		let Some(package) = dep
+			.as_table_like()
+			.and_then(|d| d.get("package"))
+			.and_then(Item::as_str)
+		else {
+			continue;
+		};
+		if package == name || deps.contains_key(package) {
+			continue;
+		}
+		let [by_name, by_package] = [name, package].map(|n| {
+			let mut stored = key.clone();
+			stored.push(n.to_owned());
+			stored
+		});
+		let get = |k: &Key| get_item(originals, k.iter().map(String::as_str));
+		if get(&by_name).is_some() {
+			continue;
+		}
+		let Some(stored) = get(&by_package).cloned() else {
+			continue;
+		};
+		let table = originals.as_table_mut().expect("is table checked");
+		set_table(table, &by_package, Item::None);
+		set_table(table, &by_name, stored);
+	}
+}
+
+/// Applies `--force-inline` formatting to the dependency entry, and writes version-only entries in the short form
+fn format_entry(mut key: KeyMut<'_>, entry: &mut Item, force_inline: bool) {
+	if force_inline {
+		if let Some(astable) = entry.as_table_mut() {
+			astable.set_implicit(true);
+			*entry = Item::Value(Value::InlineTable(astable.clone().into_inline_table()));
+			// Key was written in the table header, where it has no spacing around
+			key.fmt();
 		}
-		let astable = table.as_table_like().expect("is table checked");
-		if astable.len() == 1 {
-			if let Some(version) = table.get("version") {
-				*table = version.clone();
+		if let Some(astable) = entry.as_inline_table_mut() {
+			if astable.len() == 1 && !astable.is_dotted() {
+				let suffix = astable.decor().suffix().cloned();
+				astable.set_dotted(true);
+				// Spacing inside of braces would be kept around the dot otherwise
+				astable.fmt();
+				key.fmt();
+				// Dotted table has no decor of its own, trailing comment is moved to the value
+				if let (Some(suffix), Some((_, value))) = (suffix, astable.iter_mut().next()) {
+					value.decor_mut().set_suffix(suffix);
+				}
 			}
 		}
-		key.pop();
 	}
+	collapse_version(entry);
+}
+
+/// Dependency table with only the version left is written as `name = "version"`,
+/// keeping comments around the inline table
+fn collapse_version(item: &mut Item) {
+	let astable = item.as_table_like().expect("is table checked");
+	// `name.version = "1"` is already as short, and is kept in the style it was written
+	if astable.len() != 1 || item.as_table().is_some_and(Table::is_dotted) {
+		return;
+	}
+	let Some(version) = astable.get("version") else {
+		return;
+	};
+	let mut version = version.clone();
+	if let (Some(table), Some(version)) = (item.as_value(), version.as_value_mut()) {
+		*version.decor_mut() = table.decor().clone();
+	}
+	*item = version;
+}
+
+/// Renamed entries stay at the same position
+fn rename_entries(deps: &mut Table, renamed: Vec<(String, String)>) -> Result<()> {
+	if renamed.is_empty() {
+		return Ok(());
+	}
+	let mut order = deps.iter().map(|(k, _)| k.to_owned()).collect::<Vec<_>>();
+	for (from, to) in renamed {
+		if deps.contains_key(&to) {
+			bail!("can't rename {from} to {to}, dependency with this name already exists");
+		}
+		let (_, item) = deps.remove_entry(&from).expect("renamed exists");
+		deps.insert(&to, item);
+		*order
+			.iter_mut()
+			.find(|k| **k == from)
+			.expect("renamed exists") = to;
+	}
+	deps.sort_values_by(|a, _, b, _| {
+		let pos = |k: &toml_edit::Key| order.iter().position(|o| o == k.get());
+		pos(a).cmp(&pos(b))
+	});
 	Ok(())
 }
 
@@ -305,6 +1104,14 @@ fn patch_root_table(
 	Ok(())
 }
 
+/// Human-readable dependency path, as it would be written in toml
+fn display_key(key: &Key) -> String {
+	key.iter()
+		.map(|k| toml_edit::Key::new(k.as_str()).display_repr().into_owned())
+		.collect::<Vec<_>>()
+		.join(".")
+}
+
 fn get_item<'t, 'k>(table: &'t Item, key: impl IntoIterator<Item = &'k str>) -> Option<&'t Item> {
 	key.into_iter()
 		.try_fold(table, |table, key| table.as_table_like()?.get(key))
@@ -335,67 +1142,538 @@ fn set_table(mut table: &mut Table, key: &Key, value: Item) {
 	table.insert(last, value);
 }
 
-fn freeze(path: &Path) -> Result<()> {
-	let toml = fs::read_to_string(path).run_err()?;
-	let mut doc: Document = toml.parse().run_err()?;
-	set_table(
-		doc.as_table_mut(),
-		&vec![
-			"package".to_owned(),
-			"metadata".to_owned(),
-			"deppatcher".to_owned(),
-			"originals".to_owned(),
-		],
-		Item::None,
-	);
-	set_table(
-		doc.as_table_mut(),
-		&vec![
-			"workspace".to_owned(),
-			"metadata".to_owned(),
-			"deppatcher".to_owned(),
-			"originals".to_owned(),
-		],
-		Item::None,
-	);
-	let toml = doc.to_string();
-	fs::write(path, toml).run_err()?;
-	Ok(())
+/// Nearest enclosing workspace root, found the same way cargo does it.
+///
+/// Workspaces, which don't include the package the directory belongs to, are skipped.
+/// If directory isn't a part of any workspace, it is returned as is
+fn workspace_root(dir: &Path) -> Result<PathBuf> {
+	// Directory of the nearest package manifest
+	let mut package = None;
+	for ancestor in dir.ancestors() {
+		let manifest = ancestor.join("Cargo.toml");
+		if !manifest.is_file() {
+			continue;
+		}
+		let doc: Document = fs::read_to_string(&manifest).run_err()?.parse().run_err()?;
+		if doc.contains_key("workspace") {
+			match package {
+				Some(package) if !workspace_includes(ancestor, package)? => {
+					debug!(
+						"{} is not a member of the workspace at {}",
+						package.display(),
+						ancestor.display()
+					);
+				}
+				_ => return Ok(ancestor.to_owned()),
+			}
+		}
+		if package.is_none() {
+			// Explicit workspace root of the package
+			if let Some(workspace) =
+				get_item(doc.as_item(), ["package", "workspace"]).and_then(Item::as_str)
+			{
+				let root = ancestor.join(workspace);
+				let is_root = fs::read_to_string(root.join("Cargo.toml"))
+					.ok()
+					.and_then(|toml| toml.parse::<Document>().ok())
+					.is_some_and(|doc| doc.contains_key("workspace"));
+				if !is_root {
+					bail!(
+						"package.workspace of {} points to {}, which is not a workspace root",
+						manifest.display(),
+						root.display()
+					);
+				}
+				return Ok(root);
+			}
+			package = Some(ancestor);
+		}
+	}
+	Ok(dir.to_owned())
 }
 
-fn patch(path: &Path, mutator: &Mutator, force_inline: bool) -> Result<()> {
-	let toml = fs::read_to_string(path).run_err()?;
-	let mut doc: Document = toml.parse().run_err()?;
-	let metadata_root = if doc.contains_key("package") {
-		"package"
-	} else {
-		"workspace"
+/// Whether the package in `dir` is a member of the workspace: it is listed in `workspace.members`,
+/// or it is a path dependency of the root package, and it is not excluded
+fn workspace_includes(root: &Path, dir: &Path) -> Result<bool> {
+	let (excluded, members) = workspace_exclusions(root)?;
+	if members.iter().any(|m| m.matches_path(dir)) {
+		return Ok(true);
+	}
+	if excluded.iter().any(|ex| dir.starts_with(ex)) {
+		return Ok(false);
+	}
+	let dir = dir.canonicalize().run_err()?;
+	Ok(dependencies(&root.join("Cargo.toml"))?
+		.into_iter()
+		.filter_map(|(_, dep)| dep.source.path)
+		.any(|path| root.join(path).canonicalize().is_ok_and(|p| p == dir)))
+}
+
+/// Directories excluded with `workspace.exclude` of the root manifest, and patterns of explicitly listed members
+fn workspace_exclusions(root: &Path) -> Result<(Vec<PathBuf>, Vec<::glob::Pattern>)> {
+	let manifest = root.join("Cargo.toml");
+	if !manifest.is_file() {
+		return Ok((vec![], vec![]));
+	}
+	let doc: Document = fs::read_to_string(&manifest).run_err()?.parse().run_err()?;
+	let strings = |key: &str| {
+		get_item(doc.as_item(), ["workspace", key])
+			.and_then(Item::as_array)
+			.into_iter()
+			.flatten()
+			.filter_map(|v| v.as_str())
+			.map(|v| root.join(v.trim_end_matches('/')))
+			.collect::<Vec<_>>()
 	};
-	let mut originals = get_item(
-		doc.as_item(),
-		[metadata_root, "metadata", "deppatcher", "originals"],
-	)
-	.cloned()
-	.unwrap_or_else(|| {
-		let mut table = Table::new();
-		table.set_implicit(true);
-		Item::Table(table)
-	});
+	let members = strings("members")
+		.iter()
+		.map(|m| ::glob::Pattern::new(&m.to_string_lossy()).run_err())
+		.collect::<Result<_>>()?;
+	Ok((strings("exclude"), members))
+}
 
-	if !originals.is_table() {
-		bail!("originals should be table");
+const GENERATED_BANNER: &str = "# THIS FILE IS AUTOMATICALLY GENERATED BY CARGO";
+
+/// Manifests produced by `cargo package`/`cargo vendor`, and backups of the originals made by them,
+/// changes to which would be lost or break checksums
+fn is_generated(manifest: &Path) -> Result<bool> {
+	if manifest.file_name().is_some_and(|n| n == "Cargo.toml.orig") {
+		return Ok(true);
 	}
+	let components = manifest.components().collect::<Vec<_>>();
+	if components
+		.windows(2)
+		.any(|w| w[0].as_os_str() == "target" && w[1].as_os_str() == "package")
+	{
+		return Ok(true);
+	}
+	let mut head = Vec::with_capacity(GENERATED_BANNER.len());
+	File::open(manifest)
+		.run_err()?
+		.take(GENERATED_BANNER.len() as u64)
+		.read_to_end(&mut head)
+		.run_err()?;
+	Ok(head == GENERATED_BANNER.as_bytes())
+}
 
-	let table = doc.as_table_mut();
+fn manifests(root: &Path) -> Result<Vec<PathBuf>> {
+	timings::measure(timings::Phase::Traversal, || find_manifests(root))
+}
 
-	let mut key = Vec::new();
-	patch_root_table(&mut originals, &mut key, table, mutator, force_inline)?;
-	if let Some(table) = table.get_mut("workspace").and_then(Item::as_table_mut) {
-		key.push("workspace".to_owned());
-		patch_root_table(&mut originals, &mut key, table, mutator, force_inline)?;
-		key.pop();
+/// Directories of the workspace roots (manifests with `[workspace]` table) among the manifests
+///
+/// Besides the root one, there might be independent workspaces nested in the tree, i.e for tools
+fn workspace_roots(manifests: &[PathBuf]) -> Result<Vec<PathBuf>> {
+	let mut out = Vec::new();
+	for manifest in manifests {
+		let toml = fs::read_to_string(manifest).run_err()?;
+		// Cheap check before parsing
+		if !toml.contains("workspace") {
+			continue;
+		}
+		let doc: Document = toml.parse().run_err()?;
+		if doc.contains_key("workspace") {
+			out.push(manifest.parent().expect("manifest is a file").to_owned());
+		}
+	}
+	Ok(out)
+}
+
+/// Root of the innermost workspace containing the manifest
+fn owning_workspace<'r>(manifest: &Path, roots: &'r [PathBuf]) -> Option<&'r Path> {
+	let dir = manifest.parent().expect("manifest is a file");
+	roots
+		.iter()
+		.filter(|r| dir.starts_with(r))
+		.max_by_key(|r| r.components().count())
+		.map(PathBuf::as_path)
+}
+
+/// All `Cargo.toml` files in the directory tree, or ones listed with `--files-from`
+///
+/// Crates excluded from their workspace are skipped, unless they are also explicitly listed as members,
+/// as well as everything matching `--exclude`, manifests of packages not selected with `--package`,
+/// and manifests of nested workspaces not selected with `--no-nested`/`--in-workspace`
+fn find_manifests(root: &Path) -> Result<Vec<PathBuf>> {
+	if let Some(list) = &discovery().files_from {
+		return select_packages(root, listed_manifests(list)?);
+	}
+	let (excluded, members) = workspace_exclusions(root)?;
+	let exclude = &discovery().exclude;
+	let mut out = Vec::new();
+	let follow_symlinks = discovery().follow_symlinks;
+	let mut walk = walkdir::WalkDir::new(root).follow_links(follow_symlinks);
+	// The same manifest might be reachable with multiple symlinks
+	let mut seen = HashSet::new();
+	if let Some(depth) = discovery().max_depth {
+		// Manifest is one level deeper than its directory
+		walk = walk.max_depth(depth + 1);
+	}
+	for entry in walk.into_iter().filter_entry(|e| {
+		let relative = e.path().strip_prefix(root).unwrap_or_else(|_| e.path());
+		!exclude.iter().any(|p| p.matches_path(relative))
+	}) {
+		let entry = match entry {
+			Err(e) if e.loop_ancestor().is_some() => {
+				warn!("skipping symlink loop: {e}");
+				continue;
+			}
+			entry => entry.run_err()?,
+		};
+		if !entry.file_type().is_file() || !entry.path().ends_with("Cargo.toml") {
+			continue;
+		}
+		if follow_symlinks && !seen.insert(entry.path().canonicalize().run_err()?) {
+			continue;
+		}
+		if !discovery().include_generated && is_generated(entry.path())? {
+			info!(target: PROGRESS, "skipping generated {}", entry.path().display());
+			continue;
+		}
+		let dir = entry.path().parent().expect("manifest is a file");
+		if excluded.iter().any(|ex| dir.starts_with(ex))
+			&& !members.iter().any(|m| m.matches_path(dir))
+		{
+			info!(target: PROGRESS, "skipping excluded {}", entry.path().display());
+			continue;
+		}
+		out.push(entry.into_path());
+	}
+	select_packages(root, scope_workspaces(root, out)?)
+}
+
+/// Manifests listed in the file (`-` for stdin), one path (relative to the current directory) per line
+///
+/// Lines pointing at directories select `Cargo.toml` in them, missing manifests are skipped, as lists
+/// produced by change detection might include deleted files
+fn listed_manifests(list: &Path) -> Result<Vec<PathBuf>> {
+	let content = if list == Path::new("-") {
+		// Manifests might be listed multiple times per run, and stdin can only be read once
+		static STDIN: OnceLock<String> = OnceLock::new();
+		if let Some(content) = STDIN.get() {
+			content.clone()
+		} else {
+			let mut content = String::new();
+			stdin().read_to_string(&mut content).run_err()?;
+			STDIN.get_or_init(|| content).clone()
+		}
+	} else {
+		fs::read_to_string(list)
+			.map_err(|e| format!("{}: {e}", list.display()))
+			.run_err()?
+	};
+	let cwd = current_dir().run_err()?;
+	let mut out = Vec::new();
+	for line in content.lines().map(str::trim).filter(|l| !l.is_empty()) {
+		let mut manifest = cwd.join(line);
+		if manifest.is_dir() {
+			manifest.push("Cargo.toml");
+		}
+		if !manifest.is_file() {
+			warn!(
+				"skipping listed {}, which doesn't exist",
+				manifest.display()
+			);
+			continue;
+		}
+		if !out.contains(&manifest) {
+			out.push(manifest);
+		}
+	}
+	Ok(out)
+}
+
+/// Manifests of packages selected with `--package`, and virtual roots of their workspaces, if they have `[workspace.dependencies]`
+fn select_packages(root: &Path, mut out: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+	let packages = &discovery().packages;
+	if !packages.is_empty() {
+		let specs = packages
+			.iter()
+			.map(|spec| {
+				let (name, version) = spec
+					.split_once('@')
+					.map_or((spec.as_str(), None), |(n, v)| (n, Some(v)));
+				Ok((spec, ::glob::Pattern::new(name).run_err()?, version))
+			})
+			.collect::<Result<Vec<_>>>()?;
+		let mut matched = HashSet::new();
+		let mut filtered = Vec::new();
+		// Virtual roots, which declare `[workspace.dependencies]` for the selected packages
+		let mut shared = Vec::new();
+		for manifest in out {
+			let doc: Document = fs::read_to_string(&manifest).run_err()?.parse().run_err()?;
+			let get = |key| get_item(doc.as_item(), ["package", key]).and_then(Item::as_str);
+			let (Some(name), version) = (get("name"), get("version")) else {
+				if get_item(doc.as_item(), ["workspace", "dependencies"]).is_some() {
+					shared.push(manifest);
+				}
+				continue;
+			};
+			let mut selected = false;
+			for (spec, pattern, spec_version) in &specs {
+				if pattern.matches(name) && spec_version.is_none_or(|v| Some(v) == version) {
+					matched.insert(*spec);
+					selected = true;
+				}
+			}
+			if selected {
+				filtered.push(manifest);
+			}
+		}
+		if let Some(spec) = packages.iter().find(|p| !matched.contains(p)) {
+			bail!("package {spec} is not found in {}", root.display());
+		}
+		shared.retain(|root| {
+			let root = root.parent().expect("manifest is a file");
+			filtered.iter().any(|m| m.starts_with(root))
+		});
+		out = shared;
+		out.extend(filtered);
+	}
+	Ok(out)
+}
+
+/// Applies exclusions of the nested workspaces, and `--no-nested`/`--in-workspace`
+fn scope_workspaces(root: &Path, mut manifests: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+	let roots = workspace_roots(&manifests)?;
+	for nested in roots.iter().filter(|r| *r != root) {
+		let (excluded, members) = workspace_exclusions(nested)?;
+		manifests.retain(|m| {
+			let dir = m.parent().expect("manifest is a file");
+			let skip = owning_workspace(m, &roots) == Some(nested.as_path())
+				&& excluded.iter().any(|ex| dir.starts_with(ex))
+				&& !members.iter().any(|m| m.matches_path(dir));
+			if skip {
+				info!(target: PROGRESS, "skipping excluded {}", m.display());
+			}
+			!skip
+		});
+	}
+	let nested = |m: &Path| owning_workspace(m, &roots).is_some_and(|w| w != root);
+	if discovery().no_nested {
+		manifests.retain(|m| {
+			let skip = nested(m);
+			if skip {
+				info!(target: PROGRESS, "skipping nested workspace {}", m.display());
+			}
+			!skip
+		});
+	}
+	let selected = &discovery().in_workspace;
+	if !selected.is_empty() {
+		let mut chosen = HashSet::new();
+		for dir in selected {
+			let dir = dir
+				.canonicalize()
+				.map_err(|e| format!("{}: {e}", dir.display()))
+				.run_err()?;
+			let found = roots
+				.iter()
+				.find(|r| r.canonicalize().is_ok_and(|r| r == dir));
+			let Some(found) = found else {
+				bail!("{} is not a workspace root", dir.display());
+			};
+			chosen.insert(found.as_path());
+		}
+		manifests.retain(|m| owning_workspace(m, &roots).is_some_and(|w| chosen.contains(w)));
+	}
+	Ok(manifests)
+}
+
+/// Manifests, which are either listed in `paths`, or located under one of listed directories
+fn within_paths(manifests: Vec<PathBuf>, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+	let paths = paths
+		.iter()
+		.map(|p| {
+			p.canonicalize()
+				.map_err(|e| format!("{}: {e}", p.display()))
+		})
+		.collect::<Result<Vec<_>, _>>()
+		.run_err()?;
+	let mut matched = HashSet::new();
+	let mut out = Vec::new();
+	for manifest in manifests {
+		let canonical = manifest.canonicalize().run_err()?;
+		let mut selected = false;
+		for path in &paths {
+			if canonical.starts_with(path) {
+				matched.insert(path);
+				selected = true;
+			}
+		}
+		if selected {
+			out.push(manifest);
+		}
+	}
+	if let Some(path) = paths.iter().find(|p| !matched.contains(p)) {
+		bail!("no manifests found at {}", path.display());
+	}
+	Ok(out)
+}
+
+/// Keys of stored originals for dependencies on packages matching any of patterns
+///
+/// Both current package of the dependency, and the stored original one are checked
+fn matching_originals(
+	item: &Item,
+	doc: &Item,
+	key: &mut Key,
+	packages: &[::glob::Pattern],
+	out: &mut Vec<Key>,
+) {
+	match item {
+		Item::Table(table) => {
+			for (k, v) in table {
+				key.push(k.to_owned());
+				matching_originals(v, doc, key, packages, out);
+				key.pop();
+			}
+		}
+		Item::Value(Value::InlineTable(stored)) => {
+			let name = key.last().expect("dependency key is not empty");
+			let current = get_item(doc, key.iter().map(String::as_str))
+				.and_then(Item::as_table_like)
+				.and_then(|dep| dep.get("package"))
+				.and_then(Item::as_str)
+				.unwrap_or(name);
+			let original = stored.get("package").and_then(Value::as_str);
+			if packages
+				.iter()
+				.any(|p| p.matches(current) || original.is_some_and(|o| p.matches(o)))
+			{
+				out.push(key.clone());
+			}
+		}
+		_ => {}
+	}
+}
+
+/// Applies BOM and line endings of the original manifest to its rewritten version,
+/// `toml_edit` drops BOM when parsing, and uses LF for the lines it inserts
+pub(crate) fn keep_text_format(original: &str, rewritten: &str) -> String {
+	let bom = if original.starts_with('\u{feff}') {
+		"\u{feff}"
+	} else {
+		""
+	};
+	// Convention is decided by the first line ending, mixed ones are normalized to it
+	let crlf = original
+		.find('\n')
+		.is_some_and(|i| original[..i].ends_with('\r'));
+	let rewritten = rewritten.strip_prefix('\u{feff}').unwrap_or(rewritten);
+	if crlf {
+		format!(
+			"{bom}{}",
+			rewritten.replace("\r\n", "\n").replace('\n', "\r\n")
+		)
+	} else {
+		format!("{bom}{rewritten}")
+	}
+}
+
+/// Removes stored originals, only for dependencies on specified packages if any are passed
+fn freeze(path: &Path, packages: &[::glob::Pattern]) -> Result<()> {
+	let toml = fs::read_to_string(path).run_err()?;
+	let mut doc: Document = toml.parse().run_err()?;
+	freeze_doc(path, &mut doc, packages);
+	let frozen = keep_text_format(&toml, &doc.to_string());
+	if frozen != toml {
+		fs::write(path, frozen).run_err()?;
+		mark_changed();
+	}
+	Ok(())
+}
+
+fn freeze_doc(path: &Path, doc: &mut Document, packages: &[::glob::Pattern]) {
+	for root in ["package", "workspace"] {
+		let originals_key = vec![
+			root.to_owned(),
+			"metadata".to_owned(),
+			"deppatcher".to_owned(),
+			"originals".to_owned(),
+		];
+		if packages.is_empty() {
+			set_table(doc.as_table_mut(), &originals_key, Item::None);
+			continue;
+		}
+		let Some(originals) = get_item(doc.as_item(), originals_key.iter().map(String::as_str))
+		else {
+			continue;
+		};
+		let mut matching = Vec::new();
+		matching_originals(
+			originals,
+			doc.as_item(),
+			&mut Vec::new(),
+			packages,
+			&mut matching,
+		);
+		for key in matching {
+			info!(
+				"{}: {}: discarding original",
+				path.display(),
+				display_key(&key)
+			);
+			let mut full = originals_key.clone();
+			full.extend(key);
+			set_table(doc.as_table_mut(), &full, Item::None);
+		}
+	}
+}
+
+fn patch_doc(doc: &mut Document, mutator: &Mutator, force_inline: bool) -> Result<()> {
+	// Document is not modified yet, so it is printed the same way as it was parsed
+	let _lines = location::Lines::enter(&doc.to_string());
+	let metadata_root = if doc.contains_key("package") {
+		"package"
+	} else {
+		"workspace"
+	};
+	let mut originals = get_item(
+		doc.as_item(),
+		[metadata_root, "metadata", "deppatcher", "originals"],
+	)
+	.cloned()
+	.unwrap_or_else(|| {
+		let mut table = Table::new();
+		table.set_implicit(true);
+		Item::Table(table)
+	});
+
+	if !originals.is_table() {
+		bail!("originals should be table");
+	}
+	let stored = originals_text(&originals);
+
+	let table = doc.as_table_mut();
+
+	let mut key = Vec::new();
+	patch_root_table(&mut originals, &mut key, table, mutator, force_inline)?;
+	if let Some(table) = table.get_mut("workspace").and_then(Item::as_table_mut) {
+		key.push("workspace".to_owned());
+		patch_root_table(&mut originals, &mut key, table, mutator, force_inline)?;
+		key.pop();
+	}
+	assert_eq!(key.len(), 0);
+	restore_removed(&mut originals, table, mutator)?;
+	// Rules are not invoked for `[patch]` entries, but they are formatted the same way, for consistent style
+	if force_inline {
+		if let Some(patch) = table.get_mut("patch").and_then(Item::as_table_mut) {
+			for (_, source) in patch.iter_mut() {
+				let Some(source) = source.as_table_mut() else {
+					continue;
+				};
+				for (k, entry) in source.iter_mut() {
+					if entry.is_table_like() {
+						format_entry(k, entry, true);
+					}
+				}
+			}
+		}
+	}
+	if originals_text(&originals) != stored {
+		place_originals(&mut originals, max_position(table) + 1);
 	}
-	assert_eq!(key.len(), 0);
 
 	set_table(
 		table,
@@ -407,103 +1685,1071 @@ fn patch(path: &Path, mutator: &Mutator, force_inline: bool) -> Result<()> {
 		],
 		originals,
 	);
+	Ok(())
+}
+
+/// Originals as they would be printed, `Item`'s `Display` doesn't include subtables
+fn originals_text(originals: &Item) -> String {
+	let table = originals.as_table().expect("is table checked");
+	Document::from(table.clone()).to_string()
+}
 
-	let toml = doc.to_string();
-	fs::write(path, toml).run_err()?;
+/// Largest position of the tables in the document
+fn max_position(table: &Table) -> usize {
+	table
+		.iter()
+		.fold(table.position().unwrap_or(0), |max, (_, item)| match item {
+			Item::Table(t) => max.max(max_position(t)),
+			Item::ArrayOfTables(a) => a.iter().map(max_position).fold(max, usize::max),
+			_ => max,
+		})
+}
+
+/// Positions of the tables with headers
+fn header_positions(table: &Table, out: &mut Vec<usize>) {
+	if !table.is_implicit() {
+		out.extend(table.position());
+	}
+	for (_, item) in table {
+		if let Item::Table(t) = item {
+			header_positions(t, out);
+		}
+	}
+}
+
+fn place_tables(table: &mut Table, position: usize) {
+	table.sort_values();
+	if table.position().is_none() {
+		table.set_position(position);
+	}
+	for (_, item) in table.iter_mut() {
+		if let Item::Table(t) = item {
+			place_tables(t, position);
+		}
+	}
+}
+
+/// New tables of originals are placed next to the already stored ones, or at the end of the document,
+/// instead of wherever their implicit parents are, and entries are sorted, so their order doesn't depend
+/// on the order of patches
+fn place_originals(originals: &mut Item, end: usize) {
+	let table = originals.as_table_mut().expect("is table checked");
+	let mut stored = Vec::new();
+	header_positions(table, &mut stored);
+	place_tables(table, stored.into_iter().max().unwrap_or(end));
+}
+
+/// Cheap check before parsing: manifest without dependency, `[target]` and `[patch]` tables (i.e virtual manifest)
+/// and without stored originals (which might hold removed dependencies) is never changed
+fn may_have_dependencies(toml: &str) -> bool {
+	["dependencies", "target", "patch", "deppatcher"]
+		.iter()
+		.any(|key| toml.contains(key))
+}
+
+/// Patches manifest content, originals are only recorded if `record_originals` is set,
+/// otherwise previously stored originals are kept as is
+fn patch_str(
+	toml: &str,
+	mutator: &Mutator,
+	force_inline: bool,
+	record_originals: bool,
+) -> Result<String> {
+	if !may_have_dependencies(toml) {
+		return Ok(toml.to_owned());
+	}
+	let mut doc: Document = timings::measure(timings::Phase::Parse, || toml.parse()).run_err()?;
+	if record_originals {
+		patch_doc(&mut doc, mutator, force_inline)?;
+		return Ok(keep_text_format(toml, &doc.to_string()));
+	}
+	let originals_key = ["package", "workspace"].map(|root| {
+		[root, "metadata", "deppatcher", "originals"]
+			.map(ToOwned::to_owned)
+			.to_vec()
+	});
+	let stored = originals_key
+		.clone()
+		.map(|key| get_item(doc.as_item(), key.iter().map(String::as_str)).cloned());
+	patch_doc(&mut doc, mutator, force_inline)?;
+	for (key, stored) in originals_key.iter().zip(stored) {
+		set_table(doc.as_table_mut(), key, stored.unwrap_or(Item::None));
+	}
+	Ok(keep_text_format(toml, &doc.to_string()))
+}
+
+fn patch(path: &Path, mutator: &Mutator, force_inline: bool) -> Result<()> {
+	patch_into(path, &Output::File(path), mutator, force_inline, false)
+}
+
+/// Destination of the patched manifest
+enum Output<'p> {
+	File(&'p Path),
+	/// Printed to stdout after the `=== path ===` header, for preview
+	Stdout,
+}
+
+/// Patches manifest at `path`, writing the result to `output`
+///
+/// With `freeze`, stored originals are discarded in the same write
+fn patch_into(
+	path: &Path,
+	output: &Output<'_>,
+	mutator: &Mutator,
+	force_inline: bool,
+	freeze: bool,
+) -> Result<()> {
+	let _manifest = natives::CurrentManifest::enter(path);
+	let toml = fs::read_to_string(path).run_err()?;
+	if !may_have_dependencies(&toml) {
+		debug!("{}: no dependencies, skipping", path.display());
+		return Ok(());
+	}
+	let mut doc: Document = timings::measure(timings::Phase::Parse, || toml.parse()).run_err()?;
+	patch_doc(&mut doc, mutator, force_inline)?;
+	if freeze {
+		freeze_doc(path, &mut doc, &[]);
+	}
+
+	// Unchanged manifests are not touched, to not trigger rebuilds and file watchers
+	let patched = keep_text_format(&toml, &doc.to_string());
+	if patched != toml {
+		match output {
+			Output::File(output) => {
+				if let Some(parent) = output.parent() {
+					fs::create_dir_all(parent).run_err()?;
+				}
+				timings::measure(timings::Phase::Write, || fs::write(output, patched)).run_err()?;
+				mark_changed();
+			}
+			Output::Stdout => print!("=== {} ===\n{patched}", path.display()),
+		}
+	}
 
 	Ok(())
 }
 
+/// All dependencies declared in manifest, with their paths, manifest is not modified
+fn dependencies(path: &Path) -> Result<Vec<(Key, DirectInput)>> {
+	let _manifest = natives::CurrentManifest::enter(path);
+	let toml = fs::read_to_string(path).run_err()?;
+	if !may_have_dependencies(&toml) {
+		return Ok(Vec::new());
+	}
+	let mut doc: Document = timings::measure(timings::Phase::Parse, || toml.parse()).run_err()?;
+	let out = RefCell::new(Vec::new());
+	patch_doc(
+		&mut doc,
+		&|key, input| {
+			out.borrow_mut().push((key.clone(), input));
+			Ok(Either2::A(Null))
+		},
+		false,
+	)?;
+	Ok(out.into_inner())
+}
+
+/// Resolves `..` and symlinks in the path of the source, keeping relative path relative
+fn canonicalize_path(manifest: &Path, source: &mut DirectSource) -> Result<()> {
+	let Some(path) = &source.path else {
+		return Ok(());
+	};
+	let dir = manifest.parent().expect("manifest is a file");
+	let Ok(target) = dir.join(path).canonicalize() else {
+		warn!("{path} doesn't exist, keeping it as is");
+		return Ok(());
+	};
+	let target = natives::path_to_string(&target)?;
+	source.path = Some(if Path::new(path).is_absolute() {
+		target
+	} else {
+		let dir = natives::path_to_string(&dir.canonicalize().run_err()?)?;
+		paths::relative(&dir, &target)?
+	});
+	Ok(())
+}
+
+/// Problem with the path source, if it doesn't point at the crate with expected name
+fn check_path_source(manifest: &Path, package: &str, path: &str) -> Option<String> {
+	let target = manifest
+		.parent()
+		.expect("manifest is a file")
+		.join(path)
+		.join("Cargo.toml");
+	let Ok(toml) = fs::read_to_string(&target) else {
+		return Some(format!("{} doesn't exist", target.display()));
+	};
+	let name = toml
+		.parse::<Document>()
+		.ok()
+		.and_then(|doc| Some(doc.get("package")?.get("name")?.as_str()?.to_owned()));
+	match name {
+		Some(name) if name == package => None,
+		Some(name) => Some(format!("{} is the manifest of {name}", target.display())),
+		None => Some(format!("{} has no package name", target.display())),
+	}
+}
+
+/// Replaces absolute path of the source with the path relative to the manifest, if it points inside of `repository`
+fn relativize_path(manifest: &Path, repository: &Path, source: &mut DirectSource) -> Result<bool> {
+	let Some(path) = &source.path else {
+		return Ok(false);
+	};
+	if !Path::new(path).is_absolute() {
+		return Ok(false);
+	}
+	let Ok(target) = Path::new(path).canonicalize() else {
+		warn!("{path} doesn't exist, keeping it absolute");
+		return Ok(false);
+	};
+	if !target.starts_with(repository) {
+		return Ok(false);
+	}
+	let dir = manifest
+		.parent()
+		.expect("manifest is a file")
+		.canonicalize()
+		.run_err()?;
+	source.path = Some(paths::relative(
+		&natives::path_to_string(&dir)?,
+		&natives::path_to_string(&target)?,
+	)?);
+	Ok(true)
+}
+
+/// Rule result with absolute path made relative, the current source is relativized, if rule left it unchanged
+fn relativize_result(
+	manifest: &Path,
+	repository: &Path,
+	input: &DirectInput,
+	result: Either![Null, DirectSource],
+) -> Result<Either![Null, DirectSource]> {
+	Ok(match result {
+		Either2::A(_) => {
+			let mut source = input.source.clone();
+			if relativize_path(manifest, repository, &mut source)? {
+				Either2::B(source)
+			} else {
+				Either2::A(Null)
+			}
+		}
+		Either2::B(mut source) => {
+			relativize_path(manifest, repository, &mut source)?;
+			Either2::B(source)
+		}
+	})
+}
+
+/// Adds manifests of crates, path-depended on from the specified manifests, which are located outside of the root
+///
+/// Path dependencies introduced by deppatcher itself (having stored originals) are not followed
+fn with_path_deps(root: &Path, mut manifests: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+	let root = root.canonicalize().run_err()?;
+	let mut seen = manifests
+		.iter()
+		.map(|m| m.canonicalize().run_err())
+		.collect::<Result<HashSet<_>>>()?;
+	let mut i = 0;
+	while i < manifests.len() {
+		let manifest = manifests[i].clone();
+		i += 1;
+		let dir = manifest.parent().expect("manifest is a file");
+		for (_, input) in dependencies(&manifest)? {
+			let Some(path) = &input.source.path else {
+				continue;
+			};
+			if input.original_source != input.source {
+				continue;
+			}
+			let Ok(dep) = dir.join(path).join("Cargo.toml").canonicalize() else {
+				continue;
+			};
+			if dep.starts_with(&root) || !seen.insert(dep.clone()) {
+				continue;
+			}
+			if !discovery().include_generated && is_generated(&dep)? {
+				info!(target: PROGRESS, "skipping generated {}", dep.display());
+				continue;
+			}
+			info!(target: PROGRESS, "following path dependency to {}", dep.display());
+			manifests.push(dep);
+		}
+	}
+	Ok(manifests)
+}
+
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq)]
+enum DepKind {
+	Normal,
+	Dev,
+	Build,
+}
+impl DepKind {
+	/// Kind of dependency by its manifest section, `workspace.dependencies` are considered normal
+	fn of(key: &Key) -> Self {
+		match key.iter().rev().nth(1).map(String::as_str) {
+			Some("dev-dependencies") => Self::Dev,
+			Some("build-dependencies") => Self::Build,
+			_ => Self::Normal,
+		}
+	}
+}
+
+/// Target table key of the dependency (`cfg(unix)` for `["target", "cfg(unix)", "dependencies", "serde"]`)
+fn target_of(key: &Key) -> Option<&str> {
+	match key.as_slice() {
+		[target, cfg, _, _] if target == "target" => Some(cfg),
+		_ => None,
+	}
+}
+
+/// Whether dependency is selected by `--target` filter, compared ignoring whitespace
+fn target_matches(targets: &[String], key: &Key) -> bool {
+	let normalize = |s: &str| s.split_whitespace().collect::<String>();
+	targets.is_empty()
+		|| targets.iter().any(|t| {
+			target_of(key).map_or_else(|| t == "none", |cfg| normalize(t) == normalize(cfg))
+		})
+}
+
+#[derive(ValueEnum, Clone, Copy)]
+enum PatchFormat {
+	/// `[patch]` section of Cargo.toml
+	Toml,
+	/// `{"source": {"package": {...}}}` object
+	Json,
+	/// Nix attrset, with the same structure as json
+	Nix,
+}
+
+/// `[patch]` document of the patches keyed by patched source, and then by package name
+///
+/// Every entry is marked as managed by deppatcher, with the hash of the rule which produced it
+fn patch_document(
+	patches: &BTreeMap<String, BTreeMap<String, DirectSource>>,
+	rule_hash: &str,
+) -> Document {
+	let mut table = Document::new();
+	table.insert_formatted(&toml_edit::Key::new("patch"), Item::Table(Table::new()));
+	let patch_table = table
+		.get_mut("patch")
+		.expect("just inserted")
+		.as_table_mut()
+		.expect("table like");
+	patch_table.set_implicit(true);
+
+	for (source, packages) in patches {
+		let source_table = patch_table
+			.entry(source)
+			.or_insert(Item::Table(Table::new()))
+			.as_table_mut()
+			.expect("table like");
+		source_table.set_implicit(false);
+		for (name, v) in packages {
+			let item_table = source_table
+				.entry(name)
+				.or_insert(Item::Value(Value::InlineTable(InlineTable::new())))
+				.as_table_like_mut()
+				.expect("table like");
+			v.write(item_table);
+			if let Some(value) = source_table.get_mut(name).and_then(Item::as_value_mut) {
+				value
+					.decor_mut()
+					.set_suffix(format!(" # {MANAGED_MARKER} rule={rule_hash}"));
+			}
+		}
+	}
+	table
+}
+
 /// Mass rewriter of Cargo.toml files
-#[allow(clippy::large_enum_variant)]
 #[derive(Parser)]
 #[clap(author, disable_version_flag = true)]
-enum Opts {
+struct Opts {
+	/// Operate on the current directory, instead of the enclosing workspace root
+	#[clap(long, global = true)]
+	no_ascend: bool,
+	#[clap(flatten)]
+	discovery: DiscoveryOpts,
+	#[clap(flatten)]
+	cargo: CargoOpts,
+	#[clap(flatten)]
+	verbosity: Verbosity,
+	#[clap(flatten)]
+	run: RunOpts,
+	#[clap(subcommand)]
+	command: Command,
+}
+
+/// How the run interacts with other runs and with the caller
+#[derive(clap::Args)]
+struct RunOpts {
+	/// Wait for other deppatcher instance operating on the same workspace, instead of failing
+	#[clap(long, global = true)]
+	wait: bool,
+	/// Exit with 0 even if files were modified, instead of the distinct code 3
+	#[clap(long, global = true)]
+	no_changes_ok: bool,
+	/// Print time spent in manifest discovery, parsing, rule evaluation, cargo and writing to stderr
+	#[clap(long, global = true)]
+	timings: bool,
+	/// Number of concurrent network requests and external commands, defaults to `CARGO_BUILD_JOBS`,
+	/// or to the number of available cores
+	#[clap(long, short = 'j', global = true)]
+	jobs: Option<usize>,
+}
+
+/// Exit code of the run, which has modified any file, unless `--no-changes-ok` is passed
+///
+/// Errors exit with 1, and invalid arguments are reported by clap with 2
+const CHANGED_EXIT_CODE: u8 = 3;
+
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Records that the run has written a changed file
+pub(crate) fn mark_changed() {
+	CHANGED.store(true, Ordering::Relaxed);
+}
+
+/// Target of per-manifest progress messages, which are hidden by `--quiet`
+const PROGRESS: &str = "deppatcher::progress";
+
+/// Amount of log output
+#[derive(clap::Args)]
+struct Verbosity {
+	/// Only print changes, warnings and errors, without per-manifest progress
+	#[clap(long, short = 'q', global = true, conflicts_with = "verbose")]
+	quiet: bool,
+	/// Also print skipped dependencies and rule decisions, `-vv` additionally prints rule inputs
+	#[clap(long, short = 'v', global = true, action = clap::ArgAction::Count)]
+	verbose: u8,
+}
+impl Verbosity {
+	fn init(&self) {
+		let filter = Targets::new().with_default(Level::INFO);
+		let filter = match (self.quiet, self.verbose) {
+			(true, _) => filter.with_target(PROGRESS, LevelFilter::OFF),
+			(false, 0) => filter,
+			(false, 1) => filter.with_target("deppatcher", Level::DEBUG),
+			(false, _) => filter.with_target("deppatcher", Level::TRACE),
+		};
+		// Stdout is reserved for command output, and for the protocol of `serve`
+		tracing_subscriber::registry()
+			.with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+			.with(filter)
+			.init();
+	}
+}
+
+/// Opt-out of the uncommitted changes check, shared by commands modifying manifests
+#[derive(clap::Args)]
+struct DirtyOpts {
+	/// Allow patching manifests with uncommitted changes
+	#[clap(long)]
+	allow_dirty: bool,
+}
+
+/// Options of manifest discovery, shared by every command
+#[derive(clap::Args, Default)]
+struct DiscoveryOpts {
+	/// Skip files and directories matching glob (relative to the workspace root), i.e `examples/**`
+	#[clap(long, global = true)]
+	exclude: Vec<::glob::Pattern>,
+	/// Only process manifests of specified packages (`name` or `name@version`, name might be a glob)
+	#[clap(long = "package", short = 'p', global = true)]
+	packages: Vec<String>,
+	/// Maximal depth of directories (relative to the workspace root) to look for manifests in,
+	/// `0` only processes the root manifest
+	#[clap(long, global = true)]
+	max_depth: Option<usize>,
+	/// Follow symlinks to directories, symlink loops are skipped
+	#[clap(long, global = true)]
+	follow_symlinks: bool,
+	/// Also process manifests generated by cargo (`target/package`, vendored and registry sources)
+	#[clap(long, global = true)]
+	include_generated: bool,
+	/// Skip manifests of workspaces nested in the tree, only processing the root workspace
+	#[clap(long, global = true)]
+	no_nested: bool,
+	/// Only process manifests of the workspace rooted at this directory (repeatable), without workspaces nested in it
+	#[clap(long, global = true, conflicts_with = "no_nested", value_hint = clap::ValueHint::DirPath)]
+	in_workspace: Vec<PathBuf>,
+	/// Only process manifests listed in the file (one path per line, `-` for stdin),
+	/// instead of looking for them in the directory tree
+	#[clap(
+		long,
+		global = true,
+		conflicts_with_all = ["exclude", "max_depth", "no_nested", "in_workspace"],
+		value_hint = clap::ValueHint::FilePath
+	)]
+	files_from: Option<PathBuf>,
+}
+
+static DISCOVERY: OnceLock<DiscoveryOpts> = OnceLock::new();
+
+fn discovery() -> &'static DiscoveryOpts {
+	DISCOVERY.get_or_init(DiscoveryOpts::default)
+}
+
+/// Flags forwarded to `cargo metadata` invocations
+#[derive(clap::Args, Default)]
+struct CargoOpts {
+	/// Run cargo without accessing the network
+	#[clap(long, global = true)]
+	offline: bool,
+	/// Require Cargo.lock and cache to be up to date
+	#[clap(long, global = true)]
+	frozen: bool,
+	/// Require Cargo.lock to be up to date
+	#[clap(long, global = true)]
+	locked: bool,
+}
+
+static CARGO: OnceLock<CargoOpts> = OnceLock::new();
+
+/// Extra arguments for `cargo metadata`, as specified on the command line
+pub fn cargo_flags() -> Vec<String> {
+	let opts = CARGO.get_or_init(CargoOpts::default);
+	[
+		("--offline", opts.offline),
+		("--frozen", opts.frozen),
+		("--locked", opts.locked),
+	]
+	.into_iter()
+	.filter(|(_, enabled)| *enabled)
+	.map(|(flag, _)| flag.to_owned())
+	.collect()
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
 	/// Rewrite package sources using specified rule
 	Patch {
 		/// Format dependencies as inline table
 		#[clap(long)]
 		force_inline: bool,
+		/// Commit manifests before and after patching, to make it possible to undo the patch with git
+		#[clap(long)]
+		git_commit: bool,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+		/// Confirm every change before applying it
+		#[clap(long, short = 'i')]
+		interactive: bool,
+		/// Read single manifest from stdin, and write the patched manifest to stdout
+		#[clap(long, conflicts_with_all = ["git_commit", "interactive", "output_dir", "follow_path_deps"])]
+		stdin: bool,
+		/// Record originals in the manifest patched with `--stdin`, which is not done by default
+		#[clap(long, requires = "stdin")]
+		record_originals: bool,
+		/// Discard stored originals after patching, in the same write
+		#[clap(long, conflicts_with = "stdin")]
+		freeze: bool,
+		/// Skip manifests unchanged since the last run with the same rules and imported files.
+		/// State read by rules from elsewhere (other workspaces, lockfiles, registry) is not tracked
+		#[clap(long, conflicts_with_all = ["stdin", "interactive", "report", "output_dir", "stdout", "sync_members"])]
+		incremental: bool,
+		/// Write modified manifests into the mirror tree in this directory, leaving the workspace untouched
+		#[clap(long, conflicts_with_all = ["git_commit", "follow_path_deps"])]
+		output_dir: Option<PathBuf>,
+		/// Print modified manifests to stdout, each after the `=== path ===` header, instead of writing them
+		#[clap(long, conflicts_with_all = ["git_commit", "output_dir", "stdin", "report"])]
+		stdout: bool,
+		/// Also process manifests of path dependencies located outside of the workspace
+		#[clap(long)]
+		follow_path_deps: bool,
+		/// Rename and remove member entries inheriting `[workspace.dependencies]` (`workspace = true`)
+		/// together with the workspace entries, when rule doesn't change them itself
+		#[clap(long, conflicts_with = "stdin")]
+		sync_members: bool,
+		/// Rewrite absolute paths pointing inside of the repository to paths relative to the manifest
+		#[clap(long)]
+		relative_paths: bool,
+		/// Resolve `..` and symlinks in path sources, relative paths are kept relative
+		#[clap(long)]
+		canonicalize_paths: bool,
+		/// Print report of applied changes, grouped by package, to stdout
+		#[clap(long, value_enum, conflicts_with = "stdin")]
+		report: Option<report::ReportFormat>,
+		/// Only invoke rule for dependencies of specified kinds
+		#[clap(long = "kind", value_enum)]
+		kinds: Vec<DepKind>,
+		/// Only invoke rule for dependencies in `[target.<cfg>]` tables with specified keys,
+		/// `none` selects dependencies, which are not target-specific
+		#[clap(long = "target")]
+		targets: Vec<String>,
+
+		#[clap(flatten)]
+		input: RulesOpts,
+		#[clap(flatten)]
+		std: StdOpts,
+	},
+	/// Apply rules to every manifest, and then again whenever any of them changes, until interrupted
+	Watch {
+		/// Format dependencies as inline table
+		#[clap(long)]
+		force_inline: bool,
+		/// Skip manifests unchanged since the last run with the same rules and imported files.
+		/// State read by rules from elsewhere (other workspaces, lockfiles, registry) is not tracked
+		#[clap(long)]
+		incremental: bool,
 
 		#[clap(flatten)]
-		input: InputOpts,
+		input: RulesOpts,
+		#[clap(flatten)]
+		std: StdOpts,
+	},
+	/// Print shell completion script to stdout
+	Completions { shell: clap_complete::Shell },
+	/// Write man pages for deppatcher and every its subcommand into the directory
+	Manpages {
+		#[clap(value_hint = clap::ValueHint::DirPath)]
+		dir: PathBuf,
+	},
+	/// Serve JSON-RPC requests over stdio, for editor plugins and other tools
+	Serve {
 		#[clap(flatten)]
 		std: StdOpts,
 	},
 	/// Generate `[patch]` section in workspace Cargo.toml
 	/// Operates on `cargo metadata`, slower, but allows to rewrite other package dependencies
 	SoftPatch {
+		/// Output format of the generated patches
+		#[clap(long, value_enum, default_value_t = PatchFormat::Toml)]
+		format: PatchFormat,
+		/// Only visit direct dependencies of the workspace members, same as `--depth 1`
+		#[clap(long, conflicts_with = "depth")]
+		direct_only: bool,
+		/// Only visit dependencies up to this depth, counting from the workspace members
+		#[clap(long)]
+		depth: Option<usize>,
+		/// Generate patches for the workspace containing this manifest, instead of the current one
+		#[clap(long)]
+		manifest_path: Option<PathBuf>,
+		/// Reference Cargo.lock (i.e from before the patches were applied),
+		/// `originalSource` is taken from, otherwise it is equal to the current source
+		#[clap(long)]
+		baseline: Option<PathBuf>,
+		/// Index url of the registry, as `<name>=<url>`, in addition to `[registries]` of the cargo config.
+		/// Rules see registries by name, patches are emitted for their urls
+		#[clap(long)]
+		registry_url: Vec<String>,
+
+		#[clap(flatten)]
+		input: RulesOpts,
+		#[clap(flatten)]
+		std: StdOpts,
+	},
+	/// Print dependencies, for which specified predicate returns true, as JSON, without modifying anything
+	Query {
 		#[clap(flatten)]
 		input: InputOpts,
 		#[clap(flatten)]
-		std: StdOpts,
+		std: StdOpts,
+	},
+	/// Check every dependency against policy rules, and fail if any are violated
+	///
+	/// Rules file should evaluate to function, returning null, violation message, or array of them
+	Policy {
+		/// Path to the rules file
+		#[clap(long)]
+		rules: String,
+		#[clap(flatten)]
+		std: StdOpts,
+	},
+	/// Add dependency to every selected manifest (use `-p` to select members)
+	#[clap(group(clap::ArgGroup::new("source").required(true)))]
+	Add {
+		/// Package to depend on
+		package: String,
+		/// Dependency entry name, if it should differ from the package name
+		#[clap(long)]
+		rename: Option<String>,
+		/// Version requirement
+		#[clap(long, group = "source")]
+		version: Option<String>,
+		/// Registry to use, default registry is used if not specified
+		#[clap(long, requires = "version")]
+		registry: Option<String>,
+		/// Path to the dependency, relative to the current directory
+		#[clap(long, group = "source")]
+		path: Option<PathBuf>,
+		/// Git repository url
+		#[clap(long, group = "source")]
+		git: Option<String>,
+		#[clap(long, requires = "git")]
+		rev: Option<String>,
+		#[clap(long, requires = "git")]
+		tag: Option<String>,
+		#[clap(long, requires = "git")]
+		branch: Option<String>,
+		/// Inherit dependency from `[workspace.dependencies]`
+		#[clap(long, group = "source")]
+		workspace: bool,
+		/// Section to add dependency to
+		#[clap(long, value_enum, default_value = "normal")]
+		kind: DepKind,
+		/// Add dependency to `[target.<cfg>]` table
+		#[clap(long)]
+		target: Option<String>,
+		/// Features to enable, comma separated
+		#[clap(long, value_delimiter = ',')]
+		features: Vec<String>,
+		/// Mark dependency as optional
+		#[clap(long)]
+		optional: bool,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Merge `[patch]` section generated by `soft-patch` into the workspace manifest
+	ApplyPatchFile {
+		/// Patch file, `-` to read it from stdin
+		file: PathBuf,
+		/// Write patches to `.cargo/config.toml` of the workspace, instead of its manifest
+		#[clap(long)]
+		config: bool,
+		/// Overwrite existing entries, which are not managed by deppatcher
+		#[clap(long)]
+		force: bool,
+		/// Allow modifying files with uncommitted changes
+		#[clap(long)]
+		allow_dirty: bool,
+	},
+	/// Rewrite absolute paths pointing inside of the repository to paths relative to the manifest,
+	/// same as `patch --relative-paths` with no-op rule
+	RelativePaths {
+		#[clap(flatten)]
+		dirty: DirtyOpts,
 	},
 	/// Revert back to original packages version
-	Revert,
+	Revert {
+		/// Also process manifests of path dependencies located outside of the workspace
+		#[clap(long)]
+		follow_path_deps: bool,
+		/// Remove now redundant originals, as `freeze` would do
+		#[clap(long)]
+		freeze: bool,
+	},
 	/// Rewrite all package sources, to ones defined in specified workspace
 	Link {
 		/// Workspace to link
+		#[clap(value_hint = clap::ValueHint::DirPath)]
 		workspace: String,
 		/// Use soft-patch instead of patch
 		#[clap(long)]
 		soft: bool,
 		#[clap(long)]
 		by_version: bool,
+		/// Copy linked crates into this directory (relative to the workspace root), and link to the copies with relative paths
+		#[clap(long, conflicts_with = "by_version", value_hint = clap::ValueHint::DirPath)]
+		copy: Option<PathBuf>,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Link dependencies to writable copies of their sources from the cargo registry cache,
+	/// made in `.deppatcher/registry`
+	LinkRegistry {
+		/// Packages to link (`name` or `name@version`), version is only required if multiple are locked
+		#[clap(required = true, value_name = "PACKAGE")]
+		crates: Vec<String>,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Clone repository of the dependency at the resolved version, and link dependencies on it to the clone
+	Fork {
+		/// Package to fork (`name` or `name@version`), version is only required if multiple are locked
+		#[clap(value_name = "PACKAGE")]
+		spec: String,
+		/// Directory for clones, relative to the workspace root
+		#[clap(long, default_value = "forks", value_hint = clap::ValueHint::DirPath)]
+		dir: PathBuf,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Rewrite dependencies to crates in the directory produced by `cargo vendor`
+	VendorLink {
+		/// Vendor directory
+		#[clap(default_value = "vendor", value_hint = clap::ValueHint::DirPath)]
+		dir: String,
+		/// Use soft-patch instead of patch
+		#[clap(long)]
+		soft: bool,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
 	},
 	/// Remove all saved original packages
-	Freeze,
+	Freeze {
+		/// Only discard originals of dependencies on these packages (globs are supported), keeping the rest revertible
+		#[clap(long = "dep", value_name = "PACKAGE")]
+		deps: Vec<::glob::Pattern>,
+		/// Only freeze these manifests, or manifests located under these directories
+		paths: Vec<PathBuf>,
+		/// Also process manifests of path dependencies located outside of the workspace
+		#[clap(long)]
+		follow_path_deps: bool,
+	},
+	/// Raise requirements of dependencies affected by security advisories to the nearest fixed versions
+	AuditPatch {
+		/// Local clone of advisory database, `$CARGO_HOME/advisory-db` is used by default
+		#[clap(long)]
+		db: Option<PathBuf>,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+		/// Also fix dependencies with informational advisories (unmaintained, unsound)
+		#[clap(long)]
+		informational: bool,
+		/// Only print report, without modifying manifests
+		#[clap(long)]
+		dry_run: bool,
+	},
+	/// Report dependencies, for which newer compatible or incompatible releases are available in crates.io index
+	Outdated {
+		#[clap(long, value_enum, default_value_t)]
+		format: outdated::OutdatedFormat,
+		/// Which upgrades are included into the generated rule
+		#[clap(long, value_enum, default_value_t)]
+		policy: outdated::UpgradePolicy,
+	},
+	/// Print software bill of materials, listing resolved packages with their current sources
+	Sbom {
+		#[clap(long, value_enum)]
+		format: sbom::SbomFormat,
+		/// Annotate packages with originals stored for dependencies on them
+		#[clap(long)]
+		originals: bool,
+	},
+	/// Report packages, which version or source differs between two lockfiles
+	DiffLock {
+		old: PathBuf,
+		new: PathBuf,
+		#[clap(long, value_enum, default_value_t)]
+		format: diff_lock::DiffFormat,
+	},
+	/// Rewrite version requirements of dependencies to versions resolved in Cargo.lock
+	SyncVersions {
+		/// Pin exact versions with `=` requirements
+		#[clap(long)]
+		exact: bool,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Pin every registry dependency to the version resolved in Cargo.lock with `=` requirement,
+	/// same as `sync-versions --exact`
+	PinExact {
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Set `rust-version` of every member declaring it (or `workspace.package.rust-version` they inherit),
+	/// previous values are restored by `revert`
+	SetRustVersion {
+		/// New MSRV, i.e `1.70`
+		version: String,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Relax `=` pins of dependencies into caret requirements, the inverse of `pin-exact`
+	Unpin {
+		/// Parts of the pinned version kept in the requirement
+		#[clap(long, value_enum, default_value_t = versions::Precision::Patch)]
+		precision: versions::Precision,
+		/// How caret requirements are written, bare by default
+		#[clap(long, value_enum)]
+		style: Option<versions::ReqStyle>,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Write version requirement operators of dependencies in the same way
+	#[clap(group(clap::ArgGroup::new("normalization").required(true).multiple(true)))]
+	NormalizeVersions {
+		/// How caret requirements are written
+		#[clap(long, value_enum, group = "normalization")]
+		style: Option<versions::ReqStyle>,
+		/// Turn `=` pins into caret requirements
+		#[clap(long, group = "normalization")]
+		strip_exact: bool,
+		#[clap(flatten)]
+		dirty: DirtyOpts,
+	},
+	/// Print resolved dependency tree of the workspace, annotated with package sources
+	Tree {
+		/// Only show packages matching any of glob patterns, and their dependents
+		#[clap(long)]
+		filter: Vec<::glob::Pattern>,
+		#[clap(long, value_enum, default_value_t)]
+		color: style::ColorChoice,
+	},
+	/// Show where package is declared, with which sources, and how it is reached from the workspace
+	Why {
+		/// Package name
+		package: String,
+	},
+	/// Remove stale state from `.deppatcher` directory, and clones left by interrupted runs
+	Gc {
+		/// Remove entries unused for this many days
+		#[clap(long, default_value_t = 30)]
+		max_age_days: u64,
+		/// Only print entries, which would be removed
+		#[clap(long)]
+		dry_run: bool,
+	},
+	/// Diagnose leftovers of previous runs: stale originals, missing path dependencies, unused patches
+	Doctor,
+	/// Fail if any manifest still contains overrides, stored originals, or deppatcher-managed patches
+	VerifyClean,
+	/// Install git hooks, running `verify-clean` before commit or push
+	InstallHooks {
+		/// Hooks to install
+		#[clap(long = "hook", value_enum, default_value = "pre-commit")]
+		hooks: Vec<hooks::Hook>,
+		/// Overwrite existing hooks, which were not installed by deppatcher
+		#[clap(long)]
+		force: bool,
+	},
+	/// Print content hashes of overridden git and path sources, keyed by package name
+	LockHashes,
+	/// Print `outputHashes` attrset for nixpkgs' `importCargoLock`, prefetching every git dependency
+	NixOutputHashes {
+		/// Lockfile to read git dependencies from
+		#[clap(long, default_value = "Cargo.lock")]
+		lockfile: PathBuf,
+	},
+}
+impl Command {
+	/// Whether command writes to manifests or state of the workspace, and thus should hold the lock
+	fn modifies_manifests(&self) -> bool {
+		match self {
+			Self::Patch {
+				stdin,
+				stdout,
+				output_dir,
+				..
+			} => !stdin && !stdout && output_dir.is_none(),
+			Self::AuditPatch { dry_run, .. } | Self::Gc { dry_run, .. } => !dry_run,
+			Self::Add { .. }
+			| Self::ApplyPatchFile { .. }
+			| Self::Freeze { .. }
+			| Self::SyncVersions { .. }
+			| Self::PinExact { .. }
+			| Self::SetRustVersion { .. }
+			| Self::Unpin { .. }
+			| Self::NormalizeVersions { .. }
+			| Self::Revert { .. }
+			| Self::RelativePaths { .. }
+			| Self::LinkRegistry { .. }
+			| Self::Fork { .. } => true,
+			Self::Link { soft, .. } | Self::VendorLink { soft, .. } => !soft,
+			_ => false,
+		}
+	}
 }
 
-#[builtin]
-fn load_paths(loc: CallLocation, workspace: String) -> Result<ObjValue> {
-	let mut path = match loc.0 {
-		Some(loc) => loc
-			.0
-			.source_path()
-			.path()
-			.map_or(current_dir().expect("no current dir?"), Path::to_path_buf),
-		None => bail!("only callable from jsonnet"),
+/// Directory, relative to which paths passed to natives are resolved:
+/// directory of calling rule file, or current directory for inline rules
+pub(crate) fn caller_dir(loc: CallLocation) -> Result<PathBuf> {
+	let Some(loc) = loc.0 else {
+		bail!("only callable from jsonnet")
 	};
-	path.push(workspace);
+	let source = loc.0.source_path();
+	Ok(match source.path() {
+		Some(path) if source.downcast_ref::<SourceFile>().is_some() => {
+			path.parent().expect("file always has parent").to_path_buf()
+		}
+		Some(path) => path.to_path_buf(),
+		None => current_dir().run_err()?,
+	})
+}
+
+/// Options of `dpp.loadPaths`, forwarded to `cargo metadata` in addition to the command line flags
+#[derive(Typed, Default, Debug)]
+struct MetadataOptions {
+	offline: Option<bool>,
+	locked: Option<bool>,
+	features: Option<Vec<String>>,
+	#[typed(rename = "noDefaultFeatures")]
+	no_default_features: Option<bool>,
+}
 
-	let mut command = cargo_metadata::MetadataCommand::new();
-	command.no_deps();
-	command.current_dir(path);
-	let metadata = command.exec().run_err()?;
+#[builtin]
+fn load_paths(
+	loc: CallLocation,
+	workspace: String,
+	options: Option<MetadataOptions>,
+) -> Result<ObjValue> {
+	let mut path = caller_dir(loc)?;
+	path.push(workspace);
+	let path = path.canonicalize().unwrap_or(path);
+	let options = options.unwrap_or_default();
+	let key = format!("loadPaths {} {options:?}", path.display());
+	let packages = natives::cached(key, || {
+		let mut command = cargo_metadata::MetadataCommand::new();
+		command.no_deps();
+		let mut flags = cargo_flags();
+		for (flag, enabled) in [("--offline", options.offline), ("--locked", options.locked)] {
+			if enabled == Some(true) && !flags.iter().any(|f| f == flag) {
+				flags.push(flag.to_owned());
+			}
+		}
+		command.other_options(flags);
+		if options.no_default_features == Some(true) {
+			command.features(cargo_metadata::CargoOpt::NoDefaultFeatures);
+		}
+		if let Some(features) = options.features {
+			command.features(cargo_metadata::CargoOpt::SomeFeatures(features));
+		}
+		command.current_dir(path);
+		let metadata = timings::measure(timings::Phase::Metadata, || command.exec()).run_err()?;
+		Ok(metadata
+			.packages
+			.into_iter()
+			.map(|package| {
+				let path = package.manifest_path.parent().unwrap();
+				(package.name, paths::normalize(path.as_str()))
+			})
+			.collect::<Vec<_>>())
+	})?;
 
 	let mut out = ObjValueBuilder::new();
-	for package in &metadata.packages {
-		let path = package.manifest_path.parent().unwrap();
-		out.field(package.name.clone())
-			.value(Val::Str(StrValue::Flat(path.to_string().into())));
+	for (name, path) in packages.iter() {
+		out.field(name.clone())
+			.value(Val::Str(StrValue::Flat(path.as_str().into())));
 	}
 	Ok(out.build())
 }
 
 #[builtin]
 fn load_locked(loc: CallLocation, lockfile: String) -> Result<ObjValue> {
-	let mut path = match loc.0 {
-		Some(loc) => loc
-			.0
-			.source_path()
-			.path()
-			.map_or(current_dir().expect("no current dir?"), Path::to_path_buf),
-		None => bail!("only callable from jsonnet"),
-	};
+	let mut path = caller_dir(loc)?;
 	path.push(lockfile);
+	let path = path.canonicalize().unwrap_or(path);
 
-	let lockfile = cargo_lock::Lockfile::load(path).run_err()?;
+	let key = format!("loadLocked {}", path.display());
+	let packages = natives::cached(key, || {
+		let lockfile = cargo_lock::Lockfile::load(path).run_err()?;
+		Ok(lockfile
+			.packages
+			.into_iter()
+			.filter(|dep| dep.source.is_none())
+			.map(|dep| (dep.name.to_string(), dep.version.to_string()))
+			.collect::<Vec<_>>())
+	})?;
 	let mut out = ObjValueBuilder::new();
-	for dep in lockfile.packages {
-		if dep.source.is_some() {
-			continue;
-		}
-		out.field(dep.name.to_string())
-			.value(Val::Str(StrValue::Flat(dep.version.to_string().into())));
+	for (name, version) in packages.iter() {
+		out.field(name.clone())
+			.value(Val::Str(StrValue::Flat(version.as_str().into())));
 	}
 	Ok(out.build())
 }
 
+#[builtin]
+fn load_cargo_config(loc: CallLocation, dir: Option<String>) -> Result<Val> {
+	let mut path = caller_dir(loc)?;
+	if let Some(dir) = dir {
+		path.push(dir);
+	}
+	Val::from_serde(cargo_config::load(&path)?).run_err()
+}
+
 #[derive(Trace)]
 struct DppContextInitializer;
 impl ContextInitializer for DppContextInitializer {
@@ -513,6 +2759,41 @@ impl ContextInitializer for DppContextInitializer {
 			.value(Val::Func(FuncVal::StaticBuiltin(load_paths::INST)));
 		dpp.field("loadLocked")
 			.value(Val::Func(FuncVal::StaticBuiltin(load_locked::INST)));
+		dpp.field("loadCargoConfig")
+			.value(Val::Func(FuncVal::StaticBuiltin(load_cargo_config::INST)));
+		dpp.field("workspaceRoot")
+			.value(Val::Func(FuncVal::StaticBuiltin(
+				natives::workspace_root::INST,
+			)));
+		dpp.field("manifestDir")
+			.value(Val::Func(FuncVal::StaticBuiltin(
+				natives::manifest_dir::INST,
+			)));
+		dpp.field("ruleDir")
+			.value(Val::Func(FuncVal::StaticBuiltin(natives::rule_dir::INST)));
+		dpp.field("loadGraph")
+			.value(Val::Func(FuncVal::StaticBuiltin(graph::load_graph::INST)));
+		dpp.field("relpath")
+			.value(Val::Func(FuncVal::StaticBuiltin(paths::relpath::INST)));
+		dpp.field("loadAdvisories")
+			.value(Val::Func(FuncVal::StaticBuiltin(
+				advisories::load_advisories::INST,
+			)));
+		dpp.field("loadFlakeInputs")
+			.value(Val::Func(FuncVal::StaticBuiltin(
+				nix::load_flake_inputs::INST,
+			)));
+		dpp.field("loadVendored")
+			.value(Val::Func(FuncVal::StaticBuiltin(
+				vendor::load_vendored::INST,
+			)));
+		dpp.field("glob")
+			.value(Val::Func(FuncVal::StaticBuiltin(natives::glob::INST)));
+		dpp.field("maxVersionFor")
+			.value(Val::Func(FuncVal::StaticBuiltin(
+				index::max_version_for::INST,
+			)));
+		dpp.field("semver").value(versions::semver_lib());
 		let dpp = dpp.build();
 		builder.bind("dpp", Thunk::evaluated(Val::from(dpp)));
 	}
@@ -522,29 +2803,271 @@ impl ContextInitializer for DppContextInitializer {
 	}
 }
 
-#[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
-fn main() -> Result<()> {
-	tracing_subscriber::fmt::init();
+type MutatorFn = NativeFn<((DirectInput,), Either![Null, DirectSource])>;
+
+/// Evaluator with std and `dpp` helpers available
+///
+/// Context initialization and imported files are shared by everything evaluated in it,
+/// so rules applied together should be loaded into the same state
+fn rule_state(std: &StdOpts) -> Result<State> {
+	let s = State::default();
+
+	s.set_context_initializer((
+		std.context_initializer(&s)?.expect("nostd is not working"),
+		DppContextInitializer,
+	));
+	s.set_import_resolver(fingerprint::TrackingImportResolver::default());
+	Ok(s)
+}
+
+/// Evaluate rule file or snippet, with `dpp` helpers available
+fn load_rule(input: InputOpts, std: &StdOpts) -> Result<Val> {
+	load_rule_in(&rule_state(std)?, input)
+}
+
+fn load_rule_in(s: &State, input: InputOpts) -> Result<Val> {
+	if input.exec {
+		s.evaluate_snippet("<cmdline>".to_string(), input.input)
+	} else if input.input.as_str() == "-" {
+		let mut code = String::new();
+		stdin().read_to_string(&mut code).run_err()?;
+		s.evaluate_snippet("<stdin>".to_string(), code)
+	} else {
+		s.import(PathBuf::from(input.input))
+	}
+}
+
+/// One or more rules, applied in order
+#[derive(clap::Args)]
+struct RulesOpts {
+	/// Treat inputs as code, evaluate them instead of reading files
+	#[clap(long, short = 'e')]
+	exec: bool,
+	/// Paths to the rule files if `--exec` is unset, otherwise code itself.
+	/// Source returned by one rule becomes the input source of the next one
+	#[clap(required = true)]
+	inputs: Vec<String>,
+}
+impl RulesOpts {
+	/// Human readable description of the rules, i.e for commit messages
+	fn describe(&self) -> String {
+		let inputs = self.inputs.join(" ");
+		if self.exec {
+			format!("-e {inputs}")
+		} else {
+			inputs
+		}
+	}
+	/// Whether any of the rules is read from stdin
+	fn reads_stdin(&self) -> bool {
+		!self.exec && self.inputs.iter().any(|i| i == "-")
+	}
+	/// Short hash of the rules code, to identify the rules which produced the output
+	fn hash(&self) -> String {
+		let mut hasher = Sha256::new();
+		for input in &self.inputs {
+			if self.exec {
+				hasher.update(input);
+			} else {
+				// Rule from stdin can't be read twice, its name is hashed instead
+				hasher.update(fs::read(input).unwrap_or_else(|_| input.clone().into_bytes()));
+			}
+		}
+		hasher
+			.finalize()
+			.iter()
+			.take(6)
+			.fold(String::new(), |mut out, b| {
+				let _ = write!(out, "{b:02x}");
+				out
+			})
+	}
+}
+
+/// Rules applied as a pipeline
+struct Mutators(Vec<MutatorFn>);
+impl Mutators {
+	/// Returns `null` if no rule has changed the source
+	fn apply(&self, input: DirectInput) -> Result<Either![Null, DirectSource]> {
+		timings::record_input(|| format!("{input:?}"));
+		timings::measure(timings::Phase::Evaluate, || self.apply_all(input))
+	}
+
+	fn apply_all(&self, mut input: DirectInput) -> Result<Either![Null, DirectSource]> {
+		let mut changed = false;
+		let (mut name, mut package) = (None, None);
+		for mutator in &self.0 {
+			if let Either2::B(source) = mutator(input.clone())? {
+				// Renames are preserved, even if next rules don't mention them
+				if let Some(new) = &source.name {
+					input.name.clone_from(new);
+					name = Some(new.clone());
+				}
+				if let Some(new) = &source.package {
+					input.package.clone_from(new);
+					package = Some(new.clone());
+				}
+				input.source = source;
+				changed = true;
+			}
+		}
+		Ok(if changed {
+			Either2::B(DirectSource {
+				name,
+				package,
+				..input.source
+			})
+		} else {
+			Either2::A(Null)
+		})
+	}
+}
+
+fn load_mutators(rules: RulesOpts, std: &StdOpts) -> Result<Mutators> {
+	let s = rule_state(std)?;
+	rules
+		.inputs
+		.into_iter()
+		.map(|input| {
+			MutatorFn::from_untyped(load_rule_in(
+				&s,
+				InputOpts {
+					exec: rules.exec,
+					input,
+				},
+			)?)
+		})
+		.collect::<Result<_>>()
+		.map(Mutators)
+}
+
+/// Directory to operate on, the enclosing workspace root, unless `--no-ascend` is passed
+fn discover_root(no_ascend: bool) -> Result<PathBuf> {
+	let dir = current_dir().run_err()?;
+	if no_ascend {
+		Ok(dir)
+	} else {
+		workspace_root(&dir)
+	}
+}
 
-	let mut opts = Opts::parse();
-	if matches!(opts, Opts::Revert) {
-		opts = Opts::parse_from(["deppatcher", "patch", "-e", "function(p) p.originalSource"]);
-	} else if let Opts::Link {
+#[allow(clippy::too_many_lines, clippy::cognitive_complexity)]
+fn main() -> Result<ExitCode> {
+	let Opts {
+		no_ascend,
+		discovery,
+		cargo,
+		verbosity,
+		run: RunOpts {
+			wait,
+			no_changes_ok,
+			timings,
+			jobs,
+		},
+		mut command,
+	} = Opts::parse();
+	verbosity.init();
+	jobs::init(jobs);
+	let _timings_report = timings.then(timings::Report::start);
+	assert!(
+		DISCOVERY.set(discovery).is_ok(),
+		"discovery is initialized once"
+	);
+	assert!(CARGO.set(cargo).is_ok(), "cargo flags are initialized once");
+	// Don't require being in the workspace
+	match &command {
+		Command::Completions { shell } => {
+			completions::completions(*shell);
+			return Ok(ExitCode::SUCCESS);
+		}
+		Command::Manpages { dir } => return completions::manpages(dir).map(|()| ExitCode::SUCCESS),
+		_ => {}
+	}
+	let root = discover_root(no_ascend)?;
+	natives::init_workspace_root(&root);
+	// Taken before expanding aliases, as some of them modify files themselves
+	let _lock = command
+		.modifies_manifests()
+		.then(|| lock::acquire(&root, wait))
+		.transpose()?;
+	if let Command::Revert {
+		follow_path_deps,
+		freeze,
+	} = command
+	{
+		// Not a dependency, so it is not visible to the rule below
+		rust_version::revert(&root)?;
+		// Reverting is undoing our own changes, which are expected to be uncommitted
+		let mut args = vec!["deppatcher", "patch", "--allow-dirty"];
+		if follow_path_deps {
+			args.push("--follow-path-deps");
+		}
+		if freeze {
+			args.push("--freeze");
+		}
+		args.extend(["-e", "function(p) p.originalSource"]);
+		command = Opts::parse_from(args).command;
+	} else if let Command::RelativePaths {
+		dirty: DirtyOpts { allow_dirty },
+	} = command
+	{
+		let mut args = vec!["deppatcher", "patch", "--relative-paths"];
+		if allow_dirty {
+			args.push("--allow-dirty");
+		}
+		args.extend(["-e", "function(p) null"]);
+		command = Opts::parse_from(args).command;
+	} else if let Command::Link {
 		workspace,
 		soft,
 		by_version,
-	} = opts
+		copy,
+		dirty: DirtyOpts { allow_dirty },
+	} = command
 	{
 		let mut ext = String::new();
 		ext.push_str("linkTo=");
-		ext.push_str(&workspace);
-		opts = Opts::parse_from([
-			"deppatcher",
-			if soft { "soft-patch" } else { "patch" },
-			"--ext-str",
+		if let Some(copy) = &copy {
+			// Checked before copying, not to leave copies behind when patch refuses to run
+			if !allow_dirty && !soft {
+				git::ensure_clean(&manifests(&root)?)?;
+			}
+			let copies = copy::copy_linked(&root, Path::new(&workspace), copy)?;
+			ext.push_str(&serde_json::to_string(&copies).run_err()?);
+		} else {
+			ext.push_str(&workspace);
+		}
+		let mut args = vec!["deppatcher", if soft { "soft-patch" } else { "patch" }];
+		if allow_dirty && !soft {
+			args.push("--allow-dirty");
+		}
+		args.extend([
+			if copy.is_some() {
+				"--ext-code"
+			} else {
+				"--ext-str"
+			},
 			&ext,
 			"-e",
-			if by_version {
+			// `[patch]` paths are resolved from the workspace root, not from the dependent manifest
+			if copy.is_some() && soft {
+				r"
+					local linkTo = std.extVar('linkTo');
+					function(pkg) if std.objectHas(linkTo, pkg.package) then {
+						path: linkTo[pkg.package],
+					}
+				"
+			} else if copy.is_some() {
+				r"
+					local linkTo = std.extVar('linkTo');
+					function(pkg) if
+						std.objectHas(linkTo, pkg.package)
+						&& !std.get(pkg.source, 'workspace', false)
+					then {
+						path: dpp.relpath(dpp.manifestDir(), linkTo[pkg.package]),
+					}
+				"
+			} else if by_version {
 				r"
 					local linkFrom = dpp.loadLocked('./Cargo.lock');
 					local _linkTo = dpp.loadLocked(std.extVar('linkTo'));
@@ -572,73 +3095,552 @@ fn main() -> Result<()> {
 				"
 			},
 		]);
-	}
-	match opts {
-		Opts::Freeze => {
-			for entry in walkdir::WalkDir::new(current_dir().run_err()?) {
-				let entry = entry.run_err()?;
-				if entry.file_type().is_file() && entry.path().ends_with("Cargo.toml") {
-					info!("freezing {}", entry.path().display());
-					freeze(entry.path())?;
+		command = Opts::parse_from(args).command;
+	} else if matches!(command, Command::LinkRegistry { .. } | Command::Fork { .. }) {
+		// Checked before copying or cloning, not to leave them behind when patch refuses to run
+		let ensure_clean = |allow_dirty| {
+			if allow_dirty {
+				Ok(())
+			} else {
+				git::ensure_clean(&manifests(&root)?)
+			}
+		};
+		let (copies, allow_dirty) = match command {
+			Command::LinkRegistry {
+				crates,
+				dirty: DirtyOpts { allow_dirty },
+			} => {
+				ensure_clean(allow_dirty)?;
+				(copy::copy_from_registry(&root, &crates)?, allow_dirty)
+			}
+			Command::Fork {
+				spec,
+				dir,
+				dirty: DirtyOpts { allow_dirty },
+			} => {
+				ensure_clean(allow_dirty)?;
+				(fork::fork(&root, &spec, &dir)?, allow_dirty)
+			}
+			_ => unreachable!("checked above"),
+		};
+		let ext = format!("linkTo={}", serde_json::to_string(&copies).run_err()?);
+		let mut args = vec!["deppatcher", "patch"];
+		if allow_dirty {
+			args.push("--allow-dirty");
+		}
+		args.extend([
+			"--ext-code",
+			&ext,
+			"-e",
+			r"
+				local linkTo = std.extVar('linkTo');
+				function(pkg) if
+					std.objectHas(linkTo, pkg.package)
+					&& !std.get(pkg.source, 'workspace', false)
+					&& !std.objectHas(pkg.source, 'path')
+					&& dpp.semver.satisfies(linkTo[pkg.package].version, std.get(pkg.source, 'version', '*'))
+				then {
+					path: dpp.relpath(dpp.manifestDir(), linkTo[pkg.package].path),
 				}
+			",
+		]);
+		command = Opts::parse_from(args).command;
+	} else if let Command::VendorLink {
+		dir,
+		soft,
+		dirty: DirtyOpts { allow_dirty },
+	} = command
+	{
+		let ext = format!("vendorDir={dir}");
+		let mut args = vec!["deppatcher", if soft { "soft-patch" } else { "patch" }];
+		if allow_dirty && !soft {
+			args.push("--allow-dirty");
+		}
+		args.extend([
+			"--ext-str",
+			&ext,
+			"-e",
+			// Newest vendored version matching the requirement is picked
+			if soft {
+				r"
+					local vendored = dpp.loadVendored(std.extVar('vendorDir'));
+					local newest(versions) = std.foldl(function(a, b) if a == null || dpp.semver.compare(b, a) > 0 then b else a, versions, null);
+					function(pkg) if std.objectHas(vendored, pkg.package) then
+						local version = newest([
+							v for v in std.objectFields(vendored[pkg.package])
+							if !std.objectHas(pkg.source, 'version') || dpp.semver.satisfies(v, pkg.source.version)
+						]);
+						if version != null then {
+							path: vendored[pkg.package][version],
+						}
+				"
+			} else {
+				r"
+					local vendored = dpp.loadVendored(std.extVar('vendorDir'));
+					local newest(versions) = std.foldl(function(a, b) if a == null || dpp.semver.compare(b, a) > 0 then b else a, versions, null);
+					function(pkg) if
+						std.objectHas(vendored, pkg.package)
+						&& !std.get(pkg.source, 'workspace', false)
+					then
+						local version = newest([
+							v for v in std.objectFields(vendored[pkg.package])
+							if !std.objectHas(pkg.source, 'version') || dpp.semver.satisfies(v, pkg.source.version)
+						]);
+						if version != null then {
+							path: dpp.relpath(dpp.manifestDir(), vendored[pkg.package][version]),
+						}
+				"
+			},
+		]);
+		command = Opts::parse_from(args).command;
+	}
+	// Set by checks, which found problems, exit code is returned to let guards (i.e timings report) finish
+	let mut failed = false;
+	match command {
+		Command::Freeze {
+			deps,
+			paths,
+			follow_path_deps,
+		} => {
+			let mut manifests = manifests(&root)?;
+			if follow_path_deps {
+				manifests = with_path_deps(&root, manifests)?;
+			}
+			if !paths.is_empty() {
+				manifests = within_paths(manifests, &paths)?;
+			}
+			for manifest in manifests {
+				info!(target: PROGRESS, "freezing {}", manifest.display());
+				freeze(&manifest, &deps)?;
+			}
+		}
+		Command::ApplyPatchFile {
+			file,
+			config,
+			force,
+			allow_dirty,
+		} => {
+			let patch = if file.as_os_str() == "-" {
+				let mut patch = String::new();
+				stdin().read_to_string(&mut patch).run_err()?;
+				patch
+			} else {
+				fs::read_to_string(&file).run_err()?
+			};
+			let patch: Document = patch.parse().run_err()?;
+			let target = if config {
+				root.join(".cargo/config.toml")
+			} else {
+				root.join("Cargo.toml")
+			};
+			if !allow_dirty && target.exists() {
+				git::ensure_clean(std::slice::from_ref(&target))?;
+			}
+			apply_patch::apply_patch_file(&target, &patch, force)?;
+		}
+		Command::Add {
+			package,
+			rename,
+			version,
+			registry,
+			path,
+			git,
+			rev,
+			tag,
+			branch,
+			workspace,
+			kind,
+			target,
+			features,
+			optional,
+			dirty: DirtyOpts { allow_dirty },
+		} => {
+			let path = path
+				.map(|p| natives::path_to_string(&current_dir().run_err()?.join(p)))
+				.transpose()?;
+			let manifests = manifests(&root)?;
+			if !allow_dirty {
+				git::ensure_clean(&manifests)?;
+			}
+			let (name, package) = match rename {
+				Some(rename) => (rename, Some(package)),
+				None => (package, None),
+			};
+			add::add(
+				&manifests,
+				&add::NewDependency {
+					name,
+					package,
+					source: DirectSource {
+						version,
+						registry,
+						path,
+						git,
+						rev,
+						tag,
+						branch,
+						workspace: workspace.then_some(true),
+						..DirectSource::default()
+					},
+					kind,
+					target,
+					features,
+					optional,
+				},
+			)?;
+		}
+		Command::Revert { .. }
+		| Command::Link { .. }
+		| Command::LinkRegistry { .. }
+		| Command::Fork { .. }
+		| Command::VendorLink { .. }
+		| Command::RelativePaths { .. } => {
+			unreachable!("this is alias")
+		}
+		Command::Completions { .. } | Command::Manpages { .. } => unreachable!("handled above"),
+		Command::AuditPatch {
+			db,
+			dirty: DirtyOpts { allow_dirty },
+			informational,
+			dry_run,
+		} => {
+			if !allow_dirty && !dry_run {
+				git::ensure_clean(&manifests(&root)?)?;
+			}
+			audit::audit_patch(&root, db, informational, dry_run)?;
+		}
+		Command::Outdated { format, policy } => outdated::outdated(&root, format, policy)?,
+		Command::Sbom { format, originals } => sbom::sbom(&root, format, originals)?,
+		Command::DiffLock { old, new, format } => diff_lock::diff_lock(&old, &new, format)?,
+		Command::SyncVersions {
+			exact,
+			dirty: DirtyOpts { allow_dirty },
+		} => {
+			if !allow_dirty {
+				git::ensure_clean(&manifests(&root)?)?;
+			}
+			sync_versions::sync_versions(&root, exact)?;
+		}
+		Command::PinExact {
+			dirty: DirtyOpts { allow_dirty },
+		} => {
+			if !allow_dirty {
+				git::ensure_clean(&manifests(&root)?)?;
+			}
+			sync_versions::sync_versions(&root, true)?;
+		}
+		Command::SetRustVersion {
+			version,
+			dirty: DirtyOpts { allow_dirty },
+		} => {
+			if !allow_dirty {
+				git::ensure_clean(&manifests(&root)?)?;
+			}
+			rust_version::set_rust_version(&root, &version)?;
+		}
+		Command::Unpin {
+			precision,
+			style,
+			dirty: DirtyOpts { allow_dirty },
+		} => {
+			if !allow_dirty {
+				git::ensure_clean(&manifests(&root)?)?;
+			}
+			normalize_versions::unpin(&root, precision, style)?;
+		}
+		Command::NormalizeVersions {
+			style,
+			strip_exact,
+			dirty: DirtyOpts { allow_dirty },
+		} => {
+			if !allow_dirty {
+				git::ensure_clean(&manifests(&root)?)?;
 			}
+			normalize_versions::normalize_versions(&root, style, strip_exact)?;
+		}
+		Command::Query { input, std } => {
+			let predicate =
+				<NativeFn<((DirectInput,), bool)>>::from_untyped(load_rule(input, &std)?)?;
+			query::query(&root, &*predicate)?;
+		}
+		Command::Policy { rules, std } => {
+			let rule =
+				<NativeFn<((DirectInput,), Either![Null, String, Vec<String>])>>::from_untyped(
+					load_rule(
+						InputOpts {
+							exec: false,
+							input: rules,
+						},
+						&std,
+					)?,
+				)?;
+			failed = !policy::policy(&root, &|input| {
+				Ok(match rule(input)? {
+					Either3::A(Null) => vec![],
+					Either3::B(violation) => vec![violation],
+					Either3::C(violations) => violations,
+				})
+			})?;
 		}
-		Opts::Revert | Opts::Link { .. } => unreachable!("this is alias"),
-		Opts::Patch {
+		Command::Tree { filter, color } => tree::tree(&root, filter, style::Painter::new(color))?,
+		Command::Why { package } => why::why(&root, &package)?,
+		Command::Gc {
+			max_age_days,
+			dry_run,
+		} => state::gc(
+			&root,
+			Duration::from_secs(max_age_days * 24 * 60 * 60),
+			dry_run,
+		)?,
+		Command::Doctor => failed = !doctor::doctor(&root)?,
+		Command::VerifyClean => failed = !verify::verify_clean(&root)?,
+		Command::InstallHooks { hooks, force } => {
+			hooks::install_hooks(&root, &hooks, force)?;
+		}
+		Command::LockHashes => lock_hashes::lock_hashes(&root)?,
+		Command::NixOutputHashes { lockfile } => nix::output_hashes(&lockfile)?,
+		Command::Patch {
 			input,
 			force_inline,
+			git_commit,
+			dirty: DirtyOpts { allow_dirty },
+			follow_path_deps,
+			sync_members,
+			relative_paths,
+			canonicalize_paths,
+			report: report_format,
+			kinds,
+			targets,
+			interactive,
+			output_dir,
+			stdout: to_stdout,
+			stdin: from_stdin,
+			record_originals,
+			freeze,
+			incremental,
 			std,
 		} => {
-			let s = State::default();
-
-			s.set_context_initializer((
-				std.context_initializer(&s)?.expect("nostd is not working"),
-				DppContextInitializer,
-			));
-
-			let mutator = if input.exec {
-				s.evaluate_snippet("<cmdline>".to_string(), input.input)?
-			} else if input.input.as_str() == "-" {
-				let mut code = String::new();
-				stdin().read_to_string(&mut code).run_err()?;
-				s.evaluate_snippet("<stdin>".to_string(), code)?
+			if (from_stdin || interactive) && input.reads_stdin() {
+				bail!("rules can't be read from stdin, when it is used for other input");
+			}
+			if crate::discovery().files_from.as_deref() == Some(Path::new("-"))
+				&& (from_stdin || interactive || input.reads_stdin())
+			{
+				bail!("manifest list can't be read from stdin, when it is used for other input");
+			}
+			if incremental && input.reads_stdin() {
+				bail!(
+					"rules read from stdin can't be fingerprinted, --incremental is not supported"
+				);
+			}
+			let rule = input.describe();
+			let mut fingerprints = incremental
+				.then(|| fingerprint::Fingerprints::load(&root, &input.hash()))
+				.transpose()?;
+			let review = interactive.then(review::Review::new);
+			let mutator = load_mutators(input, &std)?;
+
+			if from_stdin {
+				let mut toml = String::new();
+				stdin().read_to_string(&mut toml).run_err()?;
+				let manifest = std::env::current_dir().run_err()?.join("Cargo.toml");
+				let _manifest = natives::CurrentManifest::enter_stdin(&manifest);
+				print!(
+					"{}",
+					patch_str(
+						&toml,
+						&|key, input| {
+							if (kinds.is_empty() || kinds.contains(&DepKind::of(key)))
+								&& target_matches(&targets, key)
+							{
+								mutator.apply(input)
+							} else {
+								Ok(Either2::A(Null))
+							}
+						},
+						force_inline,
+						record_originals,
+					)?
+				);
+				return Ok(ExitCode::SUCCESS);
+			}
+
+			let mut manifests = manifests(&root)?;
+			if follow_path_deps {
+				manifests = with_path_deps(&root, manifests)?;
+			}
+			// Checkpoint commit separates user changes from ours anyway
+			if !allow_dirty && !git_commit && output_dir.is_none() && !to_stdout {
+				git::ensure_clean(&manifests)?;
+			}
+			if git_commit {
+				git::checkpoint(
+					&root,
+					&manifests,
+					&format!("deppatcher: checkpoint before patch\n\nRule: {rule}"),
+				)?;
+			}
+			let workspace_roots = if sync_members {
+				workspace_roots(&manifests)?
 			} else {
-				s.import(PathBuf::from(input.input))?
+				Vec::new()
+			};
+			let mut workspace_deps = HashMap::<PathBuf, workspace_deps::WorkspaceDeps>::new();
+			// Members are synchronized with the state of the workspace entries after this run
+			manifests.sort_by_key(|m| {
+				!workspace_roots
+					.iter()
+					.any(|r| Some(r.as_path()) == m.parent())
+			});
+			let report = report::Report::default();
+			let repository = match git::toplevel(&root)? {
+				Some(toplevel) => toplevel,
+				None => root.canonicalize().run_err()?,
 			};
-			let mutator =
-				<NativeFn<((DirectInput,), Either![Null, DirectSource])>>::from_untyped(mutator)?;
-
-			for entry in walkdir::WalkDir::new(current_dir().run_err()?) {
-				let entry = entry.run_err()?;
-				if entry.file_type().is_file() && entry.path().ends_with("Cargo.toml") {
-					info!("patching {}", entry.path().display());
-					patch(entry.path(), &*mutator, force_inline)?;
+			for manifest in &manifests {
+				if let Some(fingerprints) = &fingerprints {
+					if fingerprints.is_fresh(manifest)? {
+						debug!("{} is unchanged since the last run", manifest.display());
+						continue;
+					}
+				}
+				info!(target: PROGRESS, "patching {}", manifest.display());
+				let mirrored = match &output_dir {
+					Some(dir) => dir.join(manifest.strip_prefix(&root).run_err()?),
+					None => manifest.clone(),
+				};
+				let output = if to_stdout {
+					Output::Stdout
+				} else {
+					Output::File(&mirrored)
+				};
+				patch_into(
+					manifest,
+					&output,
+					&|key, input| {
+						if !(kinds.is_empty() || kinds.contains(&DepKind::of(key)))
+							|| !target_matches(&targets, key)
+						{
+							return Ok(Either2::A(Null));
+						}
+						let mut result = mutator.apply(input.clone())?;
+						let deps = owning_workspace(manifest, &workspace_roots)
+							.and_then(|w| workspace_deps.get(w));
+						if let (Either2::A(_), Some(deps)) = (&result, deps) {
+							if let Some(synced) = deps.sync(&input) {
+								result = Either2::B(synced);
+							}
+						}
+						if canonicalize_paths {
+							if let Either2::B(source) = &mut result {
+								canonicalize_path(manifest, source)?;
+							}
+						}
+						if relative_paths {
+							result = relativize_result(manifest, &repository, &input, result)?;
+						}
+						let relative = manifest.strip_prefix(&root).unwrap_or(manifest);
+						if let Some(review) = &review {
+							result = review.review(relative, key, &input, result)?;
+						}
+						if report_format.is_some() {
+							report.record(relative, key, &input, &result);
+						}
+						Ok(result)
+					},
+					force_inline,
+					freeze,
+				)?;
+				if let Some(fingerprints) = &mut fingerprints {
+					fingerprints.update(manifest)?;
 				}
+				let dir = manifest.parent().expect("manifest is a file");
+				if workspace_roots.iter().any(|r| r == dir) {
+					workspace_deps.insert(
+						dir.to_owned(),
+						workspace_deps::WorkspaceDeps::load(manifest)?,
+					);
+				}
+			}
+			if let Some(fingerprints) = &mut fingerprints {
+				fingerprints.save()?;
+			}
+			if let Some(format) = report_format {
+				print!("{}", report.render(format));
+			}
+			if git_commit {
+				git::checkpoint(
+					&root,
+					&manifests,
+					&format!("deppatcher: patch\n\nRule: {rule}"),
+				)?;
 			}
 		}
-		Opts::SoftPatch { input, std } => {
-			let s = State::default();
-
-			s.set_context_initializer((
-				std.context_initializer(&s)?.expect("nostd is not working"),
-				DppContextInitializer,
-			));
+		Command::Serve { std } => serve::serve(&root, &std)?,
+		Command::Watch {
+			force_inline,
+			incremental,
+			input,
+			std,
+		} => {
+			if incremental && input.reads_stdin() {
+				bail!(
+					"rules read from stdin can't be fingerprinted, --incremental is not supported"
+				);
+			}
+			let fingerprints = incremental
+				.then(|| fingerprint::Fingerprints::load(&root, &input.hash()))
+				.transpose()?
+				.map(RefCell::new);
+			let mutator = load_mutators(input, &std)?;
+			watch::watch(&root, &|manifest| {
+				// Manual runs should not fail because of the watcher, so it only holds lock while patching
+				let _lock = lock::acquire(&root, true)?;
+				// Also skips reapplying rules after our own writes
+				if let Some(fingerprints) = &fingerprints {
+					if fingerprints.borrow().is_fresh(manifest)? {
+						debug!("{} is unchanged since the last run", manifest.display());
+						return Ok(());
+					}
+				}
+				patch(manifest, &|_, input| mutator.apply(input), force_inline)?;
+				if let Some(fingerprints) = &fingerprints {
+					let mut fingerprints = fingerprints.borrow_mut();
+					fingerprints.update(manifest)?;
+					fingerprints.save()?;
+				}
+				Ok(())
+			})?;
+		}
+		Command::SoftPatch {
+			input,
+			std,
+			format,
+			direct_only,
+			depth,
+			manifest_path,
+			baseline,
+			registry_url,
+		} => {
+			let max_depth = if direct_only { Some(1) } else { depth };
+			let rule_hash = input.hash();
+			let mutator = load_mutators(input, &std)?;
 
-			let mutator = if input.exec {
-				s.evaluate_snippet("<cmdline>".to_string(), input.input)?
-			} else if input.input.as_str() == "-" {
-				let mut code = String::new();
-				stdin().read_to_string(&mut code).run_err()?;
-				s.evaluate_snippet("<stdin>".to_string(), code)?
+			let graph = if let Some(manifest) = &manifest_path {
+				graph::load_manifest(manifest)?
 			} else {
-				s.import(PathBuf::from(input.input))?
+				graph::load(&root)?
 			};
-			let mutator =
-				<NativeFn<((DirectInput,), Either![Null, DirectSource])>>::from_untyped(mutator)?;
-
-			let guppy = guppy::MetadataCommand::new().exec().run_err()?;
-			let graph = guppy.build_graph().run_err()?;
 
+			let registries = registries::Registries::load(
+				graph.workspace().root().as_std_path(),
+				&registry_url,
+			)?;
+			let baseline = baseline
+				.as_deref()
+				.map(graph::Baseline::load)
+				.transpose()?
+				.map(|b| b.map_sources(|s| registries.named(s)));
 			let mut output = <BTreeMap<DirectInput, DirectSource>>::new();
 
 			let mut visited = HashSet::new();
@@ -647,7 +3649,9 @@ fn main() -> Result<()> {
 				.root_packages(DependencyDirection::Forward)
 				.map(|p| p.id())
 				.collect::<Vec<_>>();
-			while !to_visit.is_empty() {
+			let mut level = 0;
+			while !to_visit.is_empty() && max_depth.is_none_or(|max| level < max) {
+				level += 1;
 				for package in std::mem::take(&mut to_visit) {
 					// Somehow, this graph is cyclic
 					if !visited.insert(package) {
@@ -658,58 +3662,37 @@ fn main() -> Result<()> {
 						.packages()
 						.find(|i| i.id() == package)
 						.expect("bad graph");
+					let _manifest =
+						natives::CurrentManifest::enter(pkg.manifest_path().as_std_path());
 					for ele in pkg.direct_links() {
 						if !ele.normal().is_present() && !ele.build().is_present() {
 							continue;
 						}
 						let to = ele.to();
-						let source = ele.to().source();
-						let es = source.parse_external();
-						let git = match source.parse_external() {
-							Some(ExternalSource::Git {
-								repository,
-								req,
-								resolved,
-							}) => Some((repository.to_string(), req, resolved)),
-							_ => None,
-						};
-						let ds = DirectSource {
-							version: Some(to.version().to_string()),
-							registry: match es {
-								Some(ExternalSource::Registry(r)) => Some(r.to_string()),
-								_ => None,
-							},
-							path: source.local_path().map(ToString::to_string),
-							git: git.as_ref().map(|(r, _, _)| r.to_string()),
-							rev: git.as_ref().and_then(|(_, e, _)| match e {
-								GitReq::Rev(e) => Some((*e).to_string()),
-								_ => None,
-							}),
-							tag: git.as_ref().and_then(|(_, e, _)| match e {
-								GitReq::Tag(t) => Some((*t).to_string()),
-								_ => None,
-							}),
-							branch: git.as_ref().and_then(|(_, e, _)| match e {
-								GitReq::Branch(b) => Some((*b).to_string()),
-								_ => None,
-							}),
-							workspace: None,
-						};
+						let ds = registries.named(graph::source_of(to));
 
 						let input = DirectInput {
 							package: to.name().to_string(),
 							name: to.name().to_string(),
-							// Not supported
-							original_source: ds.clone(),
+							original_source: baseline.as_ref().map_or_else(
+								|| ds.clone(),
+								|b| registries.named(b.original(to.name(), &ds)),
+							),
 							source: ds.clone(),
 						};
 						if output.contains_key(&input) {
 							continue;
 						}
 
-						match (*mutator)(input.clone())? {
+						match mutator.apply(input.clone())? {
 							Either2::A(_) => {}
 							Either2::B(r) => {
+								if r.is_remove() {
+									bail!("removing dependencies is not supported by soft-patch");
+								}
+								if r.name.is_some() || r.package.is_some() {
+									bail!("renaming dependencies is not supported by soft-patch");
+								}
 								if r != ds {
 									output.insert(input.clone(), r);
 								}
@@ -720,47 +3703,50 @@ fn main() -> Result<()> {
 				}
 			}
 
-			let mut table = Document::new();
-			table.insert_formatted(&toml_edit::Key::new("patch"), Item::Table(Table::new()));
-			let patch_table = table
-				.get_mut("patch")
-				.expect("just inserted")
-				.as_table_mut()
-				.expect("table like");
-			patch_table.set_implicit(true);
-
+			let mut patches = <BTreeMap<String, BTreeMap<String, DirectSource>>>::new();
 			for (k, v) in output {
-				let source = if let Some(reg) = &k.source.registry {
-					if reg == "https://github.com/rust-lang/crates.io-index" {
-						"crates-io".to_string()
-					} else {
-						bail!("no support for custom registries")
-					}
-				} else if let Some(git) = &k.source.git {
-					git.to_string()
+				let source = if let Some(git) = &k.source.git {
+					git.clone()
 				} else if k.source.path.is_some() {
 					eprintln!("path exists {:?}", k.source);
 					continue;
+				} else if k.source.version.is_some() {
+					registries.patch_key(k.source.registry.as_deref())?
 				} else {
 					bail!("unsupported source: {:?}", k.source)
 				};
-				let source_table = patch_table
-					.entry(&source)
-					.or_insert(Item::Table(Table::new()))
-					.as_table_mut()
-					.expect("table like");
-				source_table.set_implicit(false);
-				let item_table = source_table
-					.entry(&k.name)
-					.or_insert(Item::Value(Value::InlineTable(InlineTable::new())))
-					.as_table_like_mut()
-					.expect("table like");
-				v.write(item_table);
+				patches.entry(source).or_default().insert(k.name, v);
 			}
 
-			println!("{table}");
+			match format {
+				PatchFormat::Toml => println!("{}", patch_document(&patches, &rule_hash)),
+				PatchFormat::Json => {
+					println!("{}", serde_json::to_string_pretty(&patches).run_err()?);
+				}
+				PatchFormat::Nix => {
+					println!(
+						"{}",
+						nix::to_nix(&serde_json::to_value(&patches).run_err()?)
+					);
+				}
+			}
+
+			for unused in
+				doctor::unused_patches(graph.workspace().root().as_std_path(), Some(&graph))?
+			{
+				eprintln!(
+					"warning: cargo ignores existing patch for {} {}: {}",
+					unused.name, unused.version, unused.reason
+				);
+			}
 		}
 	}
 
-	Ok(())
+	if failed {
+		return Ok(ExitCode::FAILURE);
+	}
+	if CHANGED.load(Ordering::Relaxed) && !no_changes_ok {
+		return Ok(ExitCode::from(CHANGED_EXIT_CODE));
+	}
+	Ok(ExitCode::SUCCESS)
 }
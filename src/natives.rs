@@ -0,0 +1,134 @@
+//! Miscellaneous helpers exposed to rules under `dpp`
+
+use std::{
+	any::Any,
+	cell::{Cell, RefCell},
+	collections::HashMap,
+	path::{Path, PathBuf},
+	rc::Rc,
+	sync::OnceLock,
+};
+
+use ::glob::Pattern;
+use jrsonnet_evaluator::{
+	bail,
+	function::{builtin, CallLocation},
+	Result,
+};
+
+use crate::{caller_dir, paths, ToRuntime};
+
+/// Root discovered by main, honoring `--no-ascend`
+static WORKSPACE_ROOT: OnceLock<PathBuf> = OnceLock::new();
+
+/// Called once the root is discovered, before any rule is evaluated
+pub fn init_workspace_root(root: &Path) {
+	assert!(
+		WORKSPACE_ROOT.set(root.to_owned()).is_ok(),
+		"workspace root is initialized once"
+	);
+}
+
+thread_local! {
+	/// Manifest, dependencies of which are currently passed to the rule
+	static CURRENT_MANIFEST: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+	/// Whether the current manifest is read from stdin, and its path is only used to resolve relative paths
+	static FROM_STDIN: Cell<bool> = const { Cell::new(false) };
+	/// Results of the helpers, which run cargo or read lockfiles, see [`cached`]
+	static CACHE: RefCell<HashMap<String, Rc<dyn Any>>> = RefCell::default();
+}
+
+/// Result of `load`, computed once per run for every `key`
+///
+/// Rules tend to call helpers from the rule function, so they would otherwise be rerun for every dependency.
+/// Key should contain helper name, resolved path, and every option affecting the result
+pub fn cached<T: 'static>(key: String, load: impl FnOnce() -> Result<T>) -> Result<Rc<T>> {
+	if let Some(value) = CACHE.with_borrow(|c| c.get(&key).cloned()) {
+		return Ok(value.downcast().expect("key includes helper name"));
+	}
+	let value = Rc::new(load()?);
+	CACHE.with_borrow_mut(|c| c.insert(key, value.clone()));
+	Ok(value)
+}
+
+/// Forgets results of [`cached`], for long-running commands to see changes made since they were computed
+pub fn clear_cache() {
+	CACHE.with_borrow_mut(HashMap::clear);
+}
+
+/// Manifest, which is currently processed, if any
+pub fn current_manifest() -> Option<PathBuf> {
+	CURRENT_MANIFEST.with_borrow(Clone::clone)
+}
+
+/// Current manifest as shown in logs, `<stdin>` if it is read from stdin
+pub fn current_manifest_display() -> Option<String> {
+	if FROM_STDIN.get() {
+		return Some("<stdin>".to_owned());
+	}
+	current_manifest().map(|m| m.display().to_string())
+}
+
+/// Guard, which marks manifest as currently processed until dropped
+pub struct CurrentManifest(Option<PathBuf>, bool);
+impl CurrentManifest {
+	pub fn enter(path: &Path) -> Self {
+		Self::enter_with(path, false)
+	}
+	/// Manifest read from stdin, `path` is where it would be located
+	pub fn enter_stdin(path: &Path) -> Self {
+		Self::enter_with(path, true)
+	}
+	fn enter_with(path: &Path, from_stdin: bool) -> Self {
+		Self(
+			CURRENT_MANIFEST.with_borrow_mut(|m| m.replace(path.to_owned())),
+			FROM_STDIN.replace(from_stdin),
+		)
+	}
+}
+impl Drop for CurrentManifest {
+	fn drop(&mut self) {
+		let previous = self.0.take();
+		CURRENT_MANIFEST.with_borrow_mut(|m| *m = previous);
+		FROM_STDIN.set(self.1);
+	}
+}
+
+/// Path, as it is passed to rules, see [`paths::normalize`]
+pub fn path_to_string(path: &Path) -> Result<String> {
+	Ok(paths::normalize(
+		path.to_str()
+			.ok_or_else(|| format!("non-utf8 path: {}", path.display()))
+			.run_err()?,
+	))
+}
+
+#[builtin]
+pub fn glob(pattern: String, value: String) -> Result<bool> {
+	Ok(Pattern::new(&pattern).run_err()?.matches(&value))
+}
+
+/// Root of the workspace deppatcher is invoked in, see [`init_workspace_root`]
+#[builtin]
+pub fn workspace_root() -> Result<String> {
+	path_to_string(
+		WORKSPACE_ROOT
+			.get()
+			.expect("workspace root is initialized before evaluating rules"),
+	)
+}
+
+/// Directory of the manifest, which is currently being patched
+#[builtin]
+pub fn manifest_dir() -> Result<String> {
+	let Some(manifest) = current_manifest() else {
+		bail!("no manifest is being processed, manifestDir should only be called from the rule function")
+	};
+	path_to_string(manifest.parent().expect("manifest is a file"))
+}
+
+/// Directory, relative to which rule paths are resolved
+#[builtin]
+pub fn rule_dir(loc: CallLocation) -> Result<String> {
+	path_to_string(&caller_dir(loc)?)
+}
@@ -0,0 +1,289 @@
+//! Integration with Nix: nixpkgs' `rustPlatform.importCargoLock`, and flake inputs
+
+use std::{collections::BTreeMap, fs, path::Path, process::Command};
+
+use cargo_lock::Lockfile;
+use jrsonnet_evaluator::{
+	bail,
+	function::{builtin, CallLocation},
+	Result, Val,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use tracing::info;
+
+use crate::{caller_dir, jobs, ToRuntime};
+
+#[derive(Deserialize)]
+struct Prefetched {
+	hash: Option<String>,
+}
+
+fn escape_string(s: &str) -> String {
+	let mut out = String::with_capacity(s.len() + 2);
+	out.push('"');
+	let mut chars = s.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' => out.push_str("\\\""),
+			'\\' => out.push_str("\\\\"),
+			'\n' => out.push_str("\\n"),
+			'\t' => out.push_str("\\t"),
+			'\r' => out.push_str("\\r"),
+			'$' if chars.peek() == Some(&'{') => out.push_str("\\$"),
+			c => out.push(c),
+		}
+	}
+	out.push('"');
+	out
+}
+
+fn write_nix(out: &mut String, value: &Json, indent: usize) {
+	match value {
+		Json::Null => out.push_str("null"),
+		Json::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+		Json::Number(n) => out.push_str(&n.to_string()),
+		Json::String(s) => out.push_str(&escape_string(s)),
+		Json::Array(items) => {
+			out.push('[');
+			for item in items {
+				out.push(' ');
+				write_nix(out, item, indent);
+			}
+			out.push_str(" ]");
+		}
+		Json::Object(fields) => {
+			out.push_str("{\n");
+			for (k, v) in fields {
+				out.push_str(&"  ".repeat(indent + 1));
+				out.push_str(&escape_string(k));
+				out.push_str(" = ");
+				write_nix(out, v, indent + 1);
+				out.push_str(";\n");
+			}
+			out.push_str(&"  ".repeat(indent));
+			out.push('}');
+		}
+	}
+}
+
+/// Nix expression, equivalent to specified json value
+pub fn to_nix(value: &Json) -> String {
+	let mut out = String::new();
+	write_nix(&mut out, value, 0);
+	out
+}
+
+/// SRI hash of git checkout, as computed by `fetchgit`, which is used by `importCargoLock`
+fn prefetch_git(url: &str, rev: &str) -> Result<String> {
+	info!("prefetching {url} at {rev}");
+	let output = Command::new("nix-prefetch-git")
+		.args(["--quiet", "--fetch-submodules", "--url", url, "--rev", rev])
+		.output()
+		.map_err(|e| format!("failed to run nix-prefetch-git: {e}"))
+		.run_err()?;
+	if !output.status.success() {
+		bail!(
+			"nix-prefetch-git failed for {url}: {}",
+			String::from_utf8_lossy(&output.stderr)
+		)
+	}
+	let prefetched: Prefetched = serde_json::from_slice(&output.stdout).run_err()?;
+	let Some(hash) = prefetched.hash else {
+		bail!("nix-prefetch-git is too old, SRI hash is not reported")
+	};
+	Ok(hash)
+}
+
+/// Print `cargoLock.outputHashes` attrset for every git dependency in the lockfile
+pub fn output_hashes(lockfile: &Path) -> Result<()> {
+	let lockfile = Lockfile::load(lockfile).run_err()?;
+
+	// Packages from the same repository share checkout
+	let mut checkouts = <BTreeMap<(String, String), Vec<String>>>::new();
+	for package in &lockfile.packages {
+		let Some(source) = package.source.as_ref().filter(|s| s.is_git()) else {
+			continue;
+		};
+		let Some(rev) = source.precise() else {
+			bail!("git source of {} is not locked to a revision", package.name)
+		};
+		let mut url = source.url().clone();
+		url.set_query(None);
+		url.set_fragment(None);
+		checkouts
+			.entry((url.to_string(), rev.to_owned()))
+			.or_default()
+			.push(format!("{}-{}", package.name, package.version));
+	}
+	let checkouts = checkouts.into_iter().collect::<Vec<_>>();
+	let hashes = jobs::map(&checkouts, |((url, rev), _)| prefetch_git(url, rev))?;
+	let mut out = BTreeMap::new();
+	for ((_, packages), hash) in checkouts.into_iter().zip(hashes) {
+		for package in packages {
+			out.insert(package, hash.clone());
+		}
+	}
+
+	println!("{{");
+	for (name, hash) in out {
+		println!("  \"{name}\" = \"{hash}\";");
+	}
+	println!("}}");
+	Ok(())
+}
+
+#[derive(Deserialize)]
+struct FlakeLock {
+	nodes: BTreeMap<String, FlakeNode>,
+	root: String,
+}
+#[derive(Deserialize)]
+struct FlakeNode {
+	#[serde(default)]
+	inputs: BTreeMap<String, InputRef>,
+	locked: Option<BTreeMap<String, Json>>,
+}
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum InputRef {
+	Node(String),
+	/// `follows` path, starting from the root node
+	Follows(Vec<String>),
+}
+
+#[derive(Serialize)]
+struct FlakeInput {
+	#[serde(rename = "type")]
+	kind: Option<String>,
+	/// Url, which can be used as cargo git source
+	url: Option<String>,
+	rev: Option<String>,
+	#[serde(rename = "narHash")]
+	nar_hash: Option<String>,
+	/// Store path, only filled when requested
+	path: Option<String>,
+}
+
+impl FlakeLock {
+	fn resolve<'s>(&'s self, input: &'s InputRef) -> Result<&'s str> {
+		match input {
+			InputRef::Node(n) => Ok(n),
+			InputRef::Follows(path) => {
+				let mut current = self.root.as_str();
+				for segment in path {
+					let Some(next) = self.nodes.get(current).and_then(|n| n.inputs.get(segment))
+					else {
+						bail!("broken follows path: {}", path.join("/"))
+					};
+					current = self.resolve(next)?;
+				}
+				Ok(current)
+			}
+		}
+	}
+}
+
+fn locked_url(locked: &BTreeMap<String, Json>) -> Option<String> {
+	let get = |k: &str| locked.get(k).and_then(Json::as_str);
+	match get("type")? {
+		"github" => Some(format!(
+			"https://github.com/{}/{}",
+			get("owner")?,
+			get("repo")?
+		)),
+		"gitlab" => Some(format!(
+			"https://{}/{}/{}",
+			get("host").unwrap_or("gitlab.com"),
+			get("owner")?,
+			get("repo")?
+		)),
+		"sourcehut" => Some(format!(
+			"https://{}/{}/{}",
+			get("host").unwrap_or("git.sr.ht"),
+			get("owner")?,
+			get("repo")?
+		)),
+		"git" => get("url").map(ToOwned::to_owned),
+		_ => None,
+	}
+}
+
+/// Store paths of the root flake inputs, obtained without building anything
+fn store_paths(flake: &Path) -> Result<BTreeMap<String, String>> {
+	#[derive(Deserialize)]
+	struct Archived {
+		#[serde(default)]
+		inputs: BTreeMap<String, ArchivedInput>,
+	}
+	#[derive(Deserialize)]
+	struct ArchivedInput {
+		path: String,
+	}
+	let output = Command::new("nix")
+		.args(["flake", "archive", "--json", "--dry-run"])
+		.arg(flake)
+		.output()
+		.map_err(|e| format!("failed to run nix: {e}"))
+		.run_err()?;
+	if !output.status.success() {
+		bail!(
+			"nix flake archive failed: {}",
+			String::from_utf8_lossy(&output.stderr)
+		)
+	}
+	let archived: Archived = serde_json::from_slice(&output.stdout).run_err()?;
+	Ok(archived
+		.inputs
+		.into_iter()
+		.map(|(k, v)| (k, v.path))
+		.collect())
+}
+
+/// Inputs of the flake, keyed by input name
+// Parameter names are visible to jsonnet, where named arguments are camelCase
+#[allow(non_snake_case)]
+#[builtin]
+pub fn load_flake_inputs(
+	loc: CallLocation,
+	flake: Option<String>,
+	storePaths: Option<bool>,
+) -> Result<Val> {
+	let mut dir = caller_dir(loc)?;
+	if let Some(flake) = flake {
+		dir.push(flake);
+	}
+	let lock: FlakeLock =
+		serde_json::from_str(&fs::read_to_string(dir.join("flake.lock")).run_err()?).run_err()?;
+	let mut paths = if storePaths.unwrap_or(false) {
+		store_paths(&dir)?
+	} else {
+		BTreeMap::new()
+	};
+
+	let Some(root) = lock.nodes.get(&lock.root) else {
+		bail!("flake.lock has no root node")
+	};
+	let mut out = BTreeMap::new();
+	for (name, input) in &root.inputs {
+		let node = lock.resolve(input)?;
+		let locked = lock.nodes.get(node).and_then(|n| n.locked.as_ref());
+		let get = |k: &str| {
+			locked
+				.and_then(|l| l.get(k))
+				.and_then(Json::as_str)
+				.map(ToOwned::to_owned)
+		};
+		out.insert(
+			name.clone(),
+			FlakeInput {
+				kind: get("type"),
+				url: locked.and_then(locked_url),
+				rev: get("rev"),
+				nar_hash: get("narHash"),
+				path: paths.remove(name),
+			},
+		);
+	}
+	Val::from_serde(out).run_err()
+}
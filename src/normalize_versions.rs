@@ -0,0 +1,56 @@
+//! `normalize-versions` and `unpin` commands: rewrite operators of version requirements across the tree
+
+use std::path::Path;
+
+use jrsonnet_evaluator::{
+	typed::{Either2, Null},
+	Either, Result,
+};
+use tracing::{info, warn};
+
+use crate::{
+	manifests, patch,
+	versions::{normalize_req, unpin_req, Precision, ReqStyle},
+	DirectInput, DirectSource, Key, PROGRESS,
+};
+
+/// Rewrites requirement of every dependency with `f`
+fn rewrite_versions(root: &Path, f: impl Fn(&str) -> Result<String>) -> Result<()> {
+	let mutator = |_: &Key, input: DirectInput| -> Result<Either![Null, DirectSource]> {
+		let source = &input.source;
+		let Some(current) = &source.version else {
+			return Ok(Either2::A(Null));
+		};
+		let version = match f(current) {
+			Ok(version) => version,
+			Err(e) => {
+				warn!(
+					"skipping {}: invalid requirement {current}: {e}",
+					input.name
+				);
+				return Ok(Either2::A(Null));
+			}
+		};
+		if *current == version {
+			return Ok(Either2::A(Null));
+		}
+		Ok(Either2::B(DirectSource {
+			version: Some(version),
+			..source.clone()
+		}))
+	};
+	for manifest in manifests(root)? {
+		info!(target: PROGRESS, "patching {}", manifest.display());
+		patch(&manifest, &mutator, false)?;
+	}
+	Ok(())
+}
+
+pub fn normalize_versions(root: &Path, style: Option<ReqStyle>, strip_exact: bool) -> Result<()> {
+	rewrite_versions(root, |req| normalize_req(req, style, strip_exact))
+}
+
+/// `unpin` command: relax `=` pins into caret requirements
+pub fn unpin(root: &Path, precision: Precision, style: Option<ReqStyle>) -> Result<()> {
+	rewrite_versions(root, |req| unpin_req(req, precision, style))
+}
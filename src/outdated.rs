@@ -0,0 +1,170 @@
+//! `outdated` command: compare version requirements against releases in crates.io index
+
+use std::{
+	collections::{BTreeMap, BTreeSet},
+	path::Path,
+};
+
+use clap::ValueEnum;
+use jrsonnet_evaluator::Result;
+use semver::{Version, VersionReq};
+use serde::Serialize;
+use tracing::warn;
+
+use crate::{dependencies, index, jobs, manifests, ToRuntime};
+
+#[derive(ValueEnum, Clone, Copy, Default)]
+pub enum OutdatedFormat {
+	/// Human-readable report
+	#[default]
+	Text,
+	/// `{"package": {"requirement": {"compatible": ..., "latest": ...}}}` object
+	Json,
+	/// Rule, upgrading requirements according to `--policy`
+	Rule,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default)]
+pub enum UpgradePolicy {
+	/// Only raise requirements to the newest semver-compatible release
+	#[default]
+	Compatible,
+	/// Raise requirements to the newest release, even if it is incompatible
+	Latest,
+}
+
+#[derive(Serialize)]
+struct Status {
+	/// Newest release matching the requirement, if it is newer than the oldest matching one
+	#[serde(skip_serializing_if = "Option::is_none")]
+	compatible: Option<Version>,
+	/// Newest release, if it doesn't match the requirement
+	#[serde(skip_serializing_if = "Option::is_none")]
+	latest: Option<Version>,
+}
+
+/// Requirements of dependencies from crates.io, keyed by package name
+fn requirements(root: &Path) -> Result<BTreeMap<String, BTreeSet<String>>> {
+	let mut out = <BTreeMap<_, BTreeSet<_>>>::new();
+	for manifest in manifests(root)? {
+		for (_, input) in dependencies(&manifest)? {
+			let source = input.source;
+			if source.path.is_some()
+				|| source.git.is_some()
+				|| source.registry.is_some()
+				|| source.registry_index.is_some()
+				|| source.workspace == Some(true)
+			{
+				continue;
+			}
+			if let Some(version) = source.version {
+				out.entry(input.package).or_default().insert(version);
+			}
+		}
+	}
+	Ok(out)
+}
+
+fn status(name: &str, reqs: &BTreeSet<String>) -> Result<BTreeMap<String, Status>> {
+	let mut releases = index::versions(name)?
+		.into_iter()
+		.filter(|v| !v.yanked)
+		.map(|v| v.vers)
+		.collect::<Vec<_>>();
+	releases.sort();
+	let mut out = BTreeMap::new();
+	for req in reqs {
+		let parsed = match VersionReq::parse(req) {
+			Ok(parsed) => parsed,
+			Err(e) => {
+				warn!("{name}: invalid requirement {req:?}: {e}");
+				continue;
+			}
+		};
+		// Prereleases are only considered for requirements, which mention them
+		let stable = releases
+			.iter()
+			.filter(|v| v.pre.is_empty() || req.contains('-'))
+			.collect::<Vec<_>>();
+		let matching = stable
+			.iter()
+			.filter(|v| parsed.matches(v))
+			.collect::<Vec<_>>();
+		let compatible = match (matching.first(), matching.last()) {
+			(Some(oldest), Some(newest)) if oldest != newest => Some((**newest).clone()),
+			_ => None,
+		};
+		let latest = stable
+			.last()
+			.filter(|v| !parsed.matches(v))
+			.map(|v| (*v).clone());
+		if compatible.is_some() || latest.is_some() {
+			out.insert(req.clone(), Status { compatible, latest });
+		}
+	}
+	Ok(out)
+}
+
+pub fn outdated(root: &Path, format: OutdatedFormat, policy: UpgradePolicy) -> Result<()> {
+	let requirements = requirements(root)?.into_iter().collect::<Vec<_>>();
+	let statuses = jobs::map(&requirements, |(name, reqs)| status(name, reqs))?;
+	let mut report = BTreeMap::new();
+	for ((name, _), status) in requirements.into_iter().zip(statuses) {
+		if !status.is_empty() {
+			report.insert(name, status);
+		}
+	}
+	match format {
+		OutdatedFormat::Text => {
+			for (name, status) in &report {
+				for (req, Status { compatible, latest }) in status {
+					print!("{name} {req}:");
+					if let Some(compatible) = compatible {
+						print!(" compatible {compatible}");
+					}
+					if let Some(latest) = latest {
+						print!(" latest {latest}");
+					}
+					println!();
+				}
+			}
+		}
+		OutdatedFormat::Json => println!(
+			"{}",
+			serde_json::to_string_pretty(&report).expect("report is serializable")
+		),
+		OutdatedFormat::Rule => {
+			let mut upgrades = BTreeMap::<&str, BTreeMap<&str, String>>::new();
+			for (name, status) in &report {
+				for (req, Status { compatible, latest }) in status {
+					let to = match policy {
+						UpgradePolicy::Compatible => compatible.as_ref(),
+						UpgradePolicy::Latest => latest.as_ref().or(compatible.as_ref()),
+					};
+					if let Some(to) = to {
+						upgrades
+							.entry(name)
+							.or_default()
+							.insert(req, to.to_string());
+					}
+				}
+			}
+			println!(
+				"// Generated by `deppatcher outdated`, keyed by package and current requirement"
+			);
+			println!(
+				"local upgrades = {};",
+				serde_json::to_string_pretty(&upgrades).run_err()?
+			);
+			println!("function(pkg) if std.objectHas(upgrades, pkg.package)");
+			println!("\t&& std.objectHas(pkg.source, 'version')");
+			println!("\t&& std.objectHas(upgrades[pkg.package], pkg.source.version)");
+			println!("\t&& !std.objectHas(pkg.source, 'registry')");
+			println!(
+				"\t&& !std.objectHas(pkg.source, 'path') && !std.objectHas(pkg.source, 'git')"
+			);
+			println!("then pkg.source + {{ version: upgrades[pkg.package][pkg.source.version] }}");
+		}
+	}
+	Ok(())
+}
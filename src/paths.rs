@@ -0,0 +1,94 @@
+//! Platform-independent helpers for paths, which are written into manifests
+//!
+//! Paths are handled as strings, both `/` and `\` are accepted as separators, and returned paths
+//! always use `/`, which is understood by cargo on every platform
+
+use jrsonnet_evaluator::{bail, function::builtin, Result};
+
+/// Absolute path split into its root (`/` or drive, like `c:`) and normal components
+struct Parsed<'s> {
+	root: String,
+	components: Vec<&'s str>,
+}
+
+fn parse(path: &str) -> Option<Parsed<'_>> {
+	// Verbatim prefix is added by `canonicalize` on Windows
+	let path = path
+		.strip_prefix(r"\\?\")
+		.or_else(|| path.strip_prefix("//?/"))
+		.unwrap_or(path);
+	let (root, rest) = if let Some(rest) = path.strip_prefix(['/', '\\']) {
+		(String::new(), rest)
+	} else {
+		let mut chars = path.chars();
+		match (chars.next(), chars.next(), chars.next()) {
+			(Some(drive), Some(':'), Some('/' | '\\')) if drive.is_ascii_alphabetic() => {
+				(format!("{}:", drive.to_ascii_lowercase()), &path[3..])
+			}
+			_ => return None,
+		}
+	};
+	let mut components = Vec::new();
+	for c in rest.split(['/', '\\']) {
+		match c {
+			"" | "." => {}
+			".." => {
+				components.pop();
+			}
+			c => components.push(c),
+		}
+	}
+	Some(Parsed { root, components })
+}
+
+impl Parsed<'_> {
+	/// Windows paths are compared case-insensitively
+	fn component_eq(&self, a: &str, b: &str) -> bool {
+		if cfg!(windows) || !self.root.is_empty() {
+			a.eq_ignore_ascii_case(b)
+		} else {
+			a == b
+		}
+	}
+}
+
+/// Path in the form, which is understood by cargo on every platform: `/` separators, no verbatim prefix
+///
+/// UNC paths (`\\?\UNC\server\share`) are written as `//server/share`
+pub fn normalize(path: &str) -> String {
+	path.strip_prefix(r"\\?\UNC\")
+		.map_or_else(
+			|| path.strip_prefix(r"\\?\").unwrap_or(path).to_owned(),
+			|unc| format!(r"\\{unc}"),
+		)
+		.replace('\\', "/")
+}
+
+/// Relative path from directory `from` to `to`, both paths should be absolute
+pub fn relative(from: &str, to: &str) -> Result<String> {
+	let (Some(from_parsed), Some(to_parsed)) = (parse(from), parse(to)) else {
+		bail!("both paths should be absolute: {from:?}, {to:?}")
+	};
+	if !from_parsed.component_eq(&from_parsed.root, &to_parsed.root) {
+		bail!("paths are on different drives: {from:?}, {to:?}")
+	}
+	let common = from_parsed
+		.components
+		.iter()
+		.zip(&to_parsed.components)
+		.take_while(|(a, b)| from_parsed.component_eq(a, b))
+		.count();
+	let out = std::iter::repeat_n("..", from_parsed.components.len() - common)
+		.chain(to_parsed.components[common..].iter().copied())
+		.collect::<Vec<_>>();
+	Ok(if out.is_empty() {
+		".".to_owned()
+	} else {
+		out.join("/")
+	})
+}
+
+#[builtin]
+pub fn relpath(from: String, to: String) -> Result<String> {
+	relative(&from, &to)
+}
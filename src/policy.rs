@@ -0,0 +1,27 @@
+//! `policy` command: read-only enforcement of dependency source rules
+
+use std::path::Path;
+
+use jrsonnet_evaluator::Result;
+
+use crate::{dependencies, display_key, manifests, DirectInput};
+
+/// Returns `true` if no violations were found
+pub fn policy(root: &Path, rule: &dyn Fn(DirectInput) -> Result<Vec<String>>) -> Result<bool> {
+	let mut violations = 0;
+	for manifest in manifests(root)? {
+		let relative = manifest.strip_prefix(root).unwrap_or(&manifest).display();
+		for (key, input) in dependencies(&manifest)? {
+			for violation in rule(input)? {
+				violations += 1;
+				println!("{relative}: {}: {violation}", display_key(&key));
+			}
+		}
+	}
+	if violations == 0 {
+		println!("no policy violations found");
+	} else {
+		println!("found {violations} policy violations");
+	}
+	Ok(violations == 0)
+}
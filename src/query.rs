@@ -0,0 +1,42 @@
+//! `query` command: read-only evaluation of predicate against every declared dependency
+
+use std::path::Path;
+
+use jrsonnet_evaluator::Result;
+use serde::Serialize;
+
+use crate::{dependencies, display_key, manifests, DirectInput, ToRuntime};
+
+#[derive(Serialize)]
+pub struct Match {
+	manifest: String,
+	key: String,
+	#[serde(flatten)]
+	input: DirectInput,
+}
+
+pub fn query(root: &Path, predicate: &dyn Fn(DirectInput) -> Result<bool>) -> Result<()> {
+	let out = matching(root, predicate)?;
+	println!("{}", serde_json::to_string_pretty(&out).run_err()?);
+	Ok(())
+}
+
+/// Dependencies of every manifest, for which predicate returns true
+pub fn matching(
+	root: &Path,
+	predicate: &dyn Fn(DirectInput) -> Result<bool>,
+) -> Result<Vec<Match>> {
+	let mut out = Vec::new();
+	for manifest in manifests(root)? {
+		for (key, input) in dependencies(&manifest)? {
+			if predicate(input.clone())? {
+				out.push(Match {
+					manifest: manifest.display().to_string(),
+					key: display_key(&key),
+					input,
+				});
+			}
+		}
+	}
+	Ok(out)
+}
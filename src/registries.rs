@@ -0,0 +1,63 @@
+//! Names of the registries, as used in manifests, and their index urls, as used in the resolved graph
+
+use std::{collections::BTreeMap, path::Path};
+
+use jrsonnet_evaluator::{bail, Result};
+
+use crate::{cargo_config, graph, DirectSource};
+
+pub struct Registries {
+	/// Registry name to its index url
+	indexes: BTreeMap<String, String>,
+}
+impl Registries {
+	/// `[registries]` of the cargo config applying to `dir`, overridden by `name=url` pairs
+	pub fn load(dir: &Path, overrides: &[String]) -> Result<Self> {
+		let mut indexes = BTreeMap::new();
+		if let Some(registries) = cargo_config::load(dir)?
+			.get("registries")
+			.and_then(toml::Value::as_table)
+		{
+			for (name, registry) in registries {
+				if let Some(index) = registry.get("index").and_then(toml::Value::as_str) {
+					indexes.insert(name.clone(), index.to_owned());
+				}
+			}
+		}
+		for pair in overrides {
+			let Some((name, url)) = pair.split_once('=') else {
+				bail!("registry should be specified as <name>=<url>, got {pair}")
+			};
+			indexes.insert(name.to_owned(), url.to_owned());
+		}
+		Ok(Self { indexes })
+	}
+
+	/// Source with the registry index url replaced by the registry name, crates.io is the default registry
+	pub fn named(&self, mut source: DirectSource) -> DirectSource {
+		if let Some(url) = &source.registry {
+			if graph::is_crates_io(url) {
+				source.registry = None;
+			} else if let Some((name, _)) = self.indexes.iter().find(|(_, index)| {
+				graph::canonical_registry(index) == graph::canonical_registry(url)
+			}) {
+				source.registry = Some(name.clone());
+			}
+		}
+		source
+	}
+
+	/// `[patch]` key for the packages from the registry
+	pub fn patch_key(&self, registry: Option<&str>) -> Result<String> {
+		let Some(registry) = registry else {
+			return Ok("crates-io".to_owned());
+		};
+		if registry == "crates-io" || graph::is_crates_io(registry) {
+			return Ok("crates-io".to_owned());
+		}
+		let Some(index) = self.indexes.get(registry) else {
+			bail!("index url of registry {registry} is unknown, add it to [registries] of .cargo/config.toml, or pass --registry-url {registry}=<url>")
+		};
+		Ok(index.clone())
+	}
+}
@@ -0,0 +1,145 @@
+//! `--report` option of `patch`: summary of applied source changes, grouped by package
+
+use std::{
+	cell::RefCell,
+	collections::BTreeMap,
+	fmt::Write,
+	path::{Path, PathBuf},
+};
+
+use clap::ValueEnum;
+use jrsonnet_evaluator::typed::{Either2, Null};
+use jrsonnet_evaluator::Either;
+
+use crate::{display_key, DirectInput, DirectSource, Key};
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ReportFormat {
+	/// Markdown, with a table per package
+	Md,
+	/// Standalone HTML page
+	Html,
+}
+
+struct Change {
+	manifest: PathBuf,
+	dependency: String,
+	before: String,
+	/// `None` for removed dependencies
+	after: Option<String>,
+}
+
+#[derive(Default)]
+pub struct Report {
+	/// Keyed by package name
+	changes: RefCell<BTreeMap<String, Vec<Change>>>,
+}
+impl Report {
+	/// Records rule result, if it rewrites the dependency
+	pub fn record(
+		&self,
+		manifest: &Path,
+		key: &Key,
+		input: &DirectInput,
+		result: &Either![Null, DirectSource],
+	) {
+		let Either2::B(new) = result else {
+			return;
+		};
+		if !input.is_rewritten_by(new) {
+			return;
+		}
+		let new_name = new.name.as_ref().unwrap_or(&input.name);
+		let after = (!new.is_remove()).then(|| {
+			let source = DirectSource {
+				name: None,
+				package: None,
+				..new.clone()
+			};
+			format!("{new_name} = {}", source.to_table())
+		});
+		self.changes
+			.borrow_mut()
+			.entry(input.package.clone())
+			.or_default()
+			.push(Change {
+				manifest: manifest.to_owned(),
+				dependency: display_key(key),
+				before: format!("{} = {}", input.name, input.source.to_table()),
+				after,
+			});
+	}
+
+	pub fn render(&self, format: ReportFormat) -> String {
+		let changes = self.changes.borrow();
+		let mut out = String::new();
+		match format {
+			ReportFormat::Md => {
+				out.push_str("# Dependency changes\n");
+				if changes.is_empty() {
+					out.push_str("\nNo changes.\n");
+				}
+				for (package, changes) in changes.iter() {
+					let _ = write!(
+						out,
+						"\n## {package}\n\n| Manifest | Dependency | Before | After |\n|---|---|---|---|\n"
+					);
+					for change in changes {
+						let _ = writeln!(
+							out,
+							"| `{}` | `{}` | {} | {} |",
+							change.manifest.display(),
+							change.dependency,
+							md_code(&change.before),
+							change
+								.after
+								.as_deref()
+								.map_or_else(|| "*removed*".to_owned(), md_code),
+						);
+					}
+				}
+			}
+			ReportFormat::Html => {
+				out.push_str("<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Dependency changes</title></head>\n<body>\n<h1>Dependency changes</h1>\n");
+				if changes.is_empty() {
+					out.push_str("<p>No changes.</p>\n");
+				}
+				for (package, changes) in changes.iter() {
+					let _ = writeln!(
+						out,
+						"<h2>{}</h2>\n<table>\n<tr><th>Manifest</th><th>Dependency</th><th>Before</th><th>After</th></tr>",
+						html_escape(package)
+					);
+					for change in changes {
+						let _ = writeln!(
+							out,
+							"<tr><td><code>{}</code></td><td><code>{}</code></td><td><code>{}</code></td><td>{}</td></tr>",
+							html_escape(&change.manifest.display().to_string()),
+							html_escape(&change.dependency),
+							html_escape(&change.before),
+							change.after.as_deref().map_or_else(
+								|| "<em>removed</em>".to_owned(),
+								|a| format!("<code>{}</code>", html_escape(a))
+							),
+						);
+					}
+					out.push_str("</table>\n");
+				}
+				out.push_str("</body>\n</html>\n");
+			}
+		}
+		out
+	}
+}
+
+/// Inline code span, table cell separators are escaped
+fn md_code(text: &str) -> String {
+	format!("`{}`", text.replace('|', "\\|"))
+}
+
+fn html_escape(text: &str) -> String {
+	text.replace('&', "&amp;")
+		.replace('<', "&lt;")
+		.replace('>', "&gt;")
+		.replace('"', "&quot;")
+}
@@ -0,0 +1,120 @@
+//! `--interactive` mode of `patch`: every proposed rewrite is confirmed by the user
+
+use std::{
+	cell::Cell,
+	io::{stdin, stdout, Write},
+	path::Path,
+};
+
+use jrsonnet_evaluator::{
+	typed::{Either2, Null},
+	Either, Result,
+};
+
+use crate::{
+	display_key,
+	style::{self, Painter},
+	DirectInput, DirectSource, Key, ToRuntime,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mode {
+	Ask,
+	AcceptAll,
+	RejectAll,
+}
+
+pub struct Review {
+	mode: Cell<Mode>,
+	painter: Painter,
+}
+impl Review {
+	pub fn new() -> Self {
+		Self {
+			mode: Cell::new(Mode::Ask),
+			painter: Painter::new(style::ColorChoice::Auto),
+		}
+	}
+
+	/// Asks user, whether the rule result should be applied, returns `null` for rejected changes
+	pub fn review(
+		&self,
+		manifest: &Path,
+		key: &Key,
+		input: &DirectInput,
+		result: Either![Null, DirectSource],
+	) -> Result<Either![Null, DirectSource]> {
+		let Either2::B(new) = &result else {
+			return Ok(result);
+		};
+		if !input.is_rewritten_by(new) {
+			return Ok(result);
+		}
+		let new_name = new.name.as_ref().unwrap_or(&input.name);
+		match self.mode.get() {
+			Mode::AcceptAll => return Ok(result),
+			Mode::RejectAll => return Ok(Either2::A(Null)),
+			Mode::Ask => {}
+		}
+
+		println!(
+			"{}",
+			self.painter.paint(
+				style::NAME,
+				format!("{}: {}", manifest.display(), display_key(key))
+			)
+		);
+		println!(
+			"{}",
+			self.painter.paint(
+				style::REMOVED,
+				format!("- {} = {}", input.name, input.source.to_table())
+			)
+		);
+		let added = if new.is_remove() {
+			"+ (removed)".to_owned()
+		} else {
+			format!(
+				"+ {new_name} = {}",
+				DirectSource {
+					name: None,
+					package: None,
+					..new.clone()
+				}
+				.to_table()
+			)
+		};
+		println!("{}", self.painter.paint(style::ADDED, added));
+
+		loop {
+			print!("Apply this change [y,n,a,q,?]? ");
+			stdout().flush().run_err()?;
+			let mut answer = String::new();
+			// End of input is treated as `q`, there is nobody to ask anymore
+			let answer = if stdin().read_line(&mut answer).run_err()? == 0 {
+				println!();
+				"q"
+			} else {
+				answer.trim()
+			};
+			match answer {
+				"y" => return Ok(result),
+				"n" => return Ok(Either2::A(Null)),
+				"a" => {
+					self.mode.set(Mode::AcceptAll);
+					return Ok(result);
+				}
+				"q" => {
+					self.mode.set(Mode::RejectAll);
+					return Ok(Either2::A(Null));
+				}
+				_ => {
+					println!("y - apply this change");
+					println!("n - skip this change");
+					println!("a - apply this and all remaining changes");
+					println!("q - skip this and all remaining changes");
+				}
+			}
+		}
+	}
+}
@@ -0,0 +1,194 @@
+//! `set-rust-version` command: mass edit of `rust-version` of every member, revertible with `revert`
+//!
+//! Previous value is stored in originals under the same key as the field, i.e
+//! `originals.package.rust-version` and `originals.workspace.package.rust-version`
+
+use std::{fs, path::Path};
+
+use jrsonnet_evaluator::{bail, Result};
+use toml_edit::{Document, Item, Table, TableLike};
+use tracing::info;
+
+use crate::{
+	get_item, index, keep_text_format, manifests, mark_changed, max_position, originals_text,
+	place_originals, set_table, Key, ToRuntime,
+};
+
+/// Tables, which might declare `rust-version`
+const FIELD_TABLES: [&[&str]; 2] = [&["package"], &["workspace", "package"]];
+
+fn key(table: &[&str]) -> Key {
+	table
+		.iter()
+		.copied()
+		.chain(["rust-version"])
+		.map(ToOwned::to_owned)
+		.collect()
+}
+
+/// `rust-version` values stored in the originals table by `set-rust-version`, keyed by the field
+pub fn stored_originals(originals: &Item) -> Vec<(Key, String)> {
+	FIELD_TABLES
+		.iter()
+		.filter_map(|table| {
+			let key = key(table);
+			let original = get_item(originals, key.iter().map(String::as_str))?.as_str()?;
+			Some((key, original.to_owned()))
+		})
+		.collect()
+}
+
+/// Declared `rust-version`, `{ workspace = true }` is returned as is
+fn field<'d>(doc: &'d Table, key: &Key) -> Option<&'d Item> {
+	let (first, rest) = key.split_first().expect("key is not empty");
+	get_item(doc.get(first)?, rest.iter().map(String::as_str))
+}
+
+/// Replaces declared `rust-version`, keeping its comments
+fn replace_field(doc: &mut Table, key: &Key, version: &str) {
+	let (last, path) = key.split_last().expect("key is not empty");
+	let table = path
+		.iter()
+		.try_fold(doc as &mut dyn TableLike, |t, k| {
+			t.get_mut(k)?.as_table_like_mut()
+		})
+		.expect("field is declared");
+	let Some(Item::Value(value)) = table.get_mut(last) else {
+		unreachable!("field is declared as string")
+	};
+	let decor = value.decor().clone();
+	*value = version.into();
+	*value.decor_mut() = decor;
+}
+
+fn originals_key(doc: &Document) -> Key {
+	let metadata_root = if doc.contains_key("package") {
+		"package"
+	} else {
+		"workspace"
+	};
+	[metadata_root, "metadata", "deppatcher", "originals"]
+		.into_iter()
+		.map(ToOwned::to_owned)
+		.collect()
+}
+
+/// Applies `f` to the stored originals of the manifest, and writes it if anything changed
+fn edit_manifest(
+	manifest: &Path,
+	f: impl FnOnce(&mut Table, &mut Item) -> Result<()>,
+) -> Result<()> {
+	let toml = fs::read_to_string(manifest).run_err()?;
+	let mut doc: Document = toml.parse().run_err()?;
+	let originals_key = originals_key(&doc);
+	let mut originals = get_item(doc.as_item(), originals_key.iter().map(String::as_str))
+		.cloned()
+		.unwrap_or_else(|| {
+			let mut table = Table::new();
+			table.set_implicit(true);
+			Item::Table(table)
+		});
+	if !originals.is_table() {
+		bail!("originals should be table");
+	}
+	let stored = originals_text(&originals);
+	f(doc.as_table_mut(), &mut originals)?;
+	if originals_text(&originals) != stored {
+		place_originals(&mut originals, max_position(doc.as_table()) + 1);
+	}
+	set_table(doc.as_table_mut(), &originals_key, originals);
+	let patched = keep_text_format(&toml, &doc.to_string());
+	if patched != toml {
+		fs::write(manifest, patched).run_err()?;
+		mark_changed();
+	}
+	Ok(())
+}
+
+pub fn set_rust_version(root: &Path, version: &str) -> Result<()> {
+	if index::parse_rust_version(version).is_none() {
+		bail!("invalid rust version {version}, expected i.e 1.70 or 1.70.0");
+	}
+	let mut declared = false;
+	for manifest in manifests(root)? {
+		edit_manifest(&manifest, |doc, originals| {
+			for table in FIELD_TABLES {
+				let key = key(table);
+				let Some(current) = field(doc, &key) else {
+					continue;
+				};
+				declared = true;
+				// Inherited from `workspace.package`, which is updated instead
+				let Some(current) = current.as_str().map(ToOwned::to_owned) else {
+					continue;
+				};
+				if current == version {
+					continue;
+				}
+				info!(
+					"{}: {}: {current:?} → {version:?}",
+					manifest.display(),
+					key.join(".")
+				);
+				let original = get_item(originals, key.iter().map(String::as_str))
+					.and_then(Item::as_str)
+					.map(ToOwned::to_owned);
+				match original {
+					Some(original) if original == version => {
+						set_table(
+							originals.as_table_mut().expect("is table checked"),
+							&key,
+							Item::None,
+						);
+					}
+					Some(_) => {}
+					None => set_table(
+						originals.as_table_mut().expect("is table checked"),
+						&key,
+						toml_edit::value(current),
+					),
+				}
+				replace_field(doc, &key, version);
+			}
+			Ok(())
+		})?;
+	}
+	if !declared {
+		bail!("no manifest declares rust-version, nothing to update");
+	}
+	Ok(())
+}
+
+/// Restores `rust-version` values stored by `set-rust-version`
+pub fn revert(root: &Path) -> Result<()> {
+	for manifest in manifests(root)? {
+		edit_manifest(&manifest, |doc, originals| {
+			for table in FIELD_TABLES {
+				let key = key(table);
+				let Some(original) = get_item(originals, key.iter().map(String::as_str))
+					.and_then(Item::as_str)
+					.map(ToOwned::to_owned)
+				else {
+					continue;
+				};
+				info!(
+					"{}: {}: reverting to {original:?}",
+					manifest.display(),
+					key.join(".")
+				);
+				if field(doc, &key).is_some_and(Item::is_str) {
+					replace_field(doc, &key, &original);
+				} else {
+					set_table(doc, &key, toml_edit::value(original));
+				}
+				set_table(
+					originals.as_table_mut().expect("is table checked"),
+					&key,
+					Item::None,
+				);
+			}
+			Ok(())
+		})?;
+	}
+	Ok(())
+}
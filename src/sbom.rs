@@ -0,0 +1,229 @@
+//! `sbom` command: software bill of materials, listing resolved packages with their current sources
+
+use std::{
+	collections::BTreeMap,
+	fmt::Write as _,
+	path::Path,
+	time::{SystemTime, UNIX_EPOCH},
+};
+
+use clap::ValueEnum;
+use guppy::graph::PackageMetadata;
+use jrsonnet_evaluator::Result;
+use serde_json::{json, Value as Json};
+use sha2::{Digest, Sha256};
+
+use crate::{dependencies, graph, manifests, DirectSource, ToRuntime};
+
+#[derive(ValueEnum, Clone, Copy)]
+pub enum SbomFormat {
+	/// `CycloneDX` 1.5 JSON
+	Cyclonedx,
+	/// SPDX 2.3 JSON
+	Spdx,
+}
+
+const TOOL: &str = concat!("deppatcher-", env!("CARGO_PKG_VERSION"));
+
+/// Sources of the package, as seen by deppatcher
+struct Component<'g> {
+	pkg: PackageMetadata<'g>,
+	source: DirectSource,
+	/// Distinct originals stored for dependencies on this package, if requested
+	originals: Vec<DirectSource>,
+}
+impl Component<'_> {
+	const fn kind(&self) -> &'static str {
+		if self.source.path.is_some() {
+			"path"
+		} else if self.source.git.is_some() {
+			"git"
+		} else {
+			"registry"
+		}
+	}
+	fn purl(&self) -> String {
+		let mut purl = format!("pkg:cargo/{}@{}", self.pkg.name(), self.pkg.version());
+		if let Some(git) = &self.source.git {
+			let _ = write!(purl, "?vcs_url=git%2B{git}");
+		} else if let Some(registry) = &self.source.registry {
+			if !graph::is_crates_io(registry) {
+				let _ = write!(purl, "?repository_url={registry}");
+			}
+		}
+		purl
+	}
+	/// Resolved git commit, or path of the local package
+	fn location(&self) -> Option<String> {
+		if let Some(path) = &self.source.path {
+			return Some(path.clone());
+		}
+		let git = self.source.git.as_ref()?;
+		let resolved = match self.pkg.source().parse_external() {
+			Some(guppy::graph::ExternalSource::Git { resolved, .. }) => resolved,
+			_ => "",
+		};
+		Some(format!("git+{git}#{resolved}"))
+	}
+	fn originals(&self) -> Result<Option<String>> {
+		if self.originals.is_empty() {
+			return Ok(None);
+		}
+		Ok(Some(serde_json::to_string(&self.originals).run_err()?))
+	}
+}
+
+/// Originals, stored for dependencies in workspace manifests, keyed by package name
+fn stored_originals(root: &Path) -> Result<BTreeMap<String, Vec<DirectSource>>> {
+	let mut out = <BTreeMap<_, Vec<_>>>::new();
+	for manifest in manifests(root)? {
+		for (_, input) in dependencies(&manifest)? {
+			if input.original_source == input.source {
+				continue;
+			}
+			let originals = out.entry(input.package).or_default();
+			if !originals.contains(&input.original_source) {
+				originals.push(input.original_source);
+			}
+		}
+	}
+	Ok(out)
+}
+
+fn cyclonedx(components: &[Component<'_>]) -> Result<Json> {
+	let components = components
+		.iter()
+		.map(|c| {
+			let mut properties = vec![json!({"name": "deppatcher:source", "value": c.kind()})];
+			if let Some(location) = c.location() {
+				properties.push(json!({"name": "deppatcher:location", "value": location}));
+			}
+			if let Some(originals) = c.originals()? {
+				properties.push(json!({"name": "deppatcher:originalSources", "value": originals}));
+			}
+			let mut out = json!({
+				"type": "library",
+				"bom-ref": c.pkg.id().repr(),
+				"name": c.pkg.name(),
+				"version": c.pkg.version().to_string(),
+				"purl": c.purl(),
+				"properties": properties,
+			});
+			if let Some(license) = c.pkg.license() {
+				out["licenses"] = json!([{"expression": license}]);
+			}
+			if let Some(git) = &c.source.git {
+				out["externalReferences"] = json!([{"type": "vcs", "url": git}]);
+			}
+			Ok(out)
+		})
+		.collect::<Result<Vec<_>>>()?;
+	Ok(json!({
+		"bomFormat": "CycloneDX",
+		"specVersion": "1.5",
+		"version": 1,
+		"metadata": {
+			"timestamp": timestamp(),
+			"tools": [{"name": "deppatcher", "version": env!("CARGO_PKG_VERSION")}],
+		},
+		"components": components,
+	}))
+}
+
+fn spdx(root: &Path, components: &[Component<'_>]) -> Result<Json> {
+	let mut hasher = Sha256::new();
+	let packages = components
+		.iter()
+		.enumerate()
+		.map(|(i, c)| {
+			hasher.update(c.pkg.id().repr());
+			let mut comment = format!("source: {}", c.kind());
+			if let Some(location) = c.location() {
+				let _ = write!(comment, " ({location})");
+			}
+			if let Some(originals) = c.originals()? {
+				let _ = write!(comment, "; original sources: {originals}");
+			}
+			Ok(json!({
+				"name": c.pkg.name(),
+				"SPDXID": format!("SPDXRef-Package-{i}"),
+				"versionInfo": c.pkg.version().to_string(),
+				"downloadLocation": c.location().filter(|_| c.source.git.is_some()).unwrap_or_else(|| "NOASSERTION".to_owned()),
+				"licenseDeclared": c.pkg.license().unwrap_or("NOASSERTION"),
+				"externalRefs": [{
+					"referenceCategory": "PACKAGE-MANAGER",
+					"referenceType": "purl",
+					"referenceLocator": c.purl(),
+				}],
+				"comment": comment,
+			}))
+		})
+		.collect::<Result<Vec<_>>>()?;
+	let name = root
+		.file_name()
+		.map_or_else(|| "workspace".into(), |n| n.to_string_lossy());
+	Ok(json!({
+		"spdxVersion": "SPDX-2.3",
+		"dataLicense": "CC0-1.0",
+		"SPDXID": "SPDXRef-DOCUMENT",
+		"name": name,
+		"documentNamespace": format!("https://spdx.org/spdxdocs/{name}-{:x}", hasher.finalize()),
+		"creationInfo": {
+			"created": timestamp(),
+			"creators": [format!("Tool: {TOOL}")],
+		},
+		"packages": packages,
+	}))
+}
+
+/// Current time in RFC 3339 format, UTC
+fn timestamp() -> String {
+	let secs = SystemTime::now()
+		.duration_since(UNIX_EPOCH)
+		.map_or(0, |d| d.as_secs());
+	let (days, rem) = (secs / 86400, secs % 86400);
+	// Days to civil date, http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+	let z = days + 719_468;
+	let era = z / 146_097;
+	let doe = z % 146_097;
+	let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + u64::from(month <= 2);
+	format!(
+		"{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+		rem / 3600,
+		rem % 3600 / 60,
+		rem % 60
+	)
+}
+
+pub fn sbom(root: &Path, format: SbomFormat, with_originals: bool) -> Result<()> {
+	let graph = graph::load(root)?;
+	let originals = if with_originals {
+		stored_originals(root)?
+	} else {
+		BTreeMap::new()
+	};
+	let mut components = graph
+		.packages()
+		.filter(|pkg| !pkg.in_workspace())
+		.map(|pkg| Component {
+			pkg,
+			source: graph::source_of(pkg),
+			originals: originals.get(pkg.name()).cloned().unwrap_or_default(),
+		})
+		.collect::<Vec<_>>();
+	components.sort_by_key(|c| c.pkg.id());
+	let bom = match format {
+		SbomFormat::Cyclonedx => cyclonedx(&components)?,
+		SbomFormat::Spdx => spdx(root, &components)?,
+	};
+	println!(
+		"{}",
+		serde_json::to_string_pretty(&bom).expect("bom is serializable")
+	);
+	Ok(())
+}
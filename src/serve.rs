@@ -0,0 +1,213 @@
+//! `serve` command: JSON-RPC 2.0 interface over stdio, one message per line
+//!
+//! Methods:
+//! - `evaluate {rule | file, input}`: result of the rules for the `DirectInput`, `null` if unchanged
+//! - `patch {rule | file, manifest, path?, forceInline?}`: patched manifest content, `path` is used for `dpp.manifestDir`
+//! - `status {}`: every dependency of the workspace, in the same format as `query` output
+//!
+//! Loaded rules are cached, so repeated requests don't pay for the evaluator initialization
+
+use std::{
+	collections::HashMap,
+	fs,
+	io::{stdin, stdout, BufRead, Write},
+	path::{Path, PathBuf},
+};
+
+use jrsonnet_cli::StdOpts;
+use jrsonnet_evaluator::{
+	bail,
+	typed::{Either2, Typed},
+	Result, Val,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value as Json};
+use toml_edit::Document;
+
+use crate::{
+	keep_text_format, load_mutators,
+	natives::{self, CurrentManifest},
+	patch_doc, query, DirectInput, Mutators, RulesOpts, ToRuntime,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const EXECUTION_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct Request {
+	id: Option<Json>,
+	method: String,
+	#[serde(default)]
+	params: Json,
+}
+
+/// Rules are either passed as code, or as path to the rules file
+#[derive(Deserialize)]
+struct RuleParams {
+	rule: Option<String>,
+	file: Option<PathBuf>,
+}
+
+#[derive(Deserialize)]
+struct EvaluateParams {
+	#[serde(flatten)]
+	rule: RuleParams,
+	input: Json,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PatchParams {
+	#[serde(flatten)]
+	rule: RuleParams,
+	manifest: String,
+	path: Option<PathBuf>,
+	#[serde(default)]
+	force_inline: bool,
+}
+
+struct Server<'s> {
+	root: &'s Path,
+	std: &'s StdOpts,
+	/// Keyed by code, or by file path and its content
+	rules: HashMap<(Option<PathBuf>, String), Mutators>,
+}
+impl Server<'_> {
+	fn rules(&mut self, params: RuleParams) -> Result<&Mutators> {
+		let (key, rules) = match params {
+			RuleParams {
+				rule: Some(code),
+				file: None,
+			} => (
+				(None, code.clone()),
+				RulesOpts {
+					exec: true,
+					inputs: vec![code],
+				},
+			),
+			RuleParams {
+				rule: None,
+				file: Some(file),
+			} => {
+				let content = fs::read_to_string(&file).run_err()?;
+				let input = file.to_string_lossy().into_owned();
+				(
+					(Some(file), content),
+					RulesOpts {
+						exec: false,
+						inputs: vec![input],
+					},
+				)
+			}
+			_ => bail!("exactly one of rule and file should be specified"),
+		};
+		if !self.rules.contains_key(&key) {
+			let mutators = load_mutators(rules, self.std)?;
+			self.rules.insert(key.clone(), mutators);
+		}
+		Ok(&self.rules[&key])
+	}
+
+	fn evaluate(&mut self, params: EvaluateParams) -> Result<Json> {
+		natives::clear_cache();
+		let input = DirectInput::from_untyped(Val::from_serde(&params.input).run_err()?)?;
+		Ok(match self.rules(params.rule)?.apply(input)? {
+			Either2::A(_) => Json::Null,
+			Either2::B(source) => serde_json::to_value(source).run_err()?,
+		})
+	}
+
+	fn patch(&mut self, params: PatchParams) -> Result<Json> {
+		// Linked workspaces and lockfiles might have changed since the previous request
+		natives::clear_cache();
+		let mutators = self.rules(params.rule)?;
+		let _manifest = params.path.as_deref().map(CurrentManifest::enter);
+		let mut doc: Document = params.manifest.parse().run_err()?;
+		patch_doc(
+			&mut doc,
+			&|_, input| mutators.apply(input),
+			params.force_inline,
+		)?;
+		let patched = keep_text_format(&params.manifest, &doc.to_string());
+		Ok(json!({
+			"changed": patched != params.manifest,
+			"manifest": patched,
+		}))
+	}
+
+	fn status(&self) -> Result<Json> {
+		serde_json::to_value(query::matching(self.root, &|_| Ok(true))?).run_err()
+	}
+
+	/// Response to the message, `None` for notifications
+	fn handle(&mut self, line: &str) -> Option<Json> {
+		let request = match serde_json::from_str::<Json>(line) {
+			Ok(request) => request,
+			Err(e) => return Some(error(Json::Null, PARSE_ERROR, &e.to_string())),
+		};
+		let request = match serde_json::from_value::<Request>(request) {
+			Ok(request) => request,
+			Err(e) => return Some(error(Json::Null, INVALID_REQUEST, &e.to_string())),
+		};
+		let id = request.id.clone();
+
+		let result = match request.method.as_str() {
+			"evaluate" => params(request.params).map(|p| self.evaluate(p)),
+			"patch" => params(request.params).map(|p| self.patch(p)),
+			"status" => Ok(self.status()),
+			method => {
+				return id
+					.map(|id| error(id, METHOD_NOT_FOUND, &format!("unknown method {method}")))
+			}
+		};
+		let id = id?;
+		Some(match result {
+			Err(e) => error(id, INVALID_PARAMS, &e.to_string()),
+			Ok(Err(e)) => error(id, EXECUTION_ERROR, &format!("{e:?}")),
+			Ok(Ok(result)) => json!({
+				"jsonrpc": "2.0",
+				"id": id,
+				"result": result,
+			}),
+		})
+	}
+}
+
+fn params<T: DeserializeOwned>(params: Json) -> serde_json::Result<T> {
+	serde_json::from_value(params)
+}
+
+fn error(id: Json, code: i64, message: &str) -> Json {
+	json!({
+		"jsonrpc": "2.0",
+		"id": id,
+		"error": {
+			"code": code,
+			"message": message,
+		},
+	})
+}
+
+/// Serves requests until stdin is closed
+pub fn serve(root: &Path, std: &StdOpts) -> Result<()> {
+	let mut server = Server {
+		root,
+		std,
+		rules: HashMap::new(),
+	};
+	for line in stdin().lock().lines() {
+		let line = line.run_err()?;
+		if line.trim().is_empty() {
+			continue;
+		}
+		if let Some(response) = server.handle(&line) {
+			let mut out = stdout().lock();
+			writeln!(out, "{response}").run_err()?;
+			out.flush().run_err()?;
+		}
+	}
+	Ok(())
+}
@@ -0,0 +1,154 @@
+//! `.deppatcher` directory in the workspace root, holding state of deppatcher runs
+
+use std::{
+	env,
+	fs::{self, File, TryLockError},
+	path::{Path, PathBuf},
+	time::{Duration, SystemTime},
+};
+
+use jrsonnet_evaluator::{bail, Result};
+use sha2::{Digest, Sha256};
+use tracing::info;
+
+use crate::{cargo_config::cargo_home, ToRuntime};
+
+/// Files, which are never collected, `registry` holds copies manifests might still point to
+const PERMANENT: &[&str] = &["lock", ".gitignore", "registry"];
+
+/// State directory of the workspace, created if missing
+pub fn dir(root: &Path) -> Result<PathBuf> {
+	let dir = root.join(".deppatcher");
+	fs::create_dir_all(&dir).run_err()?;
+	let gitignore = dir.join(".gitignore");
+	if !gitignore.exists() {
+		fs::write(gitignore, "*\n").run_err()?;
+	}
+	Ok(dir)
+}
+
+/// Short hash of the canonical workspace root, to keep state of different workspaces apart outside of them
+pub fn workspace_id(root: &Path) -> Result<String> {
+	let root = root.canonicalize().run_err()?;
+	let hash = Sha256::digest(root.to_string_lossy().as_bytes());
+	Ok(format!("{hash:x}")[..12].to_owned())
+}
+
+/// State directory of the workspace, which is located outside of it, created if missing
+///
+/// Path dependencies inside of the workspace directory automatically become its members,
+/// so copies of third-party crates are kept in `$CARGO_HOME/deppatcher/<workspace id>`
+pub fn external_dir(root: &Path) -> Result<PathBuf> {
+	let Some(home) = cargo_home() else {
+		bail!("unable to determine cargo home")
+	};
+	let dir = home.join("deppatcher").join(workspace_id(root)?);
+	fs::create_dir_all(&dir).run_err()?;
+	Ok(dir)
+}
+
+/// Temporary directory of the run, i.e for git clones, removed on drop
+///
+/// Named after the workspace, and locked while in use,
+/// so `gc` only collects ones of the same workspace, left behind by interrupted runs
+pub struct TempDir {
+	path: PathBuf,
+	_lock: File,
+}
+impl TempDir {
+	pub fn new(root: &Path, name: &str) -> Result<Self> {
+		let path = env::temp_dir().join(format!("deppatcher-{}-{name}", workspace_id(root)?));
+		fs::create_dir_all(&path).run_err()?;
+		let lock = File::create(path.join("lock")).run_err()?;
+		lock.lock().run_err()?;
+		Ok(Self { path, _lock: lock })
+	}
+	/// Directory for the content, separate from the lock file
+	pub fn content(&self) -> PathBuf {
+		self.path.join("content")
+	}
+}
+impl Drop for TempDir {
+	fn drop(&mut self) {
+		let _ = fs::remove_dir_all(&self.path);
+	}
+}
+
+/// Whether the temporary directory is still used by the running process
+fn in_use(dir: &Path) -> bool {
+	File::open(dir.join("lock"))
+		.is_ok_and(|lock| matches!(lock.try_lock(), Err(TryLockError::WouldBlock)))
+}
+
+/// Time of the latest modification of the file, or of anything in the directory
+fn last_modified(path: &Path) -> Result<SystemTime> {
+	let mut latest = SystemTime::UNIX_EPOCH;
+	for entry in walkdir::WalkDir::new(path) {
+		let modified = entry
+			.run_err()?
+			.metadata()
+			.run_err()?
+			.modified()
+			.run_err()?;
+		latest = latest.max(modified);
+	}
+	Ok(latest)
+}
+
+/// Entries of the state directory, and git clones left in the temporary directory by interrupted runs
+fn candidates(root: &Path) -> Result<Vec<PathBuf>> {
+	let mut out = Vec::new();
+	let state = root.join(".deppatcher");
+	if state.is_dir() {
+		for entry in fs::read_dir(&state).run_err()? {
+			let entry = entry.run_err()?;
+			if !PERMANENT.iter().any(|p| entry.file_name() == *p) {
+				out.push(entry.path());
+			}
+		}
+	}
+	let prefix = format!("deppatcher-{}-", workspace_id(root)?);
+	for entry in fs::read_dir(env::temp_dir()).run_err()? {
+		let entry = entry.run_err()?;
+		if !entry.file_name().to_string_lossy().starts_with(&prefix) {
+			continue;
+		}
+		if in_use(&entry.path()) {
+			info!("skipping {}, which is in use", entry.path().display());
+			continue;
+		}
+		out.push(entry.path());
+	}
+	out.sort();
+	Ok(out)
+}
+
+/// Removes state entries, which weren't modified for longer than `max_age`
+pub fn gc(root: &Path, max_age: Duration, dry_run: bool) -> Result<()> {
+	let now = SystemTime::now();
+	let mut freed = 0;
+	for path in candidates(root)? {
+		let age = now
+			.duration_since(last_modified(&path)?)
+			.unwrap_or_default();
+		if age < max_age {
+			continue;
+		}
+		let days = age.as_secs() / (24 * 60 * 60);
+		if dry_run {
+			println!("would remove {} (unused for {days} days)", path.display());
+			continue;
+		}
+		info!("removing {} (unused for {days} days)", path.display());
+		if path.is_dir() {
+			fs::remove_dir_all(&path).run_err()?;
+		} else {
+			fs::remove_file(&path).run_err()?;
+		}
+		freed += 1;
+	}
+	if !dry_run {
+		info!("removed {freed} entries");
+	}
+	Ok(())
+}
@@ -0,0 +1,51 @@
+//! Styling of human-readable terminal output
+
+use std::{
+	env,
+	fmt::Display,
+	io::{stderr, stdout, IsTerminal},
+};
+
+use anstyle::{AnsiColor, Style};
+use clap::ValueEnum;
+
+pub const NAME: Style = Style::new().bold();
+pub const DIM: Style = Style::new().dimmed();
+pub const REGISTRY: Style = AnsiColor::Blue.on_default();
+pub const GIT: Style = AnsiColor::Magenta.on_default();
+pub const PATH: Style = AnsiColor::Yellow.on_default();
+pub const PATCHED: Style = AnsiColor::Red.on_default().bold();
+pub const REMOVED: Style = AnsiColor::Red.on_default();
+pub const ADDED: Style = AnsiColor::Green.on_default();
+
+#[derive(ValueEnum, Clone, Copy, Default)]
+pub enum ColorChoice {
+	/// Color output, if stdout is a terminal, and `NO_COLOR` is not set
+	#[default]
+	Auto,
+	Always,
+	Never,
+}
+
+#[derive(Clone, Copy)]
+pub struct Painter(bool);
+impl Painter {
+	pub fn new(choice: ColorChoice) -> Self {
+		Self(match choice {
+			ColorChoice::Auto => stdout().is_terminal() && env::var_os("NO_COLOR").is_none(),
+			ColorChoice::Always => true,
+			ColorChoice::Never => false,
+		})
+	}
+	/// Painter for log output, which is written to stderr
+	pub fn stderr() -> Self {
+		Self(stderr().is_terminal() && env::var_os("NO_COLOR").is_none())
+	}
+	pub fn paint(self, style: Style, text: impl Display) -> String {
+		if self.0 {
+			format!("{}{text}{}", style.render(), style.render_reset())
+		} else {
+			text.to_string()
+		}
+	}
+}
@@ -0,0 +1,142 @@
+//! `sync-versions` command: rewrite version requirements to versions resolved in Cargo.lock
+
+use std::{
+	collections::{BTreeSet, HashMap},
+	path::{Path, PathBuf},
+};
+
+use jrsonnet_evaluator::{
+	typed::{Either2, Null},
+	Either, Result,
+};
+use semver::{Version, VersionReq};
+use tracing::{info, warn};
+
+use crate::{
+	dep_location, graph, manifests, natives, patch, registries::Registries, DirectInput,
+	DirectSource, Key, PROGRESS,
+};
+
+/// Package name and its registry, as returned by [`registry_key`]
+type PackageKey = (String, String);
+
+/// `crates-io` for crates.io, canonical index url otherwise
+fn registry_key(registry: &str) -> String {
+	if registry == "crates-io" || graph::is_crates_io(registry) {
+		"crates-io".to_owned()
+	} else {
+		graph::canonical_registry(registry).to_owned()
+	}
+}
+
+/// Versions, to which dependencies of workspace members are resolved
+struct Resolved {
+	/// Keyed by canonical manifest path, the same package might be declared in multiple sections
+	members: HashMap<PathBuf, HashMap<PackageKey, BTreeSet<Version>>>,
+	/// Every resolved version of the package, used for `[workspace.dependencies]`
+	packages: HashMap<PackageKey, BTreeSet<Version>>,
+	registries: Registries,
+}
+impl Resolved {
+	fn load(root: &Path) -> Result<Self> {
+		let graph = graph::load(root)?;
+		let mut members = HashMap::new();
+		let mut packages = <HashMap<_, BTreeSet<_>>>::new();
+		for member in graph.workspace().iter() {
+			let manifest = member.manifest_path().as_std_path();
+			let manifest = manifest
+				.canonicalize()
+				.unwrap_or_else(|_| manifest.to_owned());
+			let deps = members
+				.entry(manifest)
+				.or_insert_with(<HashMap<_, BTreeSet<_>>>::new);
+			for link in member.direct_links() {
+				let to = link.to();
+				// Path and git dependencies are not synced
+				let Some(registry) = graph::source_of(to).registry else {
+					continue;
+				};
+				let key = (to.name().to_owned(), registry_key(&registry));
+				deps.entry(key.clone())
+					.or_default()
+					.insert(to.version().clone());
+				packages
+					.entry(key)
+					.or_default()
+					.insert(to.version().clone());
+			}
+		}
+		Ok(Self {
+			members,
+			packages,
+			registries: Registries::load(root, &[])?,
+		})
+	}
+
+	fn version(&self, manifest: &Path, key: &Key, input: &DirectInput) -> Option<&Version> {
+		let source = &input.source;
+		let registry = match &source.registry_index {
+			Some(index) => index.clone(),
+			None => match self.registries.patch_key(source.registry.as_deref()) {
+				Ok(registry) => registry,
+				Err(e) => {
+					warn!("{}: {e}", dep_location(key));
+					return None;
+				}
+			},
+		};
+		let package = (input.package.clone(), registry_key(&registry));
+		let versions = if key.first().is_some_and(|k| k == "workspace") {
+			self.packages.get(&package)?
+		} else {
+			let manifest = manifest.canonicalize().ok()?;
+			self.members.get(&manifest)?.get(&package)?
+		};
+		// Renamed dependencies on the different versions of the package are told apart by the requirement
+		let req = VersionReq::parse(source.version.as_ref()?).ok()?;
+		let mut matching = versions.iter().filter(|v| req.matches(v));
+		let version = matching.next()?;
+		if matching.next().is_some() {
+			warn!(
+				"{}: {} is resolved to multiple versions matching {req}, skipping",
+				dep_location(key),
+				input.package
+			);
+			return None;
+		}
+		Some(version)
+	}
+}
+
+pub fn sync_versions(root: &Path, exact: bool) -> Result<()> {
+	let resolved = Resolved::load(root)?;
+	let mutator = |key: &Key, input: DirectInput| -> Result<Either![Null, DirectSource]> {
+		let source = &input.source;
+		if source.path.is_some() || source.git.is_some() || source.workspace == Some(true) {
+			return Ok(Either2::A(Null));
+		}
+		let (Some(current), Some(manifest)) = (&source.version, natives::current_manifest()) else {
+			return Ok(Either2::A(Null));
+		};
+		let Some(version) = resolved.version(&manifest, key, &input) else {
+			return Ok(Either2::A(Null));
+		};
+		let version = if exact {
+			format!("={version}")
+		} else {
+			version.to_string()
+		};
+		if *current == version {
+			return Ok(Either2::A(Null));
+		}
+		Ok(Either2::B(DirectSource {
+			version: Some(version),
+			..source.clone()
+		}))
+	};
+	for manifest in manifests(root)? {
+		info!(target: PROGRESS, "patching {}", manifest.display());
+		patch(&manifest, &mutator, false)?;
+	}
+	Ok(())
+}
@@ -0,0 +1,106 @@
+//! `--timings`: where the time of the run went
+
+use std::{
+	cell::RefCell,
+	collections::HashSet,
+	sync::atomic::{AtomicBool, Ordering},
+	time::{Duration, Instant},
+};
+
+#[derive(Clone, Copy)]
+pub enum Phase {
+	/// Looking for manifests
+	Traversal,
+	/// Parsing manifests
+	Parse,
+	/// Calling rules, including helpers called by them
+	Evaluate,
+	/// Running `cargo metadata`
+	Metadata,
+	/// Writing changed manifests
+	Write,
+}
+impl Phase {
+	const ALL: [Self; 5] = [
+		Self::Traversal,
+		Self::Parse,
+		Self::Evaluate,
+		Self::Metadata,
+		Self::Write,
+	];
+	const fn name(self) -> &'static str {
+		match self {
+			Self::Traversal => "manifest discovery",
+			Self::Parse => "toml parsing",
+			Self::Evaluate => "rule evaluation",
+			Self::Metadata => "cargo metadata",
+			Self::Write => "writing manifests",
+		}
+	}
+}
+
+#[derive(Default)]
+struct Timings {
+	spent: [Duration; Phase::ALL.len()],
+	count: [usize; Phase::ALL.len()],
+	/// Distinct rule inputs, to tell how much repeated evaluations cost
+	inputs: HashSet<String>,
+}
+
+thread_local! {
+	static TIMINGS: RefCell<Timings> = RefCell::default();
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Accounts time spent in `f` to the phase
+pub fn measure<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+	let start = Instant::now();
+	let out = f();
+	let elapsed = start.elapsed();
+	TIMINGS.with_borrow_mut(|t| {
+		t.spent[phase as usize] += elapsed;
+		t.count[phase as usize] += 1;
+	});
+	out
+}
+
+/// Records rule input, identified by its debug representation, which is only built if timings are reported
+pub fn record_input(input: impl FnOnce() -> String) {
+	if ENABLED.load(Ordering::Relaxed) {
+		TIMINGS.with_borrow_mut(|t| t.inputs.insert(input()));
+	}
+}
+
+/// Guard, which prints timings to stderr when the run finishes
+pub struct Report(Instant);
+impl Report {
+	pub fn start() -> Self {
+		ENABLED.store(true, Ordering::Relaxed);
+		Self(Instant::now())
+	}
+}
+impl Drop for Report {
+	fn drop(&mut self) {
+		report(self.0.elapsed());
+	}
+}
+
+fn report(total: Duration) {
+	TIMINGS.with_borrow(|t| {
+		eprintln!("timings:");
+		for phase in Phase::ALL {
+			let (spent, count) = (t.spent[phase as usize], t.count[phase as usize]);
+			let details = match phase {
+				Phase::Evaluate => format!("{count} calls, {} distinct inputs", t.inputs.len()),
+				_ => format!("{count} times"),
+			};
+			eprintln!(
+				"  {:<20} {:>10.1}ms  ({details})",
+				phase.name(),
+				spent.as_secs_f64() * 1000.0
+			);
+		}
+		eprintln!("  {:<20} {:>10.1}ms", "total", total.as_secs_f64() * 1000.0);
+	});
+}
@@ -0,0 +1,260 @@
+//! `tree` command: resolved dependency tree, annotated with package sources
+
+use std::{
+	collections::{BTreeSet, HashMap, HashSet},
+	path::Path,
+};
+
+use ::glob::Pattern;
+use guppy::{
+	graph::{ExternalSource, GitReq, PackageGraph, PackageLink, PackageMetadata},
+	PackageId,
+};
+use jrsonnet_evaluator::Result;
+use semver::VersionReq;
+use toml_edit::{Document, Item};
+
+use crate::{
+	graph,
+	style::{self, Painter},
+	DirectSource, ToRuntime,
+};
+
+/// Entry of the `[patch]` section of the workspace manifest
+struct PatchEntry {
+	/// Key of the patched source, `crates-io` or url
+	patched: String,
+	package: String,
+	source: DirectSource,
+}
+impl PatchEntry {
+	/// Whether the resolved package comes from this entry
+	fn replaces(&self, root: &Path, pkg: PackageMetadata<'_>) -> bool {
+		if pkg.name() != self.package {
+			return false;
+		}
+		if let Some(req) = &self.source.version {
+			if !VersionReq::parse(req).is_ok_and(|req| req.matches(pkg.version())) {
+				return false;
+			}
+		}
+		let source = pkg.source();
+		if let Some(path) = &self.source.path {
+			let canonical = |p: &Path| p.canonicalize().ok();
+			return source.local_path().is_some_and(|local| {
+				canonical(&root.join(local)).is_some_and(|l| Some(l) == canonical(&root.join(path)))
+			});
+		}
+		if let Some(git) = &self.source.git {
+			return matches!(
+				source.parse_external(),
+				Some(ExternalSource::Git { repository, .. })
+					if graph::canonical_registry(repository) == graph::canonical_registry(git)
+			);
+		}
+		// Version from another registry, package of the patched registry itself is not replaced
+		!is_from(pkg, &self.patched)
+	}
+}
+
+/// Whether package is resolved from the source, as it is named in `[patch.<source>]`
+fn is_from(pkg: PackageMetadata<'_>, patched: &str) -> bool {
+	let url = match pkg.source().parse_external() {
+		Some(ExternalSource::Registry(url)) => url,
+		Some(ExternalSource::Git { repository, .. }) => repository,
+		_ => match pkg.source().external_source() {
+			Some(sparse) if sparse.starts_with("sparse+") => sparse,
+			_ => return false,
+		},
+	};
+	if patched == "crates-io" {
+		graph::is_crates_io(url)
+	} else {
+		graph::canonical_registry(url) == graph::canonical_registry(patched)
+	}
+}
+
+/// Entries of the `[patch]` section of the workspace manifest
+fn patch_entries(graph: &PackageGraph) -> Result<Vec<PatchEntry>> {
+	let manifest = graph.workspace().root().join("Cargo.toml");
+	let doc: Document = std::fs::read_to_string(manifest)
+		.run_err()?
+		.parse()
+		.run_err()?;
+	let mut out = Vec::new();
+	for (patched, source) in doc
+		.get("patch")
+		.and_then(Item::as_table_like)
+		.into_iter()
+		.flat_map(|t| t.iter())
+	{
+		for (name, entry) in source.as_table_like().into_iter().flat_map(|t| t.iter()) {
+			let package = entry.get("package").and_then(Item::as_str).unwrap_or(name);
+			out.push(PatchEntry {
+				patched: patched.to_owned(),
+				package: package.to_owned(),
+				source: DirectSource::from_item(entry),
+			});
+		}
+	}
+	Ok(out)
+}
+
+fn describe(painter: Painter, pkg: PackageMetadata<'_>, patched: bool) -> String {
+	let source = pkg.source();
+	let description = match source.parse_external() {
+		Some(ExternalSource::Registry(url)) if graph::is_crates_io(url) => {
+			painter.paint(style::REGISTRY, "registry")
+		}
+		Some(ExternalSource::Registry(url)) => {
+			painter.paint(style::REGISTRY, format!("registry {url}"))
+		}
+		Some(ExternalSource::Git {
+			repository,
+			req,
+			resolved,
+		}) => {
+			let reference = match req {
+				GitReq::Branch(b) => format!("branch={b} "),
+				GitReq::Tag(t) => format!("tag={t} "),
+				_ => String::new(),
+			};
+			painter.paint(
+				style::GIT,
+				format!(
+					"git {repository} {reference}rev={}",
+					&resolved[..resolved.len().min(8)]
+				),
+			)
+		}
+		_ => source.local_path().map_or_else(
+			|| painter.paint(style::DIM, source),
+			|path| painter.paint(style::PATH, format!("path {path}")),
+		),
+	};
+	if patched {
+		format!(
+			"{description}, {}",
+			painter.paint(style::PATCHED, "patched")
+		)
+	} else {
+		description
+	}
+}
+
+struct Printer<'g> {
+	graph: &'g PackageGraph,
+	painter: Painter,
+	filter: Vec<Pattern>,
+	patched: Vec<PatchEntry>,
+	/// Whether package, or any of its dependencies matches filter
+	matches: HashMap<&'g PackageId, bool>,
+	printed: HashSet<&'g PackageId>,
+}
+impl<'g> Printer<'g> {
+	fn links(pkg: PackageMetadata<'g>) -> Vec<PackageLink<'g>> {
+		let mut links = pkg
+			.direct_links()
+			.filter(|l| {
+				l.normal().is_present()
+					|| l.build().is_present()
+					|| (pkg.in_workspace() && l.dev().is_present())
+			})
+			.collect::<Vec<_>>();
+		links.sort_by(|a, b| a.to().name().cmp(b.to().name()));
+		links
+	}
+
+	fn matches(
+		&mut self,
+		pkg: PackageMetadata<'g>,
+		visiting: &mut BTreeSet<&'g PackageId>,
+	) -> bool {
+		if self.filter.is_empty() {
+			return true;
+		}
+		if let Some(m) = self.matches.get(pkg.id()) {
+			return *m;
+		}
+		if self.filter.iter().any(|p| p.matches(pkg.name())) {
+			self.matches.insert(pkg.id(), true);
+			return true;
+		}
+		if !visiting.insert(pkg.id()) {
+			return false;
+		}
+		let mut matches = false;
+		for link in Self::links(pkg) {
+			matches |= self.matches(link.to(), visiting);
+		}
+		visiting.remove(pkg.id());
+		self.matches.insert(pkg.id(), matches);
+		matches
+	}
+
+	fn print(
+		&mut self,
+		link: Option<PackageLink<'g>>,
+		pkg: PackageMetadata<'g>,
+		prefix: &str,
+		last: bool,
+	) {
+		let (branch, child_prefix) = match (link.is_some(), last) {
+			(false, _) => ("", String::new()),
+			(true, false) => ("├── ", format!("{prefix}│   ")),
+			(true, true) => ("└── ", format!("{prefix}    ")),
+		};
+		let kind = match link {
+			Some(l) if !l.normal().is_present() && l.build().is_present() => " [build]",
+			Some(l) if !l.normal().is_present() && l.dev().is_present() => " [dev]",
+			_ => "",
+		};
+		let repeated = !self.printed.insert(pkg.id());
+		println!(
+			"{prefix}{branch}{} v{} ({}){kind}{}",
+			self.painter.paint(style::NAME, pkg.name()),
+			pkg.version(),
+			describe(
+				self.painter,
+				pkg,
+				self.patched
+					.iter()
+					.any(|e| e.replaces(self.graph.workspace().root().as_std_path(), pkg))
+			),
+			if repeated { " (*)" } else { "" },
+		);
+		if repeated {
+			return;
+		}
+		let links = Self::links(pkg)
+			.into_iter()
+			.filter(|l| self.matches(l.to(), &mut BTreeSet::new()))
+			.collect::<Vec<_>>();
+		let count = links.len();
+		for (i, link) in links.into_iter().enumerate() {
+			self.print(Some(link), link.to(), &child_prefix, i + 1 == count);
+		}
+	}
+}
+
+pub fn tree(root: &Path, filter: Vec<Pattern>, painter: Painter) -> Result<()> {
+	let graph = graph::load(root)?;
+	let mut state = Printer {
+		graph: &graph,
+		painter,
+		filter,
+		patched: patch_entries(&graph)?,
+		matches: HashMap::new(),
+		printed: HashSet::new(),
+	};
+	let mut members = state.graph.workspace().iter().collect::<Vec<_>>();
+	members.sort_by(|a, b| a.name().cmp(b.name()));
+	for member in members {
+		if !state.matches(member, &mut BTreeSet::new()) {
+			continue;
+		}
+		state.print(None, member, "", true);
+		println!();
+	}
+	Ok(())
+}
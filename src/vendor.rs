@@ -0,0 +1,40 @@
+//! `vendor-link` command: dependencies are pointed at crates in the `cargo vendor` directory
+
+use std::{collections::BTreeMap, fs};
+
+use jrsonnet_evaluator::{
+	function::{builtin, CallLocation},
+	Result, Val,
+};
+use toml_edit::{Document, Item};
+
+use crate::{caller_dir, get_item, natives::path_to_string, ToRuntime};
+
+/// Crates of the vendor directory, as `{name: {version: path}}`
+///
+/// Both `name` and `name-version` directory layouts (`cargo vendor --versioned-dirs`) are supported,
+/// as package name and version are read from the vendored manifest
+#[builtin]
+pub fn load_vendored(loc: CallLocation, dir: Option<String>) -> Result<Val> {
+	let mut path = caller_dir(loc)?;
+	path.push(dir.as_deref().unwrap_or("vendor"));
+
+	let mut out = <BTreeMap<String, BTreeMap<String, String>>>::new();
+	for entry in fs::read_dir(&path).run_err()? {
+		let crate_dir = entry.run_err()?.path();
+		let manifest = crate_dir.join("Cargo.toml");
+		if !manifest.is_file() {
+			continue;
+		}
+		let doc: Document = fs::read_to_string(&manifest).run_err()?.parse().run_err()?;
+		let get = |key| get_item(doc.as_item(), ["package", key]).and_then(Item::as_str);
+		let (Some(name), Some(version)) = (get("name"), get("version")) else {
+			continue;
+		};
+		out.entry(name.to_owned()).or_default().insert(
+			version.to_owned(),
+			path_to_string(&crate_dir.canonicalize().run_err()?)?,
+		);
+	}
+	Val::from_serde(out).run_err()
+}
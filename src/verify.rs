@@ -0,0 +1,110 @@
+//! `verify-clean` command: checks that no overrides are left in manifests, i.e before publishing
+
+use std::{fs, path::Path};
+
+use jrsonnet_evaluator::Result;
+use toml_edit::{Decor, Document, Item, Table};
+
+use crate::{
+	display_key, doctor::originals, get_item, manifests, rust_version, DirectSource, ToRuntime,
+	MANAGED_MARKER,
+};
+
+/// Whether comments around the entry contain the managed marker
+pub fn has_marker(decor: Option<&Decor>) -> bool {
+	decor.is_some_and(|decor| {
+		[decor.prefix(), decor.suffix()]
+			.into_iter()
+			.flatten()
+			.filter_map(|s| s.as_str())
+			.any(|s| s.contains(MANAGED_MARKER))
+	})
+}
+
+/// Entries of `[patch.<source>]` tables, which were generated by deppatcher
+fn managed_patches(patch: &Table) -> Vec<String> {
+	let mut out = Vec::new();
+	for (source, entries) in patch {
+		let Some(entries) = entries.as_table() else {
+			continue;
+		};
+		for (name, entry) in entries {
+			let managed = has_marker(entries.key_decor(name))
+				|| match entry {
+					Item::Table(t) => has_marker(Some(t.decor())),
+					Item::Value(v) => has_marker(Some(v.decor())),
+					_ => false,
+				};
+			if managed {
+				out.push(format!("patch.{source}.{name}"));
+			}
+		}
+	}
+	out
+}
+
+/// Returns `true` if no overrides were found
+pub fn verify_clean(root: &Path) -> Result<bool> {
+	let mut clean = true;
+	for manifest in manifests(root)? {
+		let relative = manifest.strip_prefix(root).unwrap_or(&manifest).display();
+		let doc: Document = fs::read_to_string(&manifest).run_err()?.parse().run_err()?;
+
+		for metadata_root in ["package", "workspace"] {
+			let Some(stored) = get_item(
+				doc.as_item(),
+				[metadata_root, "metadata", "deppatcher", "originals"],
+			) else {
+				continue;
+			};
+			for (key, original) in rust_version::stored_originals(stored) {
+				clean = false;
+				println!(
+					"{relative}: {}: changed by set-rust-version, stored original {original:?}",
+					display_key(&key)
+				);
+			}
+			let mut found = Vec::new();
+			originals(stored, &mut Vec::new(), &mut found);
+			for (key, original) in found {
+				clean = false;
+				if original.is_remove() {
+					println!("{relative}: {}: removed dependency", display_key(&key));
+					continue;
+				}
+				let overridden = get_item(doc.as_item(), key.iter().map(String::as_str))
+					.map(DirectSource::from_item)
+					.filter(|s| s.path.is_some() || s.git.is_some());
+				if let Some(current) = overridden {
+					let kind = if current.path.is_some() {
+						"path"
+					} else {
+						"git"
+					};
+					println!(
+						"{relative}: {}: {kind} override with stored original",
+						display_key(&key)
+					);
+				} else {
+					println!(
+						"{relative}: {}: leftover stored original",
+						display_key(&key)
+					);
+				}
+			}
+		}
+
+		if let Some(patch) = doc.get("patch").and_then(Item::as_table) {
+			for entry in managed_patches(patch) {
+				clean = false;
+				println!("{relative}: {entry}: managed by deppatcher");
+			}
+		}
+	}
+	if clean {
+		println!("no overrides found");
+	} else {
+		println!("run `deppatcher revert` and `deppatcher freeze` before publishing");
+	}
+	Ok(clean)
+}
@@ -0,0 +1,216 @@
+//! `dpp.semver` natives, for rules which need to reason about versions
+
+use std::cmp::Ordering;
+
+use clap::ValueEnum;
+use jrsonnet_evaluator::{
+	bail,
+	function::{builtin, FuncVal},
+	ObjValue, ObjValueBuilder, Result, Val,
+};
+use semver::{BuildMetadata, Comparator, Op, Prerelease, Version, VersionReq};
+
+use crate::ToRuntime;
+
+fn parse_version(v: &str) -> Result<Version> {
+	Version::parse(v).run_err()
+}
+
+#[builtin]
+fn satisfies(version: String, req: String) -> Result<bool> {
+	let version = parse_version(&version)?;
+	let req = VersionReq::parse(&req).run_err()?;
+	Ok(req.matches(&version))
+}
+
+#[builtin]
+fn compare(a: String, b: String) -> Result<i32> {
+	Ok(match parse_version(&a)?.cmp(&parse_version(&b)?) {
+		Ordering::Less => -1,
+		Ordering::Equal => 0,
+		Ordering::Greater => 1,
+	})
+}
+
+#[builtin]
+#[allow(clippy::cast_precision_loss)]
+fn parse(v: String) -> Result<ObjValue> {
+	let v = parse_version(&v)?;
+	let mut out = ObjValueBuilder::new();
+	out.field("major").value(Val::Num(v.major as f64));
+	out.field("minor").value(Val::Num(v.minor as f64));
+	out.field("patch").value(Val::Num(v.patch as f64));
+	out.field("pre").value(v.pre.to_string());
+	out.field("build").value(v.build.to_string());
+	Ok(out.build())
+}
+
+fn bump(v: &str, f: impl FnOnce(&mut Version)) -> Result<String> {
+	let mut v = parse_version(v)?;
+	f(&mut v);
+	v.pre = Prerelease::EMPTY;
+	v.build = BuildMetadata::EMPTY;
+	Ok(v.to_string())
+}
+
+// Prerelease of the next version is released instead, as in `npm version`
+#[builtin]
+fn bump_major(v: String) -> Result<String> {
+	bump(&v, |v| {
+		if v.pre.is_empty() || v.minor != 0 || v.patch != 0 {
+			v.major += 1;
+		}
+		v.minor = 0;
+		v.patch = 0;
+	})
+}
+
+#[builtin]
+fn bump_minor(v: String) -> Result<String> {
+	bump(&v, |v| {
+		if v.pre.is_empty() || v.patch != 0 {
+			v.minor += 1;
+		}
+		v.patch = 0;
+	})
+}
+
+#[builtin]
+fn bump_patch(v: String) -> Result<String> {
+	bump(&v, |v| {
+		if v.pre.is_empty() {
+			v.patch += 1;
+		}
+	})
+}
+
+/// How caret requirements are written
+#[derive(ValueEnum, Clone, Copy)]
+pub enum ReqStyle {
+	/// `^1.2`
+	Caret,
+	/// `1.2`, which cargo treats the same as `^1.2`
+	Bare,
+}
+
+/// Parts of the version kept in caret requirements made from `=` pins by [`unpin_req`]
+#[derive(ValueEnum, Clone, Copy)]
+pub enum Precision {
+	/// `^1`
+	Major,
+	/// `^1.2`
+	Minor,
+	/// `^1.2.3`
+	Patch,
+}
+
+/// Requirement with comparators changed by `f`, which returns whether the `=` pin was turned into caret
+/// requirement. Such requirements are written in the bare form, if `style` is unset, others are left as is
+fn rewrite_req(
+	req: &str,
+	style: Option<ReqStyle>,
+	f: impl Fn(&mut Comparator) -> bool,
+) -> Result<String> {
+	let parsed = VersionReq::parse(req).run_err()?;
+	// `*` has no comparators
+	if parsed.comparators.is_empty() {
+		return Ok(req.to_owned());
+	}
+	let bare = req
+		.split(',')
+		.map(|c| c.trim().starts_with(|c: char| c.is_ascii_digit()))
+		.collect::<Vec<_>>();
+	Ok(parsed
+		.comparators
+		.into_iter()
+		.zip(bare)
+		.map(|(mut c, was_bare)| {
+			let unpinned = f(&mut c);
+			let out = c.to_string();
+			let bare = match style {
+				Some(ReqStyle::Bare) => true,
+				Some(ReqStyle::Caret) => false,
+				None => was_bare || unpinned,
+			};
+			match out.strip_prefix('^') {
+				Some(version) if bare => version.to_owned(),
+				_ => out,
+			}
+		})
+		.collect::<Vec<_>>()
+		.join(", "))
+}
+
+/// Requirement with operators written in the same way, `strip_exact` turns `=` pins into caret requirements
+///
+/// Operators are left as is, if `style` is unset
+pub fn normalize_req(req: &str, style: Option<ReqStyle>, strip_exact: bool) -> Result<String> {
+	rewrite_req(req, style, |c| {
+		let unpin = strip_exact && c.op == Op::Exact;
+		if unpin {
+			c.op = Op::Caret;
+		}
+		unpin
+	})
+}
+
+/// Requirement with `=` pins turned into caret requirements with the specified precision
+pub fn unpin_req(req: &str, precision: Precision, style: Option<ReqStyle>) -> Result<String> {
+	rewrite_req(req, style, |c| {
+		if c.op != Op::Exact {
+			return false;
+		}
+		c.op = Op::Caret;
+		match precision {
+			Precision::Major => {
+				c.minor = None;
+				c.patch = None;
+				c.pre = Prerelease::EMPTY;
+			}
+			Precision::Minor => {
+				c.patch = None;
+				c.pre = Prerelease::EMPTY;
+			}
+			Precision::Patch => {}
+		}
+		true
+	})
+}
+
+// Parameter names are visible to jsonnet, where named arguments are camelCase
+#[allow(non_snake_case)]
+#[builtin]
+fn normalize_req_builtin(
+	req: String,
+	style: Option<String>,
+	stripExact: Option<bool>,
+) -> Result<String> {
+	let style = match style.as_deref() {
+		None => None,
+		Some("caret") => Some(ReqStyle::Caret),
+		Some("bare") => Some(ReqStyle::Bare),
+		Some(style) => bail!("unknown requirement style {style}, expected caret or bare"),
+	};
+	normalize_req(&req, style, stripExact.unwrap_or(false))
+}
+
+pub fn semver_lib() -> ObjValue {
+	let mut out = ObjValueBuilder::new();
+	out.field("satisfies")
+		.value(Val::Func(FuncVal::StaticBuiltin(satisfies::INST)));
+	out.field("compare")
+		.value(Val::Func(FuncVal::StaticBuiltin(compare::INST)));
+	out.field("parse")
+		.value(Val::Func(FuncVal::StaticBuiltin(parse::INST)));
+	out.field("bumpMajor")
+		.value(Val::Func(FuncVal::StaticBuiltin(bump_major::INST)));
+	out.field("bumpMinor")
+		.value(Val::Func(FuncVal::StaticBuiltin(bump_minor::INST)));
+	out.field("bumpPatch")
+		.value(Val::Func(FuncVal::StaticBuiltin(bump_patch::INST)));
+	out.field("normalizeReq")
+		.value(Val::Func(FuncVal::StaticBuiltin(
+			normalize_req_builtin::INST,
+		)));
+	out.build()
+}
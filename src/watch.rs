@@ -0,0 +1,84 @@
+//! `watch` command: re-applies rules to manifests, whenever they are changed
+
+use std::{
+	collections::BTreeSet,
+	path::{Path, PathBuf},
+	sync::mpsc,
+	time::Duration,
+};
+
+use jrsonnet_evaluator::Result;
+use notify::{EventKind, RecursiveMode, Watcher};
+use tracing::{info, warn};
+
+use crate::{manifests, natives, ToRuntime};
+
+/// Editors and cargo write manifests in multiple steps, changes are processed after this much time of inactivity
+const DEBOUNCE: Duration = Duration::from_millis(300);
+
+fn changed_manifests(event: &notify::Event) -> impl Iterator<Item = &PathBuf> {
+	let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_));
+	event
+		.paths
+		.iter()
+		.filter(move |p| relevant && p.file_name().is_some_and(|n| n == "Cargo.toml"))
+}
+
+/// Applies rules to every manifest, and then to every changed one, until interrupted
+///
+/// Failures are only reported, as manifests are often invalid in the middle of editing
+pub fn watch(root: &Path, apply: &dyn Fn(&Path) -> Result<()>) -> Result<()> {
+	let apply = |manifest: &Path| {
+		if let Err(e) = apply(manifest) {
+			warn!("failed to patch {}: {e:?}", manifest.display());
+		}
+	};
+	let canonical = |manifests: Vec<PathBuf>| {
+		manifests
+			.into_iter()
+			.filter_map(|m| m.canonicalize().ok())
+			.collect::<BTreeSet<_>>()
+	};
+	let initial = manifests(root)?;
+	for manifest in &initial {
+		apply(manifest);
+	}
+	let mut selected = canonical(initial);
+
+	let (tx, rx) = mpsc::channel();
+	let mut watcher = notify::recommended_watcher(tx).run_err()?;
+	watcher.watch(root, RecursiveMode::Recursive).run_err()?;
+	info!("watching {} for manifest changes", root.display());
+
+	while let Ok(event) = rx.recv() {
+		let mut changed = BTreeSet::new();
+		let mut event = Some(event);
+		while let Some(e) = event {
+			match e {
+				Ok(e) => changed.extend(changed_manifests(&e).cloned()),
+				Err(e) => warn!("watch error: {e}"),
+			}
+			event = rx.recv_timeout(DEBOUNCE).ok();
+		}
+		if changed.is_empty() {
+			continue;
+		}
+		// Rules should see the current state of linked workspaces and lockfiles
+		natives::clear_cache();
+		// Membership might have changed too, and discovery filters still apply
+		match manifests(root) {
+			Ok(manifests) => selected = canonical(manifests),
+			Err(e) => warn!("discovery failed, keeping the previous set of manifests: {e:?}"),
+		}
+		for manifest in changed {
+			let Ok(canonical) = manifest.canonicalize() else {
+				continue;
+			};
+			if selected.contains(&canonical) {
+				info!("{} changed", manifest.display());
+				apply(&manifest);
+			}
+		}
+	}
+	Ok(())
+}
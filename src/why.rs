@@ -0,0 +1,83 @@
+//! `why` command: where the package is declared, and how it reaches the workspace
+
+use std::{
+	collections::{HashMap, VecDeque},
+	path::Path,
+};
+
+use guppy::PackageId;
+use jrsonnet_evaluator::Result;
+
+use crate::{dependencies, display_key, graph, manifests};
+
+pub fn why(root: &Path, package: &str) -> Result<()> {
+	println!("declarations:");
+	let mut declared = false;
+	for manifest in manifests(root)? {
+		let relative = manifest.strip_prefix(root).unwrap_or(&manifest);
+		for (key, input) in dependencies(&manifest)? {
+			if input.package != package {
+				continue;
+			}
+			declared = true;
+			println!(
+				"  {}: {} = {}",
+				relative.display(),
+				display_key(&key),
+				input.source.to_table()
+			);
+			if input.original_source != input.source {
+				println!("    original: {}", input.original_source.to_table());
+			}
+		}
+	}
+	if !declared {
+		println!("  none");
+	}
+
+	let graph = graph::load(root)?;
+	println!("dependency chains:");
+	let mut found = false;
+	for target in graph.packages().filter(|p| p.name() == package) {
+		found = true;
+		// Shortest path from every dependent to the target package
+		let mut next = <HashMap<&PackageId, &PackageId>>::new();
+		let mut queue = VecDeque::from([target.id()]);
+		while let Some(id) = queue.pop_front() {
+			let pkg = graph.metadata(id).expect("id from graph");
+			for link in pkg.reverse_direct_links() {
+				let from = link.from().id();
+				if from != target.id() && !next.contains_key(from) {
+					next.insert(from, id);
+					queue.push_back(from);
+				}
+			}
+		}
+		println!(
+			"  {} v{} ({}):",
+			target.name(),
+			target.version(),
+			target.source()
+		);
+		let mut members = graph.workspace().iter().collect::<Vec<_>>();
+		members.sort_by(|a, b| a.name().cmp(b.name()));
+		for member in members {
+			let mut chain = vec![member.name()];
+			let mut current = member.id();
+			if current != target.id() {
+				if !next.contains_key(current) {
+					continue;
+				}
+				while let Some(id) = next.get(current) {
+					chain.push(graph.metadata(id).expect("id from graph").name());
+					current = id;
+				}
+			}
+			println!("    {}", chain.join(" -> "));
+		}
+	}
+	if !found {
+		println!("  package is not in the resolved graph");
+	}
+	Ok(())
+}
@@ -0,0 +1,73 @@
+//! `patch --sync-members`: member entries inheriting `[workspace.dependencies]` follow renames and removals of the workspace entries
+
+use std::{
+	collections::{HashMap, HashSet},
+	fs,
+	path::Path,
+};
+
+use jrsonnet_evaluator::Result;
+use toml_edit::{Document, Item};
+
+use crate::{get_item, DirectInput, DirectSource, ToRuntime};
+
+/// Changes made to `[workspace.dependencies]` of the root manifest, as recorded in its originals
+#[derive(Default)]
+pub struct WorkspaceDeps {
+	/// Original entry name to the current one
+	renamed: HashMap<String, String>,
+	removed: HashSet<String>,
+}
+impl WorkspaceDeps {
+	pub fn load(manifest: &Path) -> Result<Self> {
+		let doc: Document = fs::read_to_string(manifest).run_err()?.parse().run_err()?;
+		let mut out = Self::default();
+		for metadata_root in ["package", "workspace"] {
+			let Some(stored) = get_item(
+				doc.as_item(),
+				[
+					metadata_root,
+					"metadata",
+					"deppatcher",
+					"originals",
+					"workspace",
+					"dependencies",
+				],
+			)
+			.and_then(Item::as_table_like) else {
+				continue;
+			};
+			for (name, stored) in stored.iter() {
+				let Some(stored) = stored.as_table_like() else {
+					continue;
+				};
+				if stored.get("removed").and_then(Item::as_bool) == Some(true) {
+					out.removed.insert(name.to_owned());
+				} else if let Some(original) = stored.get("name").and_then(Item::as_str) {
+					out.renamed.insert(original.to_owned(), name.to_owned());
+				}
+			}
+		}
+		Ok(out)
+	}
+
+	/// Source of the member entry, which is no longer valid after changes to the workspace entry it inherits
+	pub fn sync(&self, input: &DirectInput) -> Option<DirectSource> {
+		if input.source.workspace != Some(true) {
+			return None;
+		}
+		if self.removed.contains(&input.name) {
+			return Some(DirectSource {
+				remove: Some(true),
+				..DirectSource::default()
+			});
+		}
+		let renamed = self.renamed.get(&input.name)?;
+		// Inheriting entries can't specify `package`, the inherited one is used
+		Some(DirectSource {
+			name: Some(renamed.clone()),
+			package: Some(renamed.clone()),
+			..input.source.clone()
+		})
+	}
+}